@@ -0,0 +1,8 @@
+#!/bin/sh
+# Refreshes data/oui.txt, the IEEE MA-L registry built into the binary by
+# src/oui.rs; rebuild afterwards.
+set -e
+cd "$(dirname "$0")"
+curl -fsSL -o oui.txt.new https://standards-oui.ieee.org/oui/oui.txt
+mv oui.txt.new oui.txt
+grep -c '(hex)' oui.txt
@@ -0,0 +1,15 @@
+-- Your SQL goes here
+CREATE TABLE agent_seq (
+    agent TEXT NOT NULL,
+    sub_agent INT NOT NULL,
+    sample_type TEXT NOT NULL,
+    source_id TEXT NOT NULL,
+    last_seq BIGINT NOT NULL,
+    received BIGINT NOT NULL,
+    lost BIGINT NOT NULL,
+    reordered BIGINT NOT NULL,
+    duplicates BIGINT NOT NULL,
+    reboots BIGINT NOT NULL,
+    update_date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY Key(agent, sub_agent, sample_type, source_id)
+);
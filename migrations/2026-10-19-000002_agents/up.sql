@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE agents (
+    agent TEXT NOT NULL,
+    first_seen TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    last_seen TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    datagram_version INT NOT NULL,
+    sys_uptime BIGINT NOT NULL,
+    sample_rates TEXT NOT NULL,
+    sub_agents TEXT NOT NULL,
+    datagrams BIGINT NOT NULL,
+    dps REAL NOT NULL,
+    PRIMARY Key(agent)
+);
@@ -0,0 +1,16 @@
+-- Your SQL goes here
+CREATE TABLE incident (
+    incident_id SERIAL NOT NULL,
+    start_date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    end_date TIMESTAMP,
+    agent TEXT NOT NULL,
+    dst TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    peak_bps BIGINT NOT NULL,
+    peak_pps BIGINT NOT NULL,
+    sources INT NOT NULL,
+    baseline_bps BIGINT NOT NULL,
+    detail TEXT NOT NULL,
+    PRIMARY Key(incident_id)
+);
+CREATE INDEX incident_start_date ON incident (start_date);
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+CREATE TABLE scan_event (
+    event_id SERIAL NOT NULL,
+    event_date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    agent TEXT NOT NULL,
+    src TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    target TEXT NOT NULL,
+    distinct_count INT NOT NULL,
+    window_secs INT NOT NULL,
+    detail TEXT NOT NULL,
+    PRIMARY Key(event_id)
+);
+CREATE INDEX scan_event_event_date ON scan_event (event_date);
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE alert_rule;
\ No newline at end of file
@@ -0,0 +1,16 @@
+-- Your SQL goes here
+CREATE TABLE alert_rule (
+    rule_id SERIAL NOT NULL,
+    name TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    agent TEXT NOT NULL DEFAULT '',
+    src_prefix TEXT NOT NULL DEFAULT '',
+    dst_prefix TEXT NOT NULL DEFAULT '',
+    threshold_bps BIGINT NOT NULL DEFAULT 0,
+    duration_secs INT NOT NULL DEFAULT 0,
+    hold_secs INT NOT NULL DEFAULT 0,
+    webhook TEXT NOT NULL DEFAULT '',
+    syslog TEXT NOT NULL DEFAULT '',
+    enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    PRIMARY Key(rule_id)
+);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX flow_input_date;
+DROP TABLE edge_baseline;
+DROP TABLE flow_hourly;
\ No newline at end of file
@@ -0,0 +1,20 @@
+-- Your SQL goes here
+CREATE TABLE flow_hourly (
+    hour TIMESTAMP NOT NULL,
+    src TEXT NOT NULL,
+    dst TEXT NOT NULL,
+    bytes BIGINT NOT NULL,
+    PRIMARY Key(hour, src, dst)
+);
+CREATE TABLE edge_baseline (
+    src TEXT NOT NULL,
+    dst TEXT NOT NULL,
+    how SMALLINT NOT NULL,
+    mean DOUBLE PRECISION NOT NULL,
+    stddev DOUBLE PRECISION NOT NULL,
+    samples INT NOT NULL,
+    update_date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY Key(src, dst, how)
+);
+CREATE INDEX edge_baseline_how ON edge_baseline (how);
+CREATE INDEX flow_input_date ON flow (input_date);
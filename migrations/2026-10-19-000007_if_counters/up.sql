@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE if_counters (
+    counter_id SERIAL NOT NULL,
+    input_date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    agent TEXT NOT NULL,
+    if_index INT NOT NULL,
+    if_speed BIGINT NOT NULL,
+    in_octets BIGINT NOT NULL,
+    out_octets BIGINT NOT NULL,
+    PRIMARY Key(counter_id)
+);
+CREATE INDEX if_counters_input_date ON if_counters (input_date);
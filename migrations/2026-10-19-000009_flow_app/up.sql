@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE flow ADD COLUMN app TEXT NOT NULL DEFAULT '';
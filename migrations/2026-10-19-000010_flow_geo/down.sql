@@ -0,0 +1,10 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE flow
+    DROP COLUMN src_country,
+    DROP COLUMN src_city,
+    DROP COLUMN src_asn,
+    DROP COLUMN src_org,
+    DROP COLUMN dst_country,
+    DROP COLUMN dst_city,
+    DROP COLUMN dst_asn,
+    DROP COLUMN dst_org;
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+ALTER TABLE flow
+    ADD COLUMN src_country TEXT NOT NULL DEFAULT '',
+    ADD COLUMN src_city TEXT NOT NULL DEFAULT '',
+    ADD COLUMN src_asn INT NOT NULL DEFAULT 0,
+    ADD COLUMN src_org TEXT NOT NULL DEFAULT '',
+    ADD COLUMN dst_country TEXT NOT NULL DEFAULT '',
+    ADD COLUMN dst_city TEXT NOT NULL DEFAULT '',
+    ADD COLUMN dst_asn INT NOT NULL DEFAULT 0,
+    ADD COLUMN dst_org TEXT NOT NULL DEFAULT '';
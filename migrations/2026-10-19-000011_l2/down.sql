@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE mac_ip;
+ALTER TABLE flow
+    DROP COLUMN vlan,
+    DROP COLUMN in_if,
+    DROP COLUMN out_if;
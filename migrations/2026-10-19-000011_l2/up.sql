@@ -0,0 +1,17 @@
+-- Your SQL goes here
+ALTER TABLE flow
+    ADD COLUMN vlan INT NOT NULL DEFAULT 0,
+    ADD COLUMN in_if INT NOT NULL DEFAULT 0,
+    ADD COLUMN out_if INT NOT NULL DEFAULT 0;
+
+CREATE TABLE mac_ip (
+    mac TEXT NOT NULL,
+    ip TEXT NOT NULL,
+    agent TEXT NOT NULL,
+    vlan INT NOT NULL,
+    in_if INT NOT NULL,
+    first_seen TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    last_seen TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY Key(mac, ip)
+);
+CREATE INDEX mac_ip_ip ON mac_ip (ip);
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE api_token (
+    token_id SERIAL,
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    role TEXT NOT NULL,
+    agents TEXT NOT NULL DEFAULT '',
+    prefixes TEXT NOT NULL DEFAULT '',
+    create_date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    revoked BOOLEAN NOT NULL DEFAULT FALSE,
+    PRIMARY Key(token_id)
+);
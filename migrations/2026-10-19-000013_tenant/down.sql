@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE api_token DROP COLUMN tenant_id;
+DROP INDEX flow_tenant_input_date;
+ALTER TABLE flow DROP COLUMN tenant_id;
+DROP TABLE tenant;
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE tenant (
+    tenant_id SERIAL,
+    name TEXT NOT NULL UNIQUE,
+    agents TEXT NOT NULL DEFAULT '',
+    prefixes TEXT NOT NULL DEFAULT '',
+    create_date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY Key(tenant_id)
+);
+ALTER TABLE flow ADD COLUMN tenant_id INT NOT NULL DEFAULT 0;
+CREATE INDEX flow_tenant_input_date ON flow (tenant_id, input_date);
+ALTER TABLE api_token ADD COLUMN tenant_id INT NOT NULL DEFAULT 0;
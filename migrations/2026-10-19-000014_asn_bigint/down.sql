@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+UPDATE flow SET src_asn = 0 WHERE src_asn > 2147483647;
+UPDATE flow SET dst_asn = 0 WHERE dst_asn > 2147483647;
+ALTER TABLE flow
+    ALTER COLUMN src_asn TYPE INT,
+    ALTER COLUMN dst_asn TYPE INT;
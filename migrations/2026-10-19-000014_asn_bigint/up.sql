@@ -0,0 +1,4 @@
+-- Your SQL goes here, 32-bit ASNs go past INT
+ALTER TABLE flow
+    ALTER COLUMN src_asn TYPE BIGINT,
+    ALTER COLUMN dst_asn TYPE BIGINT;
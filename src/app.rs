@@ -0,0 +1,259 @@
+//! Application classification from protocol, ports and sampled payload
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std;
+use sflow::*;
+
+/// Built-in `proto port name` rules, extended or overridden by `APPS_FILE`.
+const DEFAULT_APPS: &'static str = "
+tcp 20 FTP
+tcp 21 FTP
+tcp 22 SSH
+tcp 23 Telnet
+tcp 25 SMTP
+any 53 DNS
+udp 67 DHCP
+udp 68 DHCP
+tcp 80 HTTP
+tcp 110 POP3
+udp 123 NTP
+tcp 139 SMB
+tcp 143 IMAP
+udp 161 SNMP
+udp 162 SNMP
+tcp 179 BGP
+tcp 389 LDAP
+tcp 443 HTTPS
+udp 443 QUIC
+tcp 445 SMB
+udp 514 Syslog
+tcp 587 SMTP
+tcp 636 LDAPS
+tcp 993 IMAPS
+tcp 995 POP3S
+tcp 1433 MSSQL
+udp 1900 SSDP
+tcp 3306 MySQL
+tcp 3389 RDP
+tcp 5432 PostgreSQL
+udp 5353 mDNS
+tcp 6379 Redis
+udp 6343 sFlow
+tcp 8080 HTTP
+tcp 8443 HTTPS
+tcp 11211 Memcached
+udp 11211 Memcached
+";
+
+const PROTO_TCP: i32 = 6;
+const PROTO_UDP: i32 = 17;
+
+/// Rules are `proto port name` with proto `tcp`, `udp` or `any`, or
+/// `sni suffix name` / `host suffix name` matched against the TLS server
+/// name or HTTP Host found in the sampled header bytes.
+pub struct Classifier {
+    ports: HashMap<(String, i32), String>,
+    names: Vec<(String, String, String)>,
+}
+
+/// Decodes the `headerBytes` hex dump printed by sflowtool.
+pub fn parse_header_bytes(s: &str) -> Vec<u8> {
+    s.split('-').filter_map(|x| u8::from_str_radix(x, 16).ok()).collect()
+}
+
+fn be16(b: &[u8], i: usize) -> Option<usize> {
+    if i + 1 < b.len() {
+        Some(((b[i] as usize) << 8) | b[i + 1] as usize)
+    } else {
+        None
+    }
+}
+
+/// TCP payload of an Ethernet frame carrying IPv4 or IPv6.
+pub fn tcp_payload(frame: &[u8]) -> Option<&[u8]> {
+    let mut off = 12;
+    let mut ethertype = be16(frame, off)?;
+    while ethertype == 0x8100 || ethertype == 0x88a8 {
+        off += 4;
+        ethertype = be16(frame, off)?;
+    }
+    off += 2;
+    let proto;
+    match ethertype {
+        0x0800 => {
+            let ihl = (*frame.get(off)? & 0x0f) as usize * 4;
+            proto = *frame.get(off + 9)?;
+            off += ihl;
+        },
+        0x86dd => {
+            proto = *frame.get(off + 6)?;
+            off += 40;
+        },
+        _ => return None,
+    }
+    if proto != PROTO_TCP as u8 {
+        return None;
+    }
+    let doff = (*frame.get(off + 12)? >> 4) as usize * 4;
+    frame.get(off + doff..)
+}
+
+/// Server name of a TLS ClientHello.
+pub fn tls_sni(p: &[u8]) -> Option<String> {
+    if *p.get(0)? != 0x16 || *p.get(5)? != 0x01 {
+        return None;
+    }
+    // record header, handshake header, version and random
+    let mut i = 5 + 4 + 2 + 32;
+    i += 1 + *p.get(i)? as usize;
+    i += 2 + be16(p, i)?;
+    i += 1 + *p.get(i)? as usize;
+    let end = (i + 2 + be16(p, i)?).min(p.len());
+    i += 2;
+    while i + 4 <= end {
+        let typ = be16(p, i)?;
+        let len = be16(p, i + 2)?;
+        i += 4;
+        if typ == 0 {
+            // server name list: list length, name type, name length, name
+            let n = be16(p, i + 3)?;
+            let name = p.get(i + 5..i + 5 + n)?;
+            return String::from_utf8(name.to_vec()).ok();
+        }
+        i += len;
+    }
+    None
+}
+
+/// Host header of an HTTP request.
+pub fn http_host(p: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(p);
+    let methods = ["GET ", "POST ", "PUT ", "HEAD ", "DELETE ", "OPTIONS ", "PATCH ", "CONNECT "];
+    if !methods.iter().any(|m| text.starts_with(m)) {
+        return None;
+    }
+    for line in text.split("\r\n").skip(1) {
+        if line.get(..5).map(|x| x.eq_ignore_ascii_case("host:")).unwrap_or(false) {
+            let host = line.get(5..).unwrap_or("").trim();
+            let host = host.split(':').next().unwrap_or(host);
+            return Some(host.to_lowercase());
+        }
+    }
+    None
+}
+
+impl Classifier {
+    pub fn new() -> Classifier {
+        let mut c = Classifier { ports: HashMap::new(), names: vec![] };
+        for line in DEFAULT_APPS.lines() {
+            let _ = c.add_rule(line);
+        }
+        c
+    }
+
+    /// Built-in rules plus the file `APPS_FILE` (default `apps.txt`) if present.
+    pub fn load() -> Result<Classifier, Box<std::error::Error>> {
+        let mut c = Classifier::new();
+        let path: String = get_env_or("APPS_FILE", "apps.txt".to_string());
+        if let Ok(f) = File::open(&path) {
+            for (n, line) in BufReader::new(f).lines().enumerate() {
+                c.add_rule(&line?).map_err(|e| format!("{}:{}: {}", path, n + 1, e))?;
+            }
+        }
+        Ok(c)
+    }
+
+    pub fn add_rule(&mut self, line: &str) -> Result<(), String> {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            return Ok(());
+        }
+        let mut it = line.splitn(3, char::is_whitespace);
+        let kind = it.next().unwrap_or("").to_lowercase();
+        let key = it.next().unwrap_or("").trim();
+        let name = it.next().unwrap_or("").trim();
+        if key.is_empty() || name.is_empty() {
+            return Err(format!("expected `proto port name`: {}", line));
+        }
+        match kind.as_str() {
+            "tcp" | "udp" | "any" => {
+                let port: i32 = key.parse().map_err(|_| format!("bad port {}", key))?;
+                self.ports.insert((kind, port), name.to_string());
+            },
+            "sni" | "host" => {
+                self.names.push((kind, key.to_lowercase(), name.to_string()));
+            },
+            _ => return Err(format!("unknown rule type {}", kind)),
+        }
+        Ok(())
+    }
+
+    fn by_name(&self, kind: &str, name: &str) -> Option<String> {
+        self.names.iter()
+            .find(|x| x.0 == kind && (name == x.1 || name.ends_with(&format!(".{}", x.1.trim_start_matches('.')))))
+            .map(|x| x.2.clone())
+    }
+
+    fn by_port(&self, proto: &str, port: i32) -> Option<String> {
+        self.ports.get(&(proto.to_string(), port))
+            .or_else(|| self.ports.get(&("any".to_string(), port)))
+            .cloned()
+    }
+
+    /// Application name of a sample, empty when unknown.
+    pub fn classify(&self, s: &SampleV5, f: &FlowDirection) -> String {
+        if let Some(ref hex) = s.headerBytes {
+            let frame = parse_header_bytes(hex);
+            if let Some(p) = tcp_payload(&frame) {
+                if let Some(sni) = tls_sni(p) {
+                    return self.by_name("sni", &sni.to_lowercase()).unwrap_or("HTTPS".to_string());
+                }
+                if let Some(host) = http_host(p) {
+                    return self.by_name("host", &host).unwrap_or("HTTP".to_string());
+                }
+            }
+        }
+        let proto = match s.IPProtocol {
+            Some(PROTO_TCP) => "tcp",
+            Some(PROTO_UDP) => "udp",
+            _ if s.TCPSrcPort.is_some() => "tcp",
+            _ if s.UDPSrcPort.is_some() => "udp",
+            _ => return String::new(),
+        };
+        // the lower port is usually the service
+        let (lo, hi) = if f.srcport < f.dstport { (f.srcport, f.dstport) } else { (f.dstport, f.srcport) };
+        self.by_port(proto, lo)
+            .or_else(|| self.by_port(proto, hi))
+            .unwrap_or_default()
+    }
+}
+
+/// Keeps only the flows of one application.
+pub fn filter_app(fm: &mut FlowMap, app: &str) {
+    let rms: Vec<String> = fm.iter().filter(|x| x.1.app != app).map(|x| x.0.clone()).collect();
+    for n in rms.iter() {
+        fm.remove(n);
+    }
+}
+
+/// Name of the middle node of an application stage.
+pub fn app_node(app: &str) -> String {
+    format!("[{}]", if app.is_empty() { "unknown" } else { app })
+}
+
+/// Turns every `src => dst` flow into `src => [app]` and `[app] => dst`
+/// so the Sankey gets an application column between sources and targets.
+pub fn stage_app(fm: &FlowMap) -> FlowMap {
+    let mut res = FlowMap::new();
+    for fd in fm.values() {
+        let node = app_node(&fd.app);
+        let mut left = fd.clone();
+        left.target = node.clone();
+        let mut right = fd.clone();
+        right.source = node;
+        merge_flow(&mut res, &format!("{}=>{}", left.source, left.target), &left);
+        merge_flow(&mut res, &format!("{}=>{}", right.source, right.target), &right);
+    }
+    res
+}
@@ -0,0 +1,216 @@
+//! Bearer tokens and the agents, prefixes and tenant a token may query
+use std;
+use actix_web::HttpRequest;
+use actix_web::http::header;
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use openssl::memcmp;
+use openssl::rand::rand_bytes;
+use openssl::sha::sha256;
+use prefix::{self, Prefix};
+use schema;
+use tenant::{TenantMap, NO_TENANT};
+use sflow::*;
+use models;
+
+pub const ROLE_ADMIN: &'static str = "admin";
+pub const ROLE_VIEWER: &'static str = "viewer";
+pub const ROLES: [&'static str; 2] = [ROLE_ADMIN, ROLE_VIEWER];
+
+/// A token to create, as posted to `/admin/tokens`.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct TokenParams {
+    pub name: String,
+    pub role: String,
+    /// Agents the token may query, all when empty.
+    #[serde(default)]
+    pub agents: Vec<String>,
+    /// Prefixes one end of every edge has to be in, all when empty.
+    #[serde(default)]
+    pub prefixes: Vec<String>,
+    /// Tenant whose flows the token is limited to, see `tenant`.
+    #[serde(default)]
+    pub tenant_id: Option<i32>,
+}
+
+/// A token as listed by `/admin/tokens`; `token` is only set once, in the
+/// response to its creation.
+#[derive(Debug, Serialize, Clone)]
+pub struct TokenInfo {
+    pub token_id: i32,
+    pub name: String,
+    pub role: String,
+    pub agents: Vec<String>,
+    pub prefixes: Vec<String>,
+    pub create_date: String,
+    pub revoked: bool,
+    pub tenant_id: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+impl TokenInfo {
+    pub fn new(x: &models::ApiToken) -> TokenInfo {
+        TokenInfo {
+            token_id: x.token_id,
+            name: x.name.clone(),
+            role: x.role.clone(),
+            agents: split_list(&x.agents),
+            prefixes: split_list(&x.prefixes),
+            create_date: x.create_date.format("%Y-%m-%d %H:%M:%S").to_string(),
+            revoked: x.revoked,
+            tenant_id: if x.tenant_id == NO_TENANT { None } else { Some(x.tenant_id) },
+            token: None,
+        }
+    }
+}
+
+/// What a request may see.
+#[derive(Debug, Clone)]
+pub struct Scope {
+    pub role: String,
+    pub agents: Vec<String>,
+    pub prefixes: Vec<Prefix>,
+    pub tenant_id: Option<i32>,
+}
+
+impl Scope {
+    /// The scope of `ADMIN_TOKEN` and of the command line.
+    pub fn all() -> Scope {
+        Scope { role: ROLE_ADMIN.to_string(), agents: vec![], prefixes: vec![], tenant_id: None }
+    }
+
+    /// The scope of requests without a token while `auth_required` is off,
+    /// which may read everything but administer nothing.
+    pub fn anonymous() -> Scope {
+        Scope { role: ROLE_VIEWER.to_string(), agents: vec![], prefixes: vec![], tenant_id: None }
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.role == ROLE_ADMIN
+    }
+
+    /// Whether no agent, prefix or tenant restricts the scope.
+    pub fn is_unrestricted(&self) -> bool {
+        self.agents.is_empty() && self.prefixes.is_empty() && self.tenant_id.is_none()
+    }
+
+    pub fn allows_agent(&self, agent: &str) -> bool {
+        self.agents.is_empty() || self.agents.iter().any(|x| x == agent)
+    }
+
+    pub fn allows_edge(&self, agent: &str, src: &str, dst: &str, tenant_id: i32) -> bool {
+        self.tenant_id.map(|x| x == tenant_id).unwrap_or(true) &&
+            self.allows_agent(agent) &&
+            (self.prefixes.is_empty() ||
+                prefix::any_contains(&self.prefixes, src) ||
+                prefix::any_contains(&self.prefixes, dst))
+    }
+
+    pub fn allows(&self, fd: &FlowDirection) -> bool {
+        self.allows_edge(&fd.agent, &fd.source, &fd.target, fd.tenant_id)
+    }
+
+    /// Whether an incident, scan or MAC binding of `agent` about `addrs` is
+    /// in the scope; `tenants` finds its tenant and has to be loaded for a
+    /// tenant scope.
+    pub fn allows_event(&self, agent: &str, addrs: &[&str], tenants: Option<&TenantMap>) -> bool {
+        let tenant_ok = match (self.tenant_id, tenants) {
+            (None, _) => true,
+            (Some(t), Some(m)) => m.owner(agent, addrs) == t,
+            (Some(_), None) => false,
+        };
+        tenant_ok && self.allows_agent(agent) &&
+            (self.prefixes.is_empty() || addrs.iter().any(|x| prefix::any_contains(&self.prefixes, x)))
+    }
+}
+
+fn split_list(s: &str) -> Vec<String> {
+    s.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()).map(|x| x.to_string()).collect()
+}
+
+fn hex(b: &[u8]) -> String {
+    b.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+/// Tokens are only stored as their SHA-256.
+pub fn hash_token(token: &str) -> String {
+    hex(&sha256(token.as_bytes()))
+}
+
+pub fn generate_token() -> Result<String, Box<std::error::Error>> {
+    let mut buf = [0u8; 32];
+    rand_bytes(&mut buf)?;
+    Ok(hex(&buf))
+}
+
+/// Token of an `Authorization: Bearer ...` header.
+pub fn bearer<S>(req: &HttpRequest<S>) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let mut it = value.splitn(2, ' ');
+    match (it.next(), it.next()) {
+        (Some(kind), Some(token)) if kind.eq_ignore_ascii_case("bearer") => Some(token.trim().to_string()),
+        _ => None,
+    }
+}
+
+pub fn validate(p: &TokenParams) -> Result<(), String> {
+    if p.name.trim().is_empty() {
+        return Err("name is required".to_string());
+    }
+    if !ROLES.contains(&p.role.as_str()) {
+        return Err(format!("role must be one of {}", ROLES.join(", ")));
+    }
+    for x in p.prefixes.iter() {
+        Prefix::parse(x)?;
+    }
+    if p.role == ROLE_ADMIN && p.tenant_id.is_some() {
+        return Err("an admin token cannot belong to a tenant".to_string());
+    }
+    Ok(())
+}
+
+/// Asks a `DbExecutor` for the scope of a token, for handlers that do not
+/// otherwise go through the database.
+#[derive(Debug, Clone)]
+pub struct Authorize {
+    pub token: Option<String>,
+}
+
+/// Settings of `authorize`, from the `[server]` section of the configuration.
+#[derive(Debug, Clone, Copy)]
+pub struct AuthConfig {
+    /// Whether requests without a token are refused.
+    pub required: bool,
+}
+
+/// The scope of `token`, `None` when it is unknown or revoked, or missing
+/// while `required` is on. `ADMIN_TOKEN` is always an admin, a missing
+/// token never is.
+pub fn authorize(conn: &PgConnection, cfg: &AuthConfig, token: Option<&str>) -> Result<Option<Scope>, Box<std::error::Error>> {
+    use self::schema::api_token::dsl::*;
+    let token = match token {
+        Some(x) => x,
+        None if cfg.required => return Ok(None),
+        None => return Ok(Some(Scope::anonymous())),
+    };
+    let hash = hash_token(token);
+    let admin: String = get_env_or("ADMIN_TOKEN", String::new());
+    if !admin.is_empty() && memcmp::eq(hash_token(&admin).as_bytes(), hash.as_bytes()) {
+        return Ok(Some(Scope::all()));
+    }
+    let row = api_token
+        .filter(token_hash.eq(&hash))
+        .filter(revoked.eq(false))
+        .first::<models::ApiToken>(conn)
+        .optional()?;
+    match row {
+        Some(x) => Ok(Some(Scope {
+            role: x.role.clone(),
+            agents: split_list(&x.agents),
+            prefixes: prefix::parse_list(&x.prefixes)?,
+            tenant_id: if x.tenant_id == NO_TENANT { None } else { Some(x.tenant_id) },
+        })),
+        None => Ok(None),
+    }
+}
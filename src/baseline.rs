@@ -0,0 +1,117 @@
+//! Hourly rollups, seasonal (hour-of-week) baselines and anomaly scores per edge
+use std::collections::{BTreeMap, BTreeSet};
+use std::thread;
+use std::time::Duration;
+use std;
+use chrono::{Datelike, NaiveDateTime, Timelike, Duration as ChronoDuration};
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use diesel::sql_query;
+use db::DBPool;
+use schema;
+use sflow::*;
+use models;
+
+/// Rolls up complete hours of `flow` into `flow_hourly`.
+const ROLLUP_SQL: &'static str = "INSERT INTO flow_hourly (hour, src, dst, bytes)
+    SELECT date_trunc('hour', input_date), src, dst, SUM(size)
+    FROM flow
+    WHERE input_date >= COALESCE((SELECT MAX(hour) + interval '1 hour' FROM flow_hourly),
+            date_trunc('hour', CURRENT_TIMESTAMP) - interval '{weeks} weeks')
+        AND input_date < date_trunc('hour', CURRENT_TIMESTAMP)
+    GROUP BY 1, 2, 3
+    ON CONFLICT (hour, src, dst) DO UPDATE SET bytes = EXCLUDED.bytes";
+
+/// Mean and standard deviation per edge and hour of the week. Hours
+/// without traffic count as zero, over as many weeks as there is data.
+const BASELINE_SQL: &'static str = "WITH span AS (
+        SELECT GREATEST(1, LEAST({weeks}, CEIL(EXTRACT(EPOCH FROM
+            (date_trunc('hour', CURRENT_TIMESTAMP) - MIN(hour))) / 604800)))::float8 AS weeks
+        FROM flow_hourly WHERE hour >= CURRENT_TIMESTAMP - interval '{weeks} weeks')
+    INSERT INTO edge_baseline (src, dst, how, mean, stddev, samples)
+    SELECT src, dst, (EXTRACT(DOW FROM hour) * 24 + EXTRACT(HOUR FROM hour))::smallint,
+        SUM(bytes)::float8 / span.weeks,
+        SQRT(GREATEST(SUM(bytes::float8 * bytes) / span.weeks - POWER(SUM(bytes)::float8 / span.weeks, 2), 0)),
+        COUNT(*)
+    FROM flow_hourly, span
+    WHERE hour >= CURRENT_TIMESTAMP - interval '{weeks} weeks'
+    GROUP BY 1, 2, 3, span.weeks
+    ON CONFLICT (src, dst, how) DO UPDATE SET mean = EXCLUDED.mean, stddev = EXCLUDED.stddev,
+        samples = EXCLUDED.samples, update_date = CURRENT_TIMESTAMP";
+
+/// Refreshes rollups and baselines every `BASELINE_INTERVAL` seconds.
+pub fn run(pool: DBPool) {
+    let interval: u64 = get_env_or("BASELINE_INTERVAL", 3600);
+    let weeks: i64 = get_env_or("BASELINE_WEEKS", 4);
+    loop {
+        if let Err(x) = refresh(&pool, weeks) {
+            info!("baseline refresh: {}", x);
+        }
+        thread::sleep(Duration::from_secs(interval));
+    }
+}
+
+fn refresh(pool: &DBPool, weeks: i64) -> Result<(), Box<std::error::Error>> {
+    let conn: &PgConnection = &*pool.get()?;
+    let weeks = weeks.to_string();
+    sql_query(ROLLUP_SQL.replace("{weeks}", &weeks)).execute(conn)?;
+    sql_query(BASELINE_SQL.replace("{weeks}", &weeks)).execute(conn)?;
+    sql_query(format!("DELETE FROM flow_hourly WHERE hour < CURRENT_TIMESTAMP - interval '{} weeks'", weeks))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Hour of the week as counted by PostgreSQL, 0 is Sunday 00:00.
+pub fn hour_of_week(t: NaiveDateTime) -> i16 {
+    (t.weekday().num_days_from_sunday() * 24 + t.hour()) as i16
+}
+
+/// Hours of the week touched by `up..dn`, with the number of times each
+/// one occurs.
+fn hours_in_range(up: NaiveDateTime, dn: NaiveDateTime) -> BTreeMap<i16, i64> {
+    let mut hours = BTreeMap::new();
+    let mut t = up;
+    let mut n = 0;
+    while t <= dn && n < 168 * 5 {
+        *hours.entry(hour_of_week(t)).or_insert(0) += 1;
+        t = t + ChronoDuration::hours(1);
+        n += 1;
+    }
+    hours
+}
+
+/// Sets `anomaly` on every link to the z-score of its hourly rate against
+/// the baseline of the hours of the week covered by `up..dn`.
+pub fn score_links(conn: &PgConnection, up: NaiveDateTime, dn: NaiveDateTime,
+        nodes: &Vec<FlowName>, links: &mut Vec<FlowDirection2>) -> Result<(), Box<std::error::Error>> {
+    use self::schema::edge_baseline::dsl::*;
+    let secs = (dn - up).num_seconds().max(1) as f64;
+    let hours = hours_in_range(up, dn);
+    let total: i64 = hours.values().sum();
+    let hows: Vec<i16> = hours.keys().cloned().collect();
+    let names: BTreeSet<&str> = nodes.iter().map(|x| x.name.as_str()).collect();
+    let rows = edge_baseline
+        .filter(how.eq_any(hows))
+        .load::<models::EdgeBaseline>(conn)?;
+    // (src, dst) => (weighted mean, weighted variance)
+    let mut base: BTreeMap<(String, String), (f64, f64)> = BTreeMap::new();
+    for r in rows.iter() {
+        if !names.contains(r.src.as_str()) || !names.contains(r.dst.as_str()) {
+            continue;
+        }
+        let w = *hours.get(&r.how).unwrap_or(&0) as f64 / total.max(1) as f64;
+        let e = base.entry((r.src.clone(), r.dst.clone())).or_insert((0.0, 0.0));
+        e.0 += w * r.mean;
+        e.1 += w * r.stddev * r.stddev;
+    }
+    for l in links.iter_mut() {
+        let key = (nodes[l.source as usize].name.clone(), nodes[l.target as usize].name.clone());
+        if let Some(&(m, v)) = base.get(&key) {
+            let rate = l.value as f64 * 3600.0 / secs;
+            // keep a floor on the deviation so quiet edges do not explode
+            let sd = v.sqrt().max(m * 0.1).max(1.0);
+            l.anomaly = Some(((rate - m) / sd) as f32);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,271 @@
+//! 95th-percentile billing reports per interface, agent and customer
+use std::collections::BTreeMap;
+use std::fs::File;
+use std;
+use chrono::{Datelike, NaiveDate, NaiveDateTime};
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use diesel::sql_query;
+use diesel::sql_types::{Array, BigInt, Integer, Text, Timestamp};
+use serde_json;
+use prefix::{self, Prefix};
+use auth::Scope;
+use tenant::NO_TENANT;
+use schema;
+use sflow::get_env_or;
+use models;
+
+/// Length of one rate sample in seconds.
+pub const SAMPLE_SECS: i64 = 300;
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct BillingParams {
+    #[serde(default)]
+    pub month: Option<String>,
+    #[serde(default)]
+    pub format: Option<String>,
+    /// Bearer token of the request, see `auth`.
+    #[serde(skip)]
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Clone)]
+pub struct BillingRow {
+    pub kind: String,
+    pub name: String,
+    pub direction: String,
+    pub p95_bps: f64,
+    pub avg_bps: f64,
+    pub peak_bps: f64,
+    pub total_bytes: i64,
+}
+
+#[derive(QueryableByName, Debug)]
+struct BucketBytes {
+    #[sql_type = "Text"]
+    name: String,
+    #[sql_type = "BigInt"]
+    bucket: i64,
+    #[sql_type = "BigInt"]
+    bytes: i64,
+}
+
+/// First and last (exclusive) instant of `YYYY-MM`, the month of `now` by
+/// default; a running month ends at `now`.
+pub fn month_range(month: Option<&str>, now: NaiveDateTime) -> Result<(NaiveDateTime, NaiveDateTime), String> {
+    let start = match month {
+        Some(m) => NaiveDate::parse_from_str(&format!("{}-01", m), "%Y-%m-%d")
+            .map_err(|_| format!("bad month {}, expected YYYY-MM", m))?,
+        None => NaiveDate::from_ymd(now.year(), now.month(), 1),
+    };
+    let next = if start.month() == 12 {
+        NaiveDate::from_ymd_opt(start.year() + 1, 1, 1)
+    } else {
+        NaiveDate::from_ymd_opt(start.year(), start.month() + 1, 1)
+    };
+    let next = next.ok_or_else(|| format!("month {} is out of range", start.format("%Y-%m")))?;
+    let up = start.and_hms(0, 0, 0);
+    let dn = next.and_hms(0, 0, 0).min(now);
+    if dn <= up {
+        return Err(format!("month {} has not started", start.format("%Y-%m")));
+    }
+    Ok((up, dn))
+}
+
+/// Customer name => prefixes, read from the JSON file `BILLING_CUSTOMERS`.
+pub fn load_customers() -> Result<BTreeMap<String, Vec<Prefix>>, Box<std::error::Error>> {
+    let path: String = get_env_or("BILLING_CUSTOMERS", "customers.json".to_string());
+    let raw: BTreeMap<String, Vec<String>> = match File::open(&path) {
+        Ok(f) => serde_json::from_reader(f)?,
+        Err(_) => return Ok(BTreeMap::new()),
+    };
+    let mut res = BTreeMap::new();
+    for (name, list) in raw {
+        res.insert(name, prefix::parse_list(&list.join(","))?);
+    }
+    Ok(res)
+}
+
+/// Summarizes the byte counts of the 5 minute samples of a period;
+/// samples without data count as zero.
+fn summarize(kind: &str, name: &str, direction: &str, samples: &BTreeMap<i64, i64>, count: usize) -> BillingRow {
+    let mut rates: Vec<f64> = samples.values().map(|x| *x as f64 * 8.0 / SAMPLE_SECS as f64).collect();
+    let count = count.max(rates.len()).max(1);
+    rates.resize(count, 0.0);
+    rates.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let idx = ((count as f64 * 0.95).ceil() as usize).max(1) - 1;
+    let total: i64 = samples.values().sum();
+    BillingRow {
+        kind: kind.to_string(),
+        name: name.to_string(),
+        direction: direction.to_string(),
+        p95_bps: rates[idx],
+        avg_bps: total as f64 * 8.0 / (count as i64 * SAMPLE_SECS) as f64,
+        peak_bps: rates[count - 1],
+        total_bytes: total,
+    }
+}
+
+fn bucket_bytes(conn: &PgConnection, column: &str, up: NaiveDateTime, dn: NaiveDateTime, scope: &Scope)
+        -> Result<Vec<BucketBytes>, Box<std::error::Error>> {
+    let q = format!("SELECT {} AS name, (EXTRACT(EPOCH FROM input_date)::bigint / {}) AS bucket, \
+        SUM(size)::bigint AS bytes FROM flow WHERE input_date >= $1 AND input_date < $2 \
+        AND ($3 = {} OR tenant_id = $3) AND (cardinality($4) = 0 OR agent = ANY($4)) GROUP BY 1, 2",
+        column, SAMPLE_SECS, NO_TENANT);
+    Ok(sql_query(q)
+        .bind::<Timestamp, _>(up)
+        .bind::<Timestamp, _>(dn)
+        .bind::<Integer, _>(scope.tenant_id.unwrap_or(NO_TENANT))
+        .bind::<Array<Text>, _>(scope.agents.clone())
+        .load::<BucketBytes>(conn)?)
+}
+
+/// Rows visible to `scope`: interfaces and agents are shared by tenants
+/// and only reported to tokens without tenant or prefix restrictions,
+/// customer traffic is limited to the addresses of the scope.
+pub fn build_report(conn: &PgConnection, up: NaiveDateTime, dn: NaiveDateTime, scope: &Scope)
+        -> Result<Vec<BillingRow>, Box<std::error::Error>> {
+    let shared = scope.tenant_id.is_none() && scope.prefixes.is_empty();
+    let count = ((dn - up).num_seconds() / SAMPLE_SECS) as usize;
+    let mut rows: Vec<BillingRow> = vec![];
+
+    // interfaces, from the deltas of the octet counters
+    let counters = {
+        use self::schema::if_counters::dsl::*;
+        if_counters
+            .filter(input_date.ge(up))
+            .filter(input_date.lt(dn))
+            .order((agent.asc(), if_index.asc(), input_date.asc()))
+            .load::<models::IfCounter>(conn)?
+    };
+    let mut ifaces: BTreeMap<String, (BTreeMap<i64, i64>, BTreeMap<i64, i64>)> = BTreeMap::new();
+    for w in counters.windows(2) {
+        let (a, b) = (&w[0], &w[1]);
+        if a.agent != b.agent || a.if_index != b.if_index {
+            continue;
+        }
+        let bucket = b.input_date.timestamp() / SAMPLE_SECS;
+        let e = ifaces.entry(format!("{}/{}", b.agent, b.if_index)).or_insert_with(Default::default);
+        // a negative delta is a counter reset, not traffic
+        if b.in_octets >= a.in_octets {
+            *e.0.entry(bucket).or_insert(0) += b.in_octets - a.in_octets;
+        }
+        if b.out_octets >= a.out_octets {
+            *e.1.entry(bucket).or_insert(0) += b.out_octets - a.out_octets;
+        }
+    }
+    if shared {
+        for (name, &(ref i, ref o)) in ifaces.iter() {
+            let agent = name.split('/').next().unwrap_or("");
+            if scope.agents.is_empty() || scope.agents.iter().any(|x| x == agent) {
+                rows.push(summarize("interface", name, "in", i, count));
+                rows.push(summarize("interface", name, "out", o, count));
+            }
+        }
+
+        // agents, from the sampled flows
+        let mut agents: BTreeMap<String, BTreeMap<i64, i64>> = BTreeMap::new();
+        for x in bucket_bytes(conn, "agent", up, dn, scope)? {
+            *agents.entry(x.name).or_insert_with(BTreeMap::new).entry(x.bucket).or_insert(0) += x.bytes;
+        }
+        for (name, samples) in agents.iter() {
+            rows.push(summarize("agent", name, "total", samples, count));
+        }
+    }
+
+    // customers, traffic to ("in") and from ("out") their prefixes
+    let customers = load_customers()?;
+    if !customers.is_empty() {
+        for &(column, direction) in [("dst", "in"), ("src", "out")].iter() {
+            let mut samples: BTreeMap<String, BTreeMap<i64, i64>> = BTreeMap::new();
+            for x in bucket_bytes(conn, column, up, dn, scope)? {
+                if !scope.prefixes.is_empty() && !prefix::any_contains(&scope.prefixes, &x.name) {
+                    continue;
+                }
+                for (name, prefixes) in customers.iter() {
+                    if prefix::any_contains(prefixes, &x.name) {
+                        *samples.entry(name.clone()).or_insert_with(BTreeMap::new)
+                            .entry(x.bucket).or_insert(0) += x.bytes;
+                    }
+                }
+            }
+            for name in customers.keys() {
+                let empty = BTreeMap::new();
+                rows.push(summarize("customer", name, direction, samples.get(name).unwrap_or(&empty), count));
+            }
+        }
+    }
+    Ok(rows)
+}
+
+pub fn to_csv(rows: &Vec<BillingRow>) -> String {
+    let mut out = String::from("kind,name,direction,p95_bps,avg_bps,peak_bps,total_bytes\n");
+    for r in rows.iter() {
+        out.push_str(&format!("{},\"{}\",{},{:.0},{:.0},{:.0},{}\n",
+            r.kind, r.name.replace('"', "\"\""), r.direction, r.p95_bps, r.avg_bps, r.peak_bps, r.total_bytes));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(y: i32, m: u32, d: u32, h: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(y, m, d).and_hms(h, 0, 0)
+    }
+
+    /// Samples of `bytes` per bucket, in buckets 0, 1, ...
+    fn samples(bytes: &[i64]) -> BTreeMap<i64, i64> {
+        bytes.iter().enumerate().map(|(i, x)| (i as i64, *x)).collect()
+    }
+
+    #[test]
+    fn months() {
+        let now = at(2026, 10, 19, 12);
+        assert_eq!(month_range(Some("2026-09"), now), Ok((at(2026, 9, 1, 0), at(2026, 10, 1, 0))));
+        assert_eq!(month_range(Some("2025-12"), now), Ok((at(2025, 12, 1, 0), at(2026, 1, 1, 0))));
+        assert_eq!(month_range(None, now), Ok((at(2026, 10, 1, 0), now)));
+        assert!(month_range(Some("2026-11"), now).is_err());
+        assert!(month_range(Some("2026-13"), now).is_err());
+        assert!(month_range(Some("october"), now).is_err());
+        assert!(month_range(Some("262143-12"), now).is_err());
+    }
+
+    #[test]
+    fn percentile_of_known_samples() {
+        // 37500 bytes in 300 seconds are 1000 bps
+        let bytes: Vec<i64> = (1..21).rev().map(|x| x * 37500).collect();
+        let r = summarize("agent", "a", "total", &samples(&bytes), 20);
+        // the 19th of 20 sorted rates, the top 5% is not billed
+        assert_eq!(r.p95_bps, 19000.0);
+        assert_eq!(r.peak_bps, 20000.0);
+        assert_eq!(r.avg_bps, 10500.0);
+        assert_eq!(r.total_bytes, 210 * 37500);
+        assert_eq!((r.kind.as_str(), r.name.as_str(), r.direction.as_str()), ("agent", "a", "total"));
+    }
+
+    #[test]
+    fn missing_samples_count_as_zero() {
+        // 3 busy samples of 100 are within the unbilled top 5%
+        let r = summarize("customer", "c", "in", &samples(&[37500, 37500, 37500]), 100);
+        assert_eq!(r.p95_bps, 0.0);
+        assert_eq!(r.peak_bps, 1000.0);
+        assert_eq!(r.avg_bps, 30.0);
+        // 6 of 100 are not
+        let r = summarize("customer", "c", "in", &samples(&[37500; 6]), 100);
+        assert_eq!(r.p95_bps, 1000.0);
+        // one sample of 10 is the 95th percentile
+        let r = summarize("customer", "c", "in", &samples(&[75000]), 10);
+        assert_eq!(r.p95_bps, 2000.0);
+    }
+
+    #[test]
+    fn empty_periods() {
+        let r = summarize("interface", "a/1", "out", &BTreeMap::new(), 0);
+        assert_eq!((r.p95_bps, r.avg_bps, r.peak_bps, r.total_bytes), (0.0, 0.0, 0.0, 0));
+        // more samples than the period holds are all kept
+        let r = summarize("interface", "a/1", "out", &samples(&[37500, 75000]), 1);
+        assert_eq!((r.p95_bps, r.peak_bps), (2000.0, 2000.0));
+    }
+}
@@ -0,0 +1,54 @@
+//! HTTP caching of answers about closed time ranges
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use actix_web::http::header;
+use openssl::sha::sha256;
+use serde::Serialize;
+use serde_json;
+use error::ApiError;
+use sflow::*;
+
+/// Whether `If-None-Match` is `*` or lists `etag`, weak or not.
+fn not_modified<S>(req: &HttpRequest<S>, etag: &str) -> bool {
+    req.headers().get_all(header::IF_NONE_MATCH).iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .map(|x| x.trim())
+        .any(|x| x == "*" || x.trim_start_matches("W/") == etag)
+}
+
+/// Cache-Control of a closed range. Only answers everybody gets alike may
+/// be kept by shared caches: the scope may come from a client certificate
+/// or a `?token=` as well as from the `Authorization` header.
+fn cache_control(shared: bool, max_age: u32) -> String {
+    format!("{}, max-age={}", if shared { "public" } else { "private" }, max_age)
+}
+
+/// Answers `value` as JSON. Answers about a closed range get an ETag and a
+/// max-age of `CACHE_MAX_AGE` seconds, public only when `shared` says that
+/// no scope restricted them, and a 304 when the client already holds them.
+pub fn json_response<S, T: Serialize>(req: &HttpRequest<S>, value: &T, closed: bool, shared: bool) -> HttpResponse {
+    if !closed {
+        return HttpResponse::Ok()
+            .header(header::CACHE_CONTROL, "no-cache")
+            .json(value);
+    }
+    let body = match serde_json::to_vec(value) {
+        Ok(x) => x,
+        Err(e) => return ApiError::Internal(e.to_string()).error_response(),
+    };
+    let etag = format!("\"{}\"", sha256(&body)[..16].iter().map(|x| format!("{:02x}", x)).collect::<String>());
+    let cache_control = cache_control(shared, get_env_or("CACHE_MAX_AGE", 86400u32));
+    if not_modified(req, &etag) {
+        return HttpResponse::NotModified()
+            .header(header::ETAG, etag)
+            .header(header::CACHE_CONTROL, cache_control)
+            .header(header::VARY, "Authorization")
+            .finish();
+    }
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .header(header::ETAG, etag)
+        .header(header::CACHE_CONTROL, cache_control)
+        .header(header::VARY, "Authorization")
+        .body(body)
+}
@@ -0,0 +1,208 @@
+//! Command line: `serve`, `ingest`, `query`, `migrate` and `replay`; without
+//! a subcommand the configured ingest and the API run in one process
+use std::sync::{Arc, Mutex};
+use std;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use config::{self, Config, IngestConfig};
+use db;
+use flow::FlowParams;
+use ingest::{self, Source};
+use auth::Scope;
+use names::{Names, NamesConfig};
+use window::SlidingWindow;
+use heavy::HeavyHitters;
+use migrate;
+use sflow::*;
+use serde_json;
+
+pub fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("sflow_read")
+        .about("Stores sflowtool output in PostgreSQL and serves it as Sankey diagrams")
+        .arg(Arg::with_name("config")
+            .short("c")
+            .long("config")
+            .value_name("FILE")
+            .takes_value(true)
+            .global(true)
+            .help("Configuration file, SFLOW_CONFIG or sflow.toml by default"))
+        .subcommand(SubCommand::with_name("serve")
+            .about("Runs the HTTP API only"))
+        .subcommand(SubCommand::with_name("ingest")
+            .about("Runs the ingest of one source only")
+            .arg(Arg::with_name("source")
+                .long("source")
+                .takes_value(true)
+                .possible_values(&config::SOURCES))
+            .arg(Arg::with_name("path")
+                .long("path")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("sflowtool output for --source file, a capture for --source pcap"))
+            .arg(Arg::with_name("port")
+                .long("port")
+                .takes_value(true)
+                .help("UDP port for --source udp")))
+        .subcommand(SubCommand::with_name("migrate")
+            .about("Applies the database migrations built into the binary")
+            .arg(Arg::with_name("check")
+                .long("check")
+                .help("Only compares the database schema with the binary")))
+        .subcommand(SubCommand::with_name("replay")
+            .about("Stores saved sflowtool output or .pcap captures, in order, and exits")
+            .arg(Arg::with_name("files")
+                .value_name("FILE")
+                .required(true)
+                .multiple(true)))
+        .subcommand(SubCommand::with_name("query")
+            .about("Prints the Sankey of a time range")
+            .arg(Arg::with_name("from")
+                .long("from")
+                .value_name("DATE")
+                .takes_value(true)
+                .required_unless("range")
+                .help("RFC 3339, epoch seconds, %Y-%m-%d %H:%M:%S or now-15m"))
+            .arg(Arg::with_name("to")
+                .long("to")
+                .value_name("DATE")
+                .takes_value(true)
+                .help("Now by default"))
+            .arg(Arg::with_name("range")
+                .long("range")
+                .takes_value(true)
+                .conflicts_with_all(&["from", "to"])
+                .help("last 15m, today or yesterday"))
+            .arg(Arg::with_name("tz")
+                .long("tz")
+                .takes_value(true)
+                .help("local, UTC, +08:00 or an IANA name, for dates without an offset"))
+            .arg(Arg::with_name("time-field")
+                .long("time-field")
+                .takes_value(true)
+                .possible_values(&["input_date", "utc"]))
+            .arg(Arg::with_name("app")
+                .long("app")
+                .takes_value(true))
+            .arg(Arg::with_name("stage")
+                .long("stage")
+                .takes_value(true)
+                .possible_values(&["app"]))
+            .arg(Arg::with_name("group-by")
+                .long("group-by")
+                .takes_value(true)
+                .possible_values(&["country", "asn"]))
+            .arg(Arg::with_name("mode")
+                .long("mode")
+                .takes_value(true)
+                .possible_values(&["l3", "l2"]))
+            .arg(Arg::with_name("foreign-only")
+                .long("foreign-only"))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["json", "table"])
+                .default_value("json")))
+}
+
+/// `--config`, before or after the subcommand.
+pub fn config_path(m: &ArgMatches) -> String {
+    m.value_of("config")
+        .or_else(|| m.subcommand().1.and_then(|x| x.value_of("config")))
+        .map(|x| x.to_string())
+        .unwrap_or_else(|| get_env_or("SFLOW_CONFIG", "sflow.toml".to_string()))
+}
+
+/// Overrides the configured source with the `ingest` arguments.
+pub fn apply_ingest(cfg: &mut IngestConfig, m: &ArgMatches) -> Result<(), Box<std::error::Error>> {
+    if let Some(x) = m.value_of("source") {
+        cfg.source = x.to_string();
+    }
+    if let Some(x) = m.value_of("path") {
+        cfg.path = x.to_string();
+    }
+    if let Some(x) = m.value_of("port") {
+        cfg.udp_port = x.parse().map_err(|_| format!("--port: bad port {}", x))?;
+    }
+    Ok(())
+}
+
+/// A connection to a database of the schema of the binary.
+/// Connects after checking the schema version. Only the subcommands that
+/// write may migrate, with `auto_migrate` set.
+fn connect(config: &Config, write: bool) -> Result<PgConnection, Box<std::error::Error>> {
+    let conn = PgConnection::establish(&config.database_url()?)?;
+    migrate::ensure(&conn, write && config.database.auto_migrate)?;
+    Ok(conn)
+}
+
+/// Sinks of an ingest without the API, nobody reads them.
+fn local_sinks(cfg: &IngestConfig) -> IngestSinks {
+    IngestSinks {
+        window: Arc::new(SlidingWindow::new(cfg.window_secs)),
+        heavy: Arc::new(Mutex::new(HeavyHitters::new(cfg.heavy_epoch_secs))),
+    }
+}
+
+pub fn ingest(config: &Config) -> Result<(), Box<std::error::Error>> {
+    let conn = connect(config, true)?;
+    let sinks = local_sinks(&config.ingest);
+    ingest::run(&conn, &sinks, &config.ingest, &Source::from_config(&config.ingest))
+}
+
+pub fn replay(config: &Config, m: &ArgMatches) -> Result<(), Box<std::error::Error>> {
+    let conn = connect(config, true)?;
+    let sinks = local_sinks(&config.ingest);
+    let cfg = IngestConfig { replay: true, ..config.ingest.clone() };
+    for x in m.values_of("files").into_iter().flat_map(|x| x) {
+        ingest::run(&conn, &sinks, &cfg, &Source::from_path(x))?;
+    }
+    Ok(())
+}
+
+pub fn migrate(config: &Config, m: &ArgMatches) -> Result<(), Box<std::error::Error>> {
+    let conn = PgConnection::establish(&config.database_url()?)?;
+    if m.is_present("check") {
+        let current = migrate::applied(&conn)?.unwrap_or_else(|| "empty".to_string());
+        println!("database {}, binary {}", current, migrate::SCHEMA_VERSION);
+        if current != migrate::SCHEMA_VERSION {
+            return Err(From::from("schema mismatch"));
+        }
+        return Ok(());
+    }
+    migrate::run(&conn)
+}
+
+pub fn query(config: &Config, m: &ArgMatches) -> Result<(), Box<std::error::Error>> {
+    let conn = connect(config, false)?;
+    let params = FlowParams {
+        up_date: m.value_of("from").unwrap_or("").to_string(),
+        down_date: m.value_of("to").unwrap_or("").to_string(),
+        range: m.value_of("range").map(|x| x.to_string()),
+        tz: m.value_of("tz").map(|x| x.to_string()),
+        time_field: m.value_of("time-field").map(|x| x.to_string()),
+        app: m.value_of("app").map(|x| x.to_string()),
+        stage: m.value_of("stage").map(|x| x.to_string()),
+        group_by: m.value_of("group-by").map(|x| x.to_string()),
+        foreign_only: if m.is_present("foreign-only") { Some(true) } else { None },
+        mode: m.value_of("mode").map(|x| x.to_string()),
+        token: None,
+        window: None,
+    };
+    params.validate()?;
+    let mut d3 = db::flow_d3(&conn, params, &Scope::all())?;
+    Names::new(NamesConfig::from_env()).annotate(&mut d3.nodes);
+    if m.value_of("format") == Some("table") {
+        let name = |id: i32| d3.nodes.iter()
+            .find(|x| x.nodeId == id)
+            .map(|x| x.label.clone().unwrap_or_else(|| x.name.clone()))
+            .unwrap_or_default();
+        println!("{:<40} {:<40} {:<6} {:>14}", "source", "target", "type", "bytes");
+        for l in d3.links.iter() {
+            println!("{:<40} {:<40} {:<6} {:>14}", name(l.source), name(l.target), l.ntype, l.value);
+        }
+    } else {
+        println!("{}", serde_json::to_string_pretty(&d3)?);
+    }
+    Ok(())
+}
@@ -0,0 +1,358 @@
+//! Startup configuration from a TOML file with `SFLOW_<SECTION>_<KEY>` overrides
+//!
+//! The detectors, the background jobs and the lookup files are tuned with
+//! environment variables only, see the end of `sflow.toml.example`.
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::Path;
+use std;
+use toml::Value;
+use sflow::*;
+use tls::TlsConfig;
+use auth::AuthConfig;
+use db::ApiConfig;
+
+/// Prefix of the environment variables overriding the file.
+const ENV_PREFIX: &'static str = "SFLOW_";
+/// Ingest sources `input_data` can read from.
+pub const SOURCES: [&'static str; 4] = ["stdin", "file", "udp", "pcap"];
+
+#[derive(Debug)]
+pub struct ConfigError(String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "config: {}", self.0)
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn description(&self) -> &str {
+        &self.0
+    }
+}
+
+fn err<T>(msg: String) -> Result<T, ConfigError> {
+    Err(ConfigError(msg))
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: Vec<String>,
+    pub workers: usize,
+    pub tls: bool,
+    /// Used when `RUST_LOG` is not set.
+    pub log: String,
+    /// Refuses requests without a bearer token, see `auth`.
+    pub auth_required: bool,
+    /// `/agents` marks an agent stale after this many seconds of silence.
+    pub agent_stale_secs: i64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            listen: vec!["0.0.0.0:8080".to_string()],
+            workers: 8,
+            tls: true,
+            log: "info".to_string(),
+            auth_required: true,
+            agent_stale_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSection {
+    pub key: String,
+    pub cert: String,
+    pub client_ca: String,
+    pub client_roles: String,
+    pub allowed_roles: Vec<String>,
+}
+
+impl Default for TlsSection {
+    fn default() -> TlsSection {
+        TlsSection {
+            key: "rootAkey.pem".to_string(),
+            cert: "rootA.pem".to_string(),
+            client_ca: String::new(),
+            client_roles: "client_roles.txt".to_string(),
+            allowed_roles: vec!["dashboard".to_string(), "admin".to_string()],
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Allowed origins, `*` allows any. Empty by default so that every
+    /// deployment names its own.
+    pub origins: Vec<String>,
+    pub max_age: usize,
+}
+
+impl Default for CorsConfig {
+    fn default() -> CorsConfig {
+        CorsConfig {
+            origins: vec![],
+            max_age: 3600,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// `DATABASE_URL` when empty.
+    pub url: String,
+    pub pool_size: u32,
+    /// Threads of the `DbExecutor` arbiter.
+    pub executors: usize,
+    /// Applies the embedded migrations at startup, see `migrate`.
+    pub auto_migrate: bool,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> DatabaseConfig {
+        DatabaseConfig {
+            url: String::new(),
+            pool_size: 16,
+            executors: 8,
+            auto_migrate: true,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct IngestConfig {
+    pub source: String,
+    /// sflowtool text output for `file`, a capture for `pcap`.
+    pub path: String,
+    /// Port sflowtool listens on for `udp`.
+    pub udp_port: u16,
+    /// sflowtool binary decoding `udp` and `pcap`.
+    pub sflowtool: String,
+    pub window_secs: usize,
+    pub heavy_epoch_secs: u64,
+    pub drop_multicast_ipv6: bool,
+    pub drop_link_local_ipv6: bool,
+    pub drop_broadcast_mac: bool,
+    /// Set by `replay`: flows and counters are dated by their datagrams
+    /// instead of by the time they are stored.
+    #[serde(skip)]
+    pub replay: bool,
+}
+
+impl Default for IngestConfig {
+    fn default() -> IngestConfig {
+        IngestConfig {
+            source: "stdin".to_string(),
+            path: String::new(),
+            udp_port: 6343,
+            sflowtool: "sflowtool".to_string(),
+            window_secs: 300,
+            heavy_epoch_secs: 300,
+            drop_multicast_ipv6: true,
+            drop_link_local_ipv6: true,
+            drop_broadcast_mac: true,
+            replay: false,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub tls: TlsSection,
+    pub cors: CorsConfig,
+    pub database: DatabaseConfig,
+    pub ingest: IngestConfig,
+}
+
+/// Parses an override as a TOML value, falling back to a string; a comma
+/// separated string replaces an array.
+fn env_value(raw: &str, current: Option<&Value>) -> Value {
+    let parsed = format!("v = {}", raw).parse::<Value>().ok()
+        .and_then(|x| x.get("v").cloned());
+    match (parsed, current) {
+        (Some(Value::Array(x)), _) => Value::Array(x),
+        (_, Some(&Value::Array(_))) => Value::Array(raw.split(',')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(|x| Value::String(x.to_string()))
+            .collect()),
+        (_, Some(&Value::String(_))) | (None, _) => Value::String(raw.to_string()),
+        (Some(x), _) => x,
+    }
+}
+
+/// Applies `SFLOW_SECTION_KEY=value` variables to the parsed file.
+fn apply_env(doc: &mut Value, defaults: &Value) -> Result<(), ConfigError> {
+    let sections = ["server", "tls", "cors", "database", "ingest"];
+    for (name, raw) in env::vars() {
+        if !name.starts_with(ENV_PREFIX) {
+            continue;
+        }
+        let rest = name[ENV_PREFIX.len()..].to_lowercase();
+        let section = match sections.iter().find(|s| rest.starts_with(&format!("{}_", s))) {
+            Some(x) => *x,
+            // other SFLOW_ variables are not ours
+            None => continue,
+        };
+        let key = rest[section.len() + 1..].to_string();
+        let current = defaults.get(section).and_then(|x| x.get(&key));
+        if current.is_none() {
+            return err(format!("{}: unknown setting {}.{}", name, section, key));
+        }
+        let table = match *doc {
+            Value::Table(ref mut t) => t.entry(section.to_string())
+                .or_insert_with(|| Value::Table(Default::default())),
+            _ => return err("the file is not a table".to_string()),
+        };
+        match *table {
+            Value::Table(ref mut t) => {
+                let value = env_value(&raw, t.get(&key).or(current));
+                t.insert(key, value);
+            },
+            _ => return err(format!("{} is not a table", section)),
+        }
+    }
+    Ok(())
+}
+
+impl Config {
+    /// Reads `path` (defaults only when it does not exist) and applies the
+    /// environment overrides. Every subcommand validates the sections it
+    /// uses, see `validate_server`.
+    pub fn load(path: &str) -> Result<Config, ConfigError> {
+        let mut doc = if Path::new(path).exists() {
+            let mut text = String::new();
+            File::open(path)
+                .and_then(|mut f| f.read_to_string(&mut text))
+                .map_err(|e| ConfigError(format!("{}: {}", path, e)))?;
+            text.parse::<Value>().map_err(|e| ConfigError(format!("{}: {}", path, e)))?
+        } else {
+            Value::Table(Default::default())
+        };
+        let defaults = Value::try_from(Config::default())
+            .map_err(|e| ConfigError(e.to_string()))?;
+        apply_env(&mut doc, &defaults)?;
+        doc.try_into().map_err(|e| ConfigError(format!("{}: {}", path, e)))
+    }
+
+    /// Checks the `[server]`, `[tls]` and `[cors]` sections, which only the
+    /// API uses.
+    pub fn validate_server(&self) -> Result<(), ConfigError> {
+        if self.server.listen.is_empty() {
+            return err("server.listen: at least one address is required".to_string());
+        }
+        for (i, x) in self.server.listen.iter().enumerate() {
+            if x.parse::<SocketAddr>().is_err() {
+                return err(format!("server.listen[{}]: `{}` is not an ip:port address", i, x));
+            }
+        }
+        if self.server.workers == 0 {
+            return err("server.workers: must be at least 1".to_string());
+        }
+        if self.server.agent_stale_secs <= 0 {
+            return err("server.agent_stale_secs: must be positive".to_string());
+        }
+        if self.server.tls {
+            for &(name, file) in [("tls.key", &self.tls.key), ("tls.cert", &self.tls.cert)].iter() {
+                if !Path::new(file).is_file() {
+                    return err(format!("{}: file `{}` not found (set server.tls = false to serve plain HTTP)", name, file));
+                }
+            }
+            if !self.tls.client_ca.is_empty() {
+                for &(name, file) in [("tls.client_ca", &self.tls.client_ca), ("tls.client_roles", &self.tls.client_roles)].iter() {
+                    if !Path::new(file).is_file() {
+                        return err(format!("{}: file `{}` not found", name, file));
+                    }
+                }
+            }
+        } else if !self.tls.client_ca.is_empty() {
+            return err("tls.client_ca: client certificates need server.tls = true".to_string());
+        }
+        if self.cors.origins.is_empty() {
+            return err("cors.origins: not set, list the allowed origins (or \"*\") in [cors] or SFLOW_CORS_ORIGINS".to_string());
+        }
+        for x in self.cors.origins.iter() {
+            if x != "*" && !x.starts_with("http://") && !x.starts_with("https://") {
+                return err(format!("cors.origins: `{}` must start with http:// or https://", x));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn validate_database(&self) -> Result<(), ConfigError> {
+        if self.database.pool_size == 0 {
+            return err("database.pool_size: must be at least 1".to_string());
+        }
+        if self.database.executors == 0 {
+            return err("database.executors: must be at least 1".to_string());
+        }
+        if (self.database.executors as u32) > self.database.pool_size {
+            return err(format!("database.executors: {} executors need a pool_size of at least {}",
+                self.database.executors, self.database.executors));
+        }
+        Ok(())
+    }
+
+    /// Checks the `[ingest]` section, its source only with `source` set, as
+    /// `replay` reads the files it is given.
+    pub fn validate_ingest(&self, source: bool) -> Result<(), ConfigError> {
+        if self.ingest.window_secs == 0 {
+            return err("ingest.window_secs: must be positive".to_string());
+        }
+        if self.ingest.heavy_epoch_secs == 0 {
+            return err("ingest.heavy_epoch_secs: must be positive".to_string());
+        }
+        if !source {
+            return Ok(());
+        }
+        if !SOURCES.contains(&self.ingest.source.as_str()) {
+            return err(format!("ingest.source: `{}` is not one of {}", self.ingest.source, SOURCES.join(", ")));
+        }
+        if (self.ingest.source == "file" || self.ingest.source == "pcap") && self.ingest.path.is_empty() {
+            return err(format!("ingest.path: required by source `{}`", self.ingest.source));
+        }
+        if self.ingest.source == "udp" && self.ingest.udp_port == 0 {
+            return err("ingest.udp_port: must not be 0".to_string());
+        }
+        Ok(())
+    }
+
+    /// `database.url` or `DATABASE_URL`.
+    pub fn database_url(&self) -> Result<String, Box<std::error::Error>> {
+        if self.database.url.is_empty() {
+            get_sql_url()
+        } else {
+            Ok(self.database.url.clone())
+        }
+    }
+
+    pub fn api_config(&self) -> ApiConfig {
+        ApiConfig {
+            auth: AuthConfig { required: self.server.auth_required },
+            agent_stale_secs: self.server.agent_stale_secs,
+        }
+    }
+
+    pub fn tls_config(&self) -> TlsConfig {
+        TlsConfig {
+            key: self.tls.key.clone(),
+            cert: self.tls.cert.clone(),
+            client_ca: self.tls.client_ca.clone(),
+            client_roles: self.tls.client_roles.clone(),
+            allowed_roles: self.tls.allowed_roles.clone(),
+        }
+    }
+}
@@ -1,25 +1,52 @@
 //! Db executor actor
 use actix::prelude::*;
-use actix_web::*;
+use error::ApiError;
 use diesel;
 use diesel::prelude::*;
 use diesel::r2d2::{Pool, ConnectionManager};
 use diesel::pg::PgConnection;
 use schema;
 use flow;
-use chrono::{NaiveDateTime, NaiveDate};
+use timerange::TimeField;
 use sflow::*;
+use seqtrack::AgentLoss;
+use registry::{self, AgentStatus};
+use timeseries::{self, TimeSeries};
+use live::LiveHub;
+use window::SlidingWindow;
+use rules;
+use auth::{self, AuthConfig, Scope, TokenInfo};
+use tenant::{TenantInfo, TenantMap, NO_TENANT};
+use baseline;
+use billing::{self, BillingParams, BillingRow};
+use matrix::{self, MatrixParams, TrafficMatrix};
+use heavy::HeavyHitters;
+use l2::{MacQuery, MacBinding};
+use names::Names;
+use std::sync::{Arc, Mutex};
 use models;
 
 no_arg_sql_function!(last_insert_id, diesel::sql_types::Integer);
 pub type DBPool = Pool<ConnectionManager<PgConnection>>;
 
 /// This is db executor actor. We are going to run 3 of them in parallel.
-pub struct DbExecutor(pub DBPool);
+pub struct DbExecutor(pub DBPool, pub ApiConfig);
+
+/// Settings of the handlers, from the `[server]` section of the configuration.
+#[derive(Debug, Clone, Copy)]
+pub struct ApiConfig {
+    pub auth: AuthConfig,
+    /// `/agents` marks an agent stale after this many seconds of silence.
+    pub agent_stale_secs: i64,
+}
 
 /// State with DbExecutor address
 pub struct AppState {
     pub db: Addr<DbExecutor>,
+    pub live: Addr<LiveHub>,
+    pub window: Arc<SlidingWindow>,
+    pub heavy: Arc<Mutex<HeavyHitters>>,
+    pub names: Arc<Names>,
 }
 
 impl Actor for DbExecutor {
@@ -28,42 +55,409 @@ impl Actor for DbExecutor {
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct FlowD3 {
-    nodes: Vec<FlowName>,
-    links: Vec<FlowDirection2>,
+    pub nodes: Vec<FlowName>,
+    pub links: Vec<FlowDirection2>,
+}
+
+/// Scope of the bearer token of a request, see `auth`.
+fn authorize(conn: &PgConnection, cfg: &AuthConfig, token: &Option<String>) -> Result<Scope, ApiError> {
+    auth::authorize(conn, cfg, token.as_ref().map(|x| x.as_str()))?
+        .ok_or_else(|| ApiError::Unauthorized("invalid or missing token".to_string()))
+}
+
+/// Like `authorize`, for an admin token only; a missing token is refused
+/// whether `auth_required` is on or not.
+fn authorize_admin(conn: &PgConnection, cfg: &AuthConfig, token: &Option<String>) -> Result<Scope, ApiError> {
+    if token.is_none() {
+        return Err(ApiError::Unauthorized("admin token required".to_string()));
+    }
+    let scope = authorize(conn, cfg, token)?;
+    if !scope.is_admin() {
+        return Err(ApiError::Forbidden("admin role required".to_string()));
+    }
+    Ok(scope)
+}
+
+/// Tenants to place incidents, scans and MAC bindings in, only needed for
+/// a tenant scope.
+fn event_tenants(conn: &PgConnection, scope: &Scope) -> Result<Option<TenantMap>, ApiError> {
+    if scope.tenant_id.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(TenantMap::load(conn)?))
 }
 
+impl Message for auth::Authorize {
+    type Result = Result<Scope, ApiError>;
+}
+impl Handler<auth::Authorize> for DbExecutor {
+    type Result = Result<Scope, ApiError>;
+
+    fn handle(&mut self, msg: auth::Authorize, _: &mut Self::Context) -> Self::Result {
+        let conn: &PgConnection = &*self.0.get()?;
+        authorize(conn, &self.1.auth, &msg.token)
+    }
+}
+
+/// The Sankey and the scope it was drawn for, which decides how it may be
+/// cached.
 impl Message for flow::FlowParams {
-    type Result = Result<FlowD3, Error>;
+    type Result = Result<(FlowD3, Scope), ApiError>;
 }
 impl Handler<flow::FlowParams> for DbExecutor {
-    type Result = Result<FlowD3, Error>;
+    type Result = Result<(FlowD3, Scope), ApiError>;
 
     fn handle(&mut self, msg: flow::FlowParams, _: &mut Self::Context) -> Self::Result {
-        use self::schema::flow::dsl::*;
-        info!("{:?}", msg);
-        let up = NaiveDateTime::parse_from_str(&msg.up_date, "%Y-%m-%d %H:%M:%S").unwrap_or(
-            NaiveDate::from_ymd(2014, 5, 17).and_hms(12, 34, 56));
-        let dn = NaiveDateTime::parse_from_str(&msg.down_date, "%Y-%m-%d %H:%M:%S").unwrap_or(
-            NaiveDate::from_ymd(2014, 5, 17).and_hms(12, 34, 56));
-        let conn: &PgConnection = &self.0.get().unwrap();
-        let loadflow = flow
-            .filter(input_date.between(up, dn))
-            .order(input_date.desc())
-            .load::<models::Flow>(conn);
-        if let Ok(loadflow) = loadflow {
-            let fmap = build_graph_from_db(&loadflow);
-            match fmap {
-                Ok(x) => {
-                    if let Ok((nodes_data, links_data)) = build_d3_data(&x) {
-                        return Ok(FlowD3{nodes:nodes_data, links:links_data})
-                    }
+        info!("flow {} - {} app {:?} stage {:?} group_by {:?} mode {:?}", msg.up_date, msg.down_date,
+            msg.app, msg.stage, msg.group_by, msg.mode);
+        let conn: &PgConnection = &*self.0.get()?;
+        let scope = authorize(conn, &self.1.auth, &msg.token)?;
+        flow_d3(conn, msg, &scope).map(|x| (x, scope))
+    }
+}
+
+/// Sankey of a flow query within `scope`, also run by the `query` command.
+/// The scope applies to the flows themselves, before they are merged.
+pub fn flow_d3(conn: &PgConnection, mut msg: flow::FlowParams, scope: &Scope) -> Result<FlowD3, ApiError> {
+    use self::schema::flow::dsl::*;
+    let range = msg.time_range()?;
+    let (up, dn) = range.local();
+    // recent ranges are answered from the sliding window without loading flows
+    let recent = match msg.window.take() {
+        Some(ref w) if range.field == TimeField::InputDate => w.query(up, dn, |fd| scope.allows(fd)),
+        _ => None,
+    };
+    let fmap = match recent {
+        Some(x) => x,
+        None => {
+            let mut loadflow = match range.field {
+                TimeField::InputDate => flow
+                    .filter(input_date.between(up, dn))
+                    .order(input_date.desc())
+                    .load::<models::Flow>(conn)?,
+                TimeField::Utc => {
+                    let (first, last) = range.epoch();
+                    flow.filter(utc.between(first, last))
+                        .order(utc.desc())
+                        .load::<models::Flow>(conn)?
                 },
-                Err(x) => {
-                    return Err(error::ErrorInternalServerError(x.to_string()))
-                }
+            };
+            if !scope.is_unrestricted() {
+                loadflow.retain(|x| scope.allows_edge(&x.agent, &x.src, &x.dst, x.tenant_id));
             }
+            build_graph_from_db(&loadflow)?
+        },
+    };
+    let fmap = msg.view(fmap).map_err(ApiError::BadRequest)?;
+    let (nodes_data, mut links_data) = build_d3_data(&fmap)?;
+    // window answers are scored too, the baseline is per hour whatever the source
+    if let Err(x) = baseline::score_links(conn, up, dn, &nodes_data, &mut links_data) {
+        info!("anomaly score: {}", x);
+    }
+    Ok(FlowD3{nodes:nodes_data, links:links_data})
+}
+
+
+impl Message for flow::LossQuery {
+    type Result = Result<Vec<AgentLoss>, ApiError>;
+}
+impl Handler<flow::LossQuery> for DbExecutor {
+    type Result = Result<Vec<AgentLoss>, ApiError>;
+
+    fn handle(&mut self, msg: flow::LossQuery, _: &mut Self::Context) -> Self::Result {
+        use self::schema::agent_seq::dsl::*;
+        let conn: &PgConnection = &*self.0.get()?;
+        let scope = authorize(conn, &self.1.auth, &msg.token)?;
+        let rows = agent_seq
+            .order((agent.asc(), sub_agent.asc(), sample_type.asc(), source_id.asc()))
+            .load::<models::AgentSeq>(conn)?;
+        Ok(rows.iter().filter(|x| scope.allows_agent(&x.agent)).map(AgentLoss::from).collect())
+    }
+}
+
+impl Message for flow::AgentsQuery {
+    type Result = Result<Vec<AgentStatus>, ApiError>;
+}
+impl Handler<flow::AgentsQuery> for DbExecutor {
+    type Result = Result<Vec<AgentStatus>, ApiError>;
+
+    fn handle(&mut self, msg: flow::AgentsQuery, _: &mut Self::Context) -> Self::Result {
+        use self::schema::agents::dsl::*;
+        let conn: &PgConnection = &*self.0.get()?;
+        let scope = authorize(conn, &self.1.auth, &msg.token)?;
+        let rows = agents
+            .order(agent.asc())
+            .load::<models::Agent>(conn)?;
+        let now = registry::db_now(conn)?;
+        Ok(rows.iter()
+            .filter(|x| scope.allows_agent(&x.agent))
+            .map(|x| AgentStatus::new(x, now, self.1.agent_stale_secs))
+            .collect())
+    }
+}
+
+impl Message for flow::TimeSeriesParams {
+    type Result = Result<TimeSeries, ApiError>;
+}
+impl Handler<flow::TimeSeriesParams> for DbExecutor {
+    type Result = Result<TimeSeries, ApiError>;
+
+    fn handle(&mut self, msg: flow::TimeSeriesParams, _: &mut Self::Context) -> Self::Result {
+        use self::schema::flow::dsl::*;
+        info!("timeseries {} - {} range {:?} interval {} split {:?} top {:?}", msg.up_date, msg.down_date,
+            msg.range, msg.interval, msg.split, msg.top);
+        let range = msg.time_range()?;
+        let (up, dn) = range.bounds();
+        let interval = timeseries::parse_interval(&msg.interval)
+            .map_err(|e| ApiError::BadRequest(e.to_string()))?;
+        let conn: &PgConnection = &*self.0.get()?;
+        let scope = authorize(conn, &self.1.auth, &msg.token)?;
+        let mut loadflow = match range.field {
+            TimeField::InputDate => flow
+                .filter(input_date.between(up, dn))
+                .load::<models::Flow>(conn)?,
+            TimeField::Utc => {
+                let (first, last) = range.epoch();
+                flow.filter(utc.between(first, last))
+                    .load::<models::Flow>(conn)?
+            },
+        };
+        if !scope.is_unrestricted() {
+            loadflow.retain(|x| scope.allows_edge(&x.agent, &x.src, &x.dst, x.tenant_id));
+        }
+        timeseries::build_timeseries(&loadflow, up, dn, range.field, interval,
+                msg.split.as_ref().map(|x| x.as_str()), msg.top)
+            .map_err(|e| ApiError::BadRequest(e.to_string()))
+    }
+}
+
+impl Message for flow::IncidentQuery {
+    type Result = Result<Vec<models::Incident>, ApiError>;
+}
+impl Handler<flow::IncidentQuery> for DbExecutor {
+    type Result = Result<Vec<models::Incident>, ApiError>;
+
+    fn handle(&mut self, msg: flow::IncidentQuery, _: &mut Self::Context) -> Self::Result {
+        use self::schema::incident::dsl::*;
+        let (up, dn) = ::flow::parse_range(&msg.up_date, &msg.down_date)?;
+        let conn: &PgConnection = &*self.0.get()?;
+        let scope = authorize(conn, &self.1.auth, &msg.token)?;
+        let tenants = event_tenants(conn, &scope)?;
+        let rows = incident
+            .filter(start_date.le(dn))
+            .filter(end_date.is_null().or(end_date.ge(up)))
+            .order(start_date.desc())
+            .load::<models::Incident>(conn)?;
+        Ok(rows.into_iter()
+            .filter(|x| scope.allows_event(&x.agent, &[x.dst.as_str()], tenants.as_ref()))
+            .collect())
+    }
+}
+
+impl Message for flow::ScanQuery {
+    type Result = Result<Vec<models::ScanEvent>, ApiError>;
+}
+impl Handler<flow::ScanQuery> for DbExecutor {
+    type Result = Result<Vec<models::ScanEvent>, ApiError>;
+
+    fn handle(&mut self, msg: flow::ScanQuery, _: &mut Self::Context) -> Self::Result {
+        use self::schema::scan_event::dsl::*;
+        let (up, dn) = ::flow::parse_range(&msg.up_date, &msg.down_date)?;
+        let conn: &PgConnection = &*self.0.get()?;
+        let scope = authorize(conn, &self.1.auth, &msg.token)?;
+        let tenants = event_tenants(conn, &scope)?;
+        let rows = scan_event
+            .filter(event_date.between(up, dn))
+            .order(event_date.desc())
+            .load::<models::ScanEvent>(conn)?;
+        Ok(rows.into_iter()
+            .filter(|x| scope.allows_event(&x.agent, &[x.src.as_str(), x.target.as_str()], tenants.as_ref()))
+            .collect())
+    }
+}
+
+impl Message for flow::RuleRequest {
+    type Result = Result<Vec<models::AlertRule>, ApiError>;
+}
+impl Handler<flow::RuleRequest> for DbExecutor {
+    type Result = Result<Vec<models::AlertRule>, ApiError>;
+
+    fn handle(&mut self, msg: flow::RuleRequest, _: &mut Self::Context) -> Self::Result {
+        use self::schema::alert_rule::dsl::*;
+        let conn: &PgConnection = &*self.0.get()?;
+        authorize_admin(conn, &self.1.auth, &msg.token)?;
+        match msg.cmd {
+            ::flow::RuleCommand::List => {},
+            ::flow::RuleCommand::Create(rule) => {
+                rules::validate(&rule).map_err(ApiError::BadRequest)?;
+                diesel::insert_into(alert_rule).values(&rule).execute(conn)?;
+            },
+            ::flow::RuleCommand::Delete(id) => {
+                diesel::delete(alert_rule.filter(rule_id.eq(id))).execute(conn)?;
+            },
+            ::flow::RuleCommand::Get(id) => {
+                let rule = alert_rule.filter(rule_id.eq(id))
+                    .first::<models::AlertRule>(conn)
+                    .optional()?
+                    .ok_or_else(|| ApiError::NotFound(format!("no rule {}", id)))?;
+                return Ok(vec![rule]);
+            },
         }
-        Err(error::ErrorInternalServerError("Select Fail!"))
+        Ok(alert_rule.order(rule_id.asc()).load::<models::AlertRule>(conn)?)
     }
 }
 
+impl Message for BillingParams {
+    type Result = Result<Vec<BillingRow>, ApiError>;
+}
+impl Handler<BillingParams> for DbExecutor {
+    type Result = Result<Vec<BillingRow>, ApiError>;
+
+    fn handle(&mut self, msg: BillingParams, _: &mut Self::Context) -> Self::Result {
+        info!("billing {:?}", msg.month);
+        let conn: &PgConnection = &*self.0.get()?;
+        let (up, dn) = billing::month_range(msg.month.as_ref().map(|x| x.as_str()), registry::db_now(conn)?)
+            .map_err(ApiError::BadRequest)?;
+        let scope = authorize(conn, &self.1.auth, &msg.token)?;
+        billing::build_report(conn, up, dn, &scope)
+            .map_err(ApiError::from)
+    }
+}
+
+impl Message for MatrixParams {
+    type Result = Result<TrafficMatrix, ApiError>;
+}
+impl Handler<MatrixParams> for DbExecutor {
+    type Result = Result<TrafficMatrix, ApiError>;
+
+    fn handle(&mut self, msg: MatrixParams, _: &mut Self::Context) -> Self::Result {
+        use self::schema::flow::dsl::*;
+        info!("matrix {} - {} sites {:?}", msg.up_date, msg.down_date, msg.sites);
+        let sites = matrix::parse_sites(&msg.sites).map_err(ApiError::BadRequest)?;
+        let (up, dn) = ::flow::parse_range(&msg.up_date, &msg.down_date)?;
+        let conn: &PgConnection = &*self.0.get()?;
+        let scope = authorize(conn, &self.1.auth, &msg.token)?;
+        let mut loadflow = flow
+            .filter(input_date.between(up, dn))
+            .load::<models::Flow>(conn)?;
+        if !scope.is_unrestricted() {
+            loadflow.retain(|x| scope.allows_edge(&x.agent, &x.src, &x.dst, x.tenant_id));
+        }
+        let fmap = build_graph_from_db(&loadflow)?;
+        Ok(matrix::build_matrix(&fmap, &sites, msg.other.unwrap_or(true)))
+    }
+}
+
+impl Message for MacQuery {
+    type Result = Result<Vec<MacBinding>, ApiError>;
+}
+impl Handler<MacQuery> for DbExecutor {
+    type Result = Result<Vec<MacBinding>, ApiError>;
+
+    fn handle(&mut self, msg: MacQuery, _: &mut Self::Context) -> Self::Result {
+        use self::schema::mac_ip::dsl::*;
+        let conn: &PgConnection = &*self.0.get()?;
+        let scope = authorize(conn, &self.1.auth, &msg.token)?;
+        let tenants = event_tenants(conn, &scope)?;
+        let mut q = mac_ip.into_boxed();
+        if let Some(ref x) = msg.mac {
+            q = q.filter(mac.eq(x.to_lowercase().replace(":", "").replace("-", "")));
+        }
+        if let Some(ref x) = msg.ip {
+            q = q.filter(ip.eq(x.clone()));
+        }
+        if let Some(x) = msg.vlan {
+            q = q.filter(vlan.eq(x));
+        }
+        let rows = q
+            .order((mac.asc(), last_seen.desc()))
+            .load::<models::MacIp>(conn)?;
+        Ok(rows.into_iter()
+            .filter(|x| scope.allows_event(&x.agent, &[x.ip.as_str()], tenants.as_ref()))
+            .map(|x| MacBinding {
+                mac: x.mac,
+                vendor: None,
+                ip: x.ip,
+                agent: x.agent,
+                vlan: x.vlan,
+                in_if: x.in_if,
+                first_seen: x.first_seen.format("%Y-%m-%d %H:%M:%S").to_string(),
+                last_seen: x.last_seen.format("%Y-%m-%d %H:%M:%S").to_string(),
+            })
+            .collect())
+    }
+}
+
+impl Message for flow::TokenRequest {
+    type Result = Result<Vec<TokenInfo>, ApiError>;
+}
+impl Handler<flow::TokenRequest> for DbExecutor {
+    type Result = Result<Vec<TokenInfo>, ApiError>;
+
+    fn handle(&mut self, msg: flow::TokenRequest, _: &mut Self::Context) -> Self::Result {
+        use self::schema::api_token::dsl::*;
+        let conn: &PgConnection = &*self.0.get()?;
+        authorize_admin(conn, &self.1.auth, &msg.token)?;
+        match msg.cmd {
+            ::flow::TokenCommand::List => {},
+            ::flow::TokenCommand::Create(p) => {
+                auth::validate(&p).map_err(ApiError::BadRequest)?;
+                let secret = auth::generate_token()?;
+                let row = diesel::insert_into(api_token)
+                    .values(&models::NewApiToken {
+                        name: p.name.clone(),
+                        token_hash: auth::hash_token(&secret),
+                        role: p.role.clone(),
+                        agents: p.agents.join(","),
+                        prefixes: p.prefixes.join(","),
+                        tenant_id: p.tenant_id.unwrap_or(NO_TENANT),
+                    })
+                    .get_result::<models::ApiToken>(conn)
+                    ?;
+                let mut info = TokenInfo::new(&row);
+                info.token = Some(secret);
+                return Ok(vec![info]);
+            },
+            ::flow::TokenCommand::Revoke(id) => {
+                diesel::update(api_token.filter(token_id.eq(id)))
+                    .set(revoked.eq(true))
+                    .execute(conn)
+                    ?;
+            },
+        }
+        let rows = api_token.order(token_id.asc()).load::<models::ApiToken>(conn)?;
+        Ok(rows.iter().map(TokenInfo::new).collect())
+    }
+}
+
+impl Message for flow::TenantRequest {
+    type Result = Result<Vec<TenantInfo>, ApiError>;
+}
+impl Handler<flow::TenantRequest> for DbExecutor {
+    type Result = Result<Vec<TenantInfo>, ApiError>;
+
+    fn handle(&mut self, msg: flow::TenantRequest, _: &mut Self::Context) -> Self::Result {
+        use self::schema::tenant::dsl::*;
+        let conn: &PgConnection = &*self.0.get()?;
+        authorize_admin(conn, &self.1.auth, &msg.token)?;
+        match msg.cmd {
+            ::flow::TenantCommand::List => {},
+            ::flow::TenantCommand::Create(p) => {
+                ::tenant::validate(&p).map_err(ApiError::BadRequest)?;
+                diesel::insert_into(tenant)
+                    .values(&models::NewTenant {
+                        name: p.name.clone(),
+                        agents: p.agents.join(","),
+                        prefixes: p.prefixes.join(","),
+                    })
+                    .execute(conn)
+                    ?;
+            },
+            ::flow::TenantCommand::Delete(id) => {
+                diesel::delete(tenant.filter(tenant_id.eq(id))).execute(conn)?;
+            },
+        }
+        let rows = tenant.order(tenant_id.asc()).load::<models::Tenant>(conn)?;
+        Ok(rows.iter().map(TenantInfo::new).collect())
+    }
+}
@@ -0,0 +1,332 @@
+//! Volumetric DDoS detection on the ingested samples
+use std::collections::{BTreeMap, HashSet};
+use std::time::{Duration, Instant};
+use std;
+use diesel::pg::PgConnection;
+use diesel::sql_query;
+use diesel::query_dsl::*;
+use sflow::*;
+
+const TCP_SYN: i32 = 0x02;
+const TCP_ACK: i32 = 0x10;
+const PROTO_UDP: i32 = 17;
+/// Source ports of the usual UDP amplification vectors:
+/// chargen, DNS, NTP, SNMP, LDAP, SSDP and memcached.
+const AMP_PORTS: [i32; 7] = [19, 53, 123, 161, 389, 1900, 11211];
+/// Cap of distinct sources remembered per destination and interval.
+const MAX_SOURCES: usize = 10000;
+/// Weight of a new interval in the baseline.
+const ALPHA: f64 = 0.1;
+/// Clear intervals before an incident is closed.
+const QUIET_INTERVALS: u32 = 3;
+/// Destinations without traffic for this many intervals are forgotten.
+const IDLE_INTERVALS: u32 = 60;
+
+#[derive(Debug, Clone)]
+pub struct DdosConfig {
+    pub interval: u64,
+    pub min_bps: f64,
+    pub min_pps: f64,
+    pub factor: f64,
+    /// Distinct sources a UDP amplification needs at least, besides
+    /// `factor` times the learned number.
+    pub min_sources: f64,
+}
+
+impl DdosConfig {
+    pub fn from_env() -> DdosConfig {
+        DdosConfig {
+            interval: get_env_or("DDOS_INTERVAL", 10),
+            min_bps: get_env_or("DDOS_MIN_BPS", 100e6),
+            min_pps: get_env_or("DDOS_MIN_PPS", 50e3),
+            factor: get_env_or("DDOS_FACTOR", 5.0),
+            min_sources: get_env_or("DDOS_MIN_SOURCES", 10.0),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    bytes: u64,
+    packets: u64,
+    syn: u64,
+    amp_bytes: u64,
+    sources: HashSet<String>,
+}
+
+#[derive(Debug, Default)]
+struct Target {
+    cur: Counters,
+    base_bps: f64,
+    base_pps: f64,
+    base_syn: f64,
+    base_sources: f64,
+    intervals: u64,
+    open: BTreeMap<&'static str, u32>,
+    idle: u32,
+}
+
+/// Tracks per-destination rates against an EWMA baseline and records
+/// incidents in the `incident` table. Every agent keeps its own targets so
+/// an incident names the agent that saw it.
+pub struct DdosDetector {
+    conf: DdosConfig,
+    targets: BTreeMap<(String, String), Target>,
+    since: Instant,
+}
+
+fn is_amp_port(p: Option<i32>) -> bool {
+    p.map(|x| AMP_PORTS.contains(&x)).unwrap_or(false)
+}
+
+impl DdosDetector {
+    pub fn new(conf: DdosConfig) -> DdosDetector {
+        DdosDetector {
+            conf: conf,
+            targets: BTreeMap::new(),
+            since: Instant::now(),
+        }
+    }
+
+    /// Closes incidents left open by a previous run.
+    pub fn close_open(&self, conn: &PgConnection) -> Result<(), Box<std::error::Error>> {
+        sql_query("UPDATE incident SET end_date = CURRENT_TIMESTAMP WHERE end_date IS NULL").execute(conn)?;
+        Ok(())
+    }
+
+    pub fn observe(&mut self, dg: &Datagram) {
+        for s in dg.samplev5.iter() {
+            let f = match sample_flow(s) {
+                Some(f) => f,
+                None => continue,
+            };
+            if f.ntype == "mac" {
+                continue;
+            }
+            let t = self.targets.entry((dg.agent.clone(), f.target.clone())).or_insert_with(Default::default);
+            let pkts = s.meanSkipCount.max(1) as u64;
+            let bytes = f.size.max(0) as u64;
+            t.cur.bytes += bytes;
+            t.cur.packets += pkts;
+            if let Some(flags) = s.TCPFlags {
+                if flags & TCP_SYN != 0 && flags & TCP_ACK == 0 {
+                    t.cur.syn += pkts;
+                }
+            }
+            if s.IPProtocol == Some(PROTO_UDP) && is_amp_port(s.UDPSrcPort) {
+                t.cur.amp_bytes += bytes;
+            }
+            if t.cur.sources.len() < MAX_SOURCES {
+                t.cur.sources.insert(f.source.clone());
+            }
+        }
+    }
+
+    pub fn need_tick(&self) -> bool {
+        self.since.elapsed() >= Duration::from_secs(self.conf.interval)
+    }
+
+    /// Closes the current interval: flags destinations above their
+    /// baseline, opens/updates/closes incidents and learns the baseline.
+    pub fn tick(&mut self, conn: &PgConnection) -> Result<(), Box<std::error::Error>> {
+        let elapsed = self.since.elapsed();
+        let secs = (elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9).max(1.0);
+        self.since = Instant::now();
+        for q in self.close_interval(secs) {
+            sql_query(q).execute(conn)?;
+        }
+        Ok(())
+    }
+
+    /// The work of `tick` for an interval of `secs` seconds, returning the
+    /// statements that record it.
+    fn close_interval(&mut self, secs: f64) -> Vec<String> {
+        let conf = self.conf.clone();
+        let mut sqls: Vec<String> = vec![];
+        let mut idle: Vec<(String, String)> = vec![];
+        for (key, t) in self.targets.iter_mut() {
+            let (ref agent, ref dst) = *key;
+            let bps = t.cur.bytes as f64 * 8.0 / secs;
+            let pps = t.cur.packets as f64 / secs;
+            let syn_pps = t.cur.syn as f64 / secs;
+            let amp_bps = t.cur.amp_bytes as f64 * 8.0 / secs;
+            let sources = t.cur.sources.len();
+            let many_sources = sources as f64 >= conf.min_sources.max(conf.factor * t.base_sources);
+            let mut kinds: Vec<&'static str> = vec![];
+            if bps >= conf.min_bps.max(conf.factor * t.base_bps)
+                || pps >= conf.min_pps.max(conf.factor * t.base_pps) {
+                kinds.push("volumetric");
+            }
+            if syn_pps >= conf.min_pps.max(conf.factor * t.base_syn) && t.cur.syn * 2 > t.cur.packets {
+                kinds.push("syn_flood");
+            }
+            if amp_bps >= conf.min_bps / 2.0 && t.cur.amp_bytes * 2 > t.cur.bytes && many_sources {
+                kinds.push("udp_amplification");
+            }
+            for kind in kinds.iter() {
+                if t.open.contains_key(kind) {
+                    sqls.push(format!("UPDATE incident SET peak_bps = GREATEST(peak_bps, {}), \
+                        peak_pps = GREATEST(peak_pps, {}), sources = GREATEST(sources, {}) \
+                        WHERE agent = '{}' AND dst = '{}' AND kind = '{}' AND end_date IS NULL",
+                        bps as i64, pps as i64, sources, agent, dst, kind));
+                } else {
+                    info!("{} on {}: {:.0} bps {:.0} pps from {} sources", kind, dst, bps, pps, sources);
+                    sqls.push(format!("INSERT INTO incident (agent, dst, kind, peak_bps, peak_pps, sources, baseline_bps, detail) \
+                        VALUES ('{}','{}','{}',{},{},{},{},'{}')",
+                        agent, dst, kind, bps as i64, pps as i64, sources, t.base_bps as i64,
+                        format!("syn_pps={:.0} amp_bps={:.0} baseline_sources={:.0}", syn_pps, amp_bps, t.base_sources)));
+                }
+                t.open.insert(*kind, 0);
+            }
+            let mut closed: Vec<&'static str> = vec![];
+            for (kind, quiet) in t.open.iter_mut() {
+                if !kinds.contains(kind) {
+                    *quiet += 1;
+                    if *quiet >= QUIET_INTERVALS {
+                        closed.push(*kind);
+                    }
+                }
+            }
+            for kind in closed.iter() {
+                info!("{} on {} ended", kind, dst);
+                t.open.remove(kind);
+                sqls.push(format!("UPDATE incident SET end_date = CURRENT_TIMESTAMP \
+                    WHERE agent = '{}' AND dst = '{}' AND kind = '{}' AND end_date IS NULL", agent, dst, kind));
+            }
+            // learn only from intervals that are not under attack
+            if t.open.is_empty() {
+                let a = if t.intervals == 0 { 1.0 } else { ALPHA };
+                t.base_bps += a * (bps - t.base_bps);
+                t.base_pps += a * (pps - t.base_pps);
+                t.base_syn += a * (syn_pps - t.base_syn);
+                t.base_sources += a * (sources as f64 - t.base_sources);
+                t.intervals += 1;
+            }
+            if t.cur.packets == 0 {
+                t.idle += 1;
+                if t.idle >= IDLE_INTERVALS && t.open.is_empty() {
+                    idle.push(key.clone());
+                }
+            } else {
+                t.idle = 0;
+            }
+            t.cur = Default::default();
+        }
+        for key in idle.iter() {
+            self.targets.remove(key);
+        }
+        sqls
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conf() -> DdosConfig {
+        DdosConfig { interval: 10, min_bps: 1e6, min_pps: 1000.0, factor: 5.0, min_sources: 10.0 }
+    }
+
+    fn sample(src: &str, dst: &str, udp_src: i32, size: i32, skip: i32) -> SampleV5 {
+        SampleV5 {
+            srcIP: Some(src.to_string()),
+            dstIP: Some(dst.to_string()),
+            UDPSrcPort: Some(udp_src),
+            UDPDstPort: Some(4444),
+            IPProtocol: Some(PROTO_UDP),
+            sampledPacketSize: size,
+            meanSkipCount: skip,
+            ..Default::default()
+        }
+    }
+
+    /// One datagram of `agent` with a sample from each of `sources`.
+    fn datagram(agent: &str, sources: usize, udp_src: i32, size: i32, skip: i32) -> Datagram {
+        Datagram {
+            agent: agent.to_string(),
+            samplev5: (0..sources)
+                .map(|i| sample(&format!("192.0.{}.{}", i / 250 + 2, i % 250 + 1), "10.0.0.1", udp_src, size, skip))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn kinds(sqls: &Vec<String>) -> Vec<&'static str> {
+        ["volumetric", "syn_flood", "udp_amplification"].iter()
+            .filter(|k| sqls.iter().any(|q| q.starts_with("INSERT") && q.contains(&format!("'{}'", k))))
+            .cloned()
+            .collect()
+    }
+
+    /// A detector that learned `intervals` quiet seconds of `sources`
+    /// sources towards 10.0.0.1.
+    fn warmed(sources: usize, intervals: usize) -> DdosDetector {
+        let mut d = DdosDetector::new(conf());
+        for _ in 0..intervals {
+            d.observe(&datagram("a", sources, 4444, 100, 1));
+            assert!(d.close_interval(1.0).is_empty());
+        }
+        d
+    }
+
+    #[test]
+    fn volumetric_against_the_baseline() {
+        let mut d = warmed(10, 3);
+        let t = &d.targets[&("a".to_string(), "10.0.0.1".to_string())];
+        assert_eq!(t.base_bps, 8000.0);
+        assert_eq!(t.base_sources, 10.0);
+        d.observe(&datagram("a", 100, 4444, 1000, 10));
+        let sqls = d.close_interval(1.0);
+        assert_eq!(kinds(&sqls), vec!["volumetric"]);
+        // ongoing incidents are updated, quiet intervals close them
+        d.observe(&datagram("a", 100, 4444, 1000, 10));
+        assert!(d.close_interval(1.0)[0].starts_with("UPDATE incident SET peak_bps"));
+        for i in 0..QUIET_INTERVALS {
+            let sqls = d.close_interval(1.0);
+            assert_eq!(sqls.iter().any(|q| q.contains("end_date = CURRENT_TIMESTAMP")), i + 1 == QUIET_INTERVALS);
+        }
+    }
+
+    #[test]
+    fn below_the_minimum_is_no_attack() {
+        let mut d = warmed(1, 3);
+        // 50 times the baseline, still under min_bps and min_pps
+        d.observe(&datagram("a", 50, 4444, 100, 1));
+        assert!(d.close_interval(1.0).is_empty());
+    }
+
+    #[test]
+    fn amplification_needs_more_sources_than_learned() {
+        let mut d = warmed(40, 5);
+        // 150 reflectors are fewer than 5 times the 40 usual sources
+        d.observe(&datagram("a", 150, 123, 1000, 10));
+        assert!(!kinds(&d.close_interval(1.0)).contains(&"udp_amplification"));
+        let mut d = warmed(20, 5);
+        d.observe(&datagram("a", 150, 123, 1000, 10));
+        assert!(kinds(&d.close_interval(1.0)).contains(&"udp_amplification"));
+        // without a baseline the minimum applies
+        let mut d = DdosDetector::new(conf());
+        d.observe(&datagram("a", 9, 123, 10000, 10));
+        assert!(!kinds(&d.close_interval(1.0)).contains(&"udp_amplification"));
+    }
+
+    #[test]
+    fn baseline_is_not_learned_under_attack() {
+        let mut d = warmed(10, 3);
+        d.observe(&datagram("a", 100, 4444, 1000, 10));
+        d.close_interval(1.0);
+        let t = &d.targets[&("a".to_string(), "10.0.0.1".to_string())];
+        assert_eq!(t.base_bps, 8000.0);
+        assert_eq!(t.intervals, 3);
+    }
+
+    #[test]
+    fn incidents_keep_their_agent() {
+        let mut d = DdosDetector::new(conf());
+        d.observe(&datagram("a", 100, 4444, 1000, 10));
+        d.observe(&datagram("b", 1, 4444, 100, 1));
+        let sqls = d.close_interval(1.0);
+        assert_eq!(sqls.len(), 1);
+        assert!(sqls[0].contains("VALUES ('a','10.0.0.1','volumetric'"));
+    }
+}
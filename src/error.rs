@@ -0,0 +1,103 @@
+//! Errors of the HTTP API, answered with their status and an `ErrorMessage`
+//! carrying a stable code
+use std::fmt;
+use std;
+use actix_web::{HttpResponse, ResponseError};
+use actix_web::http::StatusCode;
+use diesel;
+use diesel::r2d2::PoolError;
+use models::ErrorMessage;
+
+#[derive(Debug)]
+pub enum ApiError {
+    /// Unparseable or inconsistent parameters.
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    /// A service behind this one, such as an alert webhook, failed.
+    BadGateway(String),
+    /// No database connection came free in time.
+    Unavailable(String),
+    Internal(String),
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match *self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            ApiError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Code for clients to match on, the message is for people.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::BadGateway(_) => "bad_gateway",
+            ApiError::Unavailable(_) => "unavailable",
+            ApiError::Internal(_) => "internal",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match *self {
+            ApiError::BadRequest(ref x) | ApiError::Unauthorized(ref x) | ApiError::Forbidden(ref x) |
+            ApiError::NotFound(ref x) | ApiError::BadGateway(ref x) | ApiError::Unavailable(ref x) |
+            ApiError::Internal(ref x) => x,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl std::error::Error for ApiError {
+    fn description(&self) -> &str {
+        self.message()
+    }
+}
+
+impl ResponseError for ApiError {
+    fn error_response(&self) -> HttpResponse {
+        if self.status().is_server_error() {
+            error!("{}: {}", self.code(), self.message());
+        }
+        HttpResponse::build(self.status()).json(ErrorMessage {
+            error: self.message().to_string(),
+            code: self.code().to_string(),
+        })
+    }
+}
+
+impl From<PoolError> for ApiError {
+    fn from(e: PoolError) -> ApiError {
+        ApiError::Unavailable(format!("no database connection available: {}", e))
+    }
+}
+
+impl From<diesel::result::Error> for ApiError {
+    fn from(e: diesel::result::Error) -> ApiError {
+        match e {
+            diesel::result::Error::NotFound => ApiError::NotFound("not found".to_string()),
+            e => ApiError::Internal(e.to_string()),
+        }
+    }
+}
+
+impl From<Box<std::error::Error>> for ApiError {
+    fn from(e: Box<std::error::Error>) -> ApiError {
+        ApiError::Internal(e.to_string())
+    }
+}
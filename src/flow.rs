@@ -1,33 +1,501 @@
 use actix_web::{
-    AsyncResponder,
-    HttpRequest, HttpResponse, FutureResponse, Json
+    AsyncResponder, Error,
+    HttpRequest, HttpResponse, FutureResponse, Json, Query, Path
 };
 
-use db::{AppState};
-use futures::{Future};
-use std::collections::HashMap;
+use db::AppState;
+use error::ApiError;
+use futures::{future, Future};
+use futures::sync::oneshot;
+use std::thread;
+use std::sync::Arc;
+use sflow::FlowMap;
+use window::SlidingWindow;
+use app;
+use rules;
+use auth::{self, Authorize, TokenParams};
+use tenant::TenantParams;
+use geo;
+use l2::{self, MacQuery};
+use heavy::HeavyParams;
+use models::NewAlertRule;
+use billing::{self, BillingParams};
+use matrix::{self, MatrixParams};
+use chrono::NaiveDateTime;
+use timerange::TimeRange;
+use cache;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FlowParams {
+    /// RFC 3339, epoch seconds, `%Y-%m-%d %H:%M:%S` or `now-15m`, see `timerange`.
+    #[serde(default)]
     pub up_date: String,
+    /// Now when empty.
+    #[serde(default)]
     pub down_date: String,
+    /// `last 15m`, `today` or `yesterday` instead of the dates.
+    #[serde(default)]
+    pub range: Option<String>,
+    /// Zone of the dates without an offset, local by default.
+    #[serde(default)]
+    pub tz: Option<String>,
+    /// `input_date` (receive time, the default) or `utc` (agent time).
+    #[serde(default)]
+    pub time_field: Option<String>,
+    /// Keeps only the flows of this application, see `app`.
+    #[serde(default)]
+    pub app: Option<String>,
+    /// `app` adds an application column between sources and targets.
+    #[serde(default)]
+    pub stage: Option<String>,
+    /// Merges the nodes by `country` or `asn`, see `geo`.
+    #[serde(default)]
+    pub group_by: Option<String>,
+    /// Keeps the traffic towards ASNs outside `HOME_ASNS`.
+    #[serde(default)]
+    pub foreign_only: Option<bool>,
+    /// `l2` links every source to its ingress and egress switch ports.
+    #[serde(default)]
+    pub mode: Option<String>,
+    /// Bearer token of the request, see `auth`.
+    #[serde(skip)]
+    pub token: Option<String>,
+    /// Sliding window answering the range when it covers it.
+    #[serde(skip)]
+    pub window: Option<Arc<SlidingWindow>>,
+}
+
+impl FlowParams {
+    pub fn time_range(&self) -> Result<TimeRange, ApiError> {
+        TimeRange::parse(&self.up_date, &self.down_date, self.range.as_ref().map(|x| x.as_str()),
+            self.tz.as_ref().map(|x| x.as_str()), self.time_field.as_ref().map(|x| x.as_str()))
+    }
+
+    /// Checks the parameters before anything is loaded, returning the range.
+    pub fn validate(&self) -> Result<TimeRange, ApiError> {
+        let range = self.time_range()?;
+        if self.app.as_ref().map(|x| x.trim().is_empty()).unwrap_or(false) {
+            return Err(ApiError::BadRequest("app must not be empty".to_string()));
+        }
+        match self.mode.as_ref().map(|x| x.as_str()) {
+            None | Some("") | Some("l3") | Some("l2") => {},
+            Some(x) => return Err(ApiError::BadRequest(format!("unknown mode {}, expected l3 or l2", x))),
+        }
+        match self.group_by.as_ref().map(|x| x.as_str()) {
+            None | Some("country") | Some("asn") => {},
+            Some(x) => return Err(ApiError::BadRequest(format!("unknown group_by {}, expected country or asn", x))),
+        }
+        match self.stage.as_ref().map(|x| x.as_str()) {
+            None | Some("") | Some("app") => {},
+            Some(x) => return Err(ApiError::BadRequest(format!("unknown stage {}, expected app", x))),
+        }
+        Ok(range)
+    }
+
+    /// Applies the filters, mode, grouping and stage of the request to a graph.
+    pub fn view(&self, mut fm: FlowMap) -> Result<FlowMap, String> {
+        if let Some(ref x) = self.app {
+            app::filter_app(&mut fm, x);
+        }
+        if self.foreign_only.unwrap_or(false) {
+            geo::filter_foreign(&mut fm, &geo::home_asns()?);
+        }
+        match self.mode.as_ref().map(|x| x.as_str()) {
+            None | Some("") | Some("l3") => {},
+            Some("l2") => fm = l2::l2_graph(&fm),
+            Some(x) => return Err(format!("unknown mode {}", x)),
+        }
+        if let Some(ref x) = self.group_by {
+            fm = geo::group_by(&fm, x)?;
+        }
+        match self.stage.as_ref().map(|x| x.as_str()) {
+            None | Some("") => Ok(fm),
+            Some("app") => Ok(app::stage_app(&fm)),
+            Some(x) => Err(format!("unknown stage {}", x)),
+        }
+    }
+}
+
+/// Parses an `up_date`, `down_date` pair of the collector's clock, see `timerange`.
+pub fn parse_range(up: &str, down: &str) -> Result<(NaiveDateTime, NaiveDateTime), ApiError> {
+    TimeRange::parse(up, down, None, None, None).map(|x| x.local())
 }
 
 pub fn flow_post((item, req): (Json<FlowParams>, HttpRequest<AppState>)) -> FutureResponse<HttpResponse> {
-    let o = item.clone();
+    flow_query(item.into_inner(), req)
+}
+
+/// `flow_post` with the parameters in the query string, cacheable for
+/// closed ranges, see `cache`.
+pub fn flow_get((params, req): (Query<FlowParams>, HttpRequest<AppState>)) -> FutureResponse<HttpResponse> {
+    flow_query(params.into_inner(), req)
+}
+
+fn flow_query(mut o: FlowParams, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let range = match o.validate() {
+        Ok(x) => x,
+        Err(x) => return Box::new(future::err(x.into())),
+    };
+    o.window = Some(req.state().window.clone());
+    o.token = auth::bearer(&req);
+    let closed = range.is_closed();
+    let names = req.state().names.clone();
+    let r = req.clone();
     req.state().db
-        .send(FlowParams {
+        .send(o)
+        .from_err()
+        .and_then(move |res| match res {
+            Ok((mut d3, scope)) => {
+                names.annotate(&mut d3.nodes);
+                Ok(cache::json_response(&r, &d3, closed, scope.is_unrestricted()))
+            },
+            Err(x) => Err(x.into()),
+        })
+        .responder()
+}
+
+/// Loss counters of every agent, see `seqtrack`
+#[derive(Debug, Clone)]
+pub struct LossQuery {
+    pub token: Option<String>,
+}
+
+pub fn loss_get(req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    req.state().db
+        .send(LossQuery { token: auth::bearer(&req) })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(loss) => Ok(HttpResponse::Ok().json(loss)),
+            Err(x) => Err(x.into()),
+        })
+        .responder()
+}
+
+/// Registered agents, see `registry`
+#[derive(Debug, Clone)]
+pub struct AgentsQuery {
+    pub token: Option<String>,
+}
+
+pub fn agents_get(req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    req.state().db
+        .send(AgentsQuery { token: auth::bearer(&req) })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(agents) => Ok(HttpResponse::Ok().json(agents)),
+            Err(x) => Err(x.into()),
+        })
+        .responder()
+}
+
+/// Body of `/flow/timeseries`; the range takes the options of `FlowParams`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TimeSeriesParams {
+    #[serde(default)]
+    pub up_date: String,
+    #[serde(default)]
+    pub down_date: String,
+    #[serde(default)]
+    pub range: Option<String>,
+    #[serde(default)]
+    pub tz: Option<String>,
+    /// Also the clock the buckets are counted in.
+    #[serde(default)]
+    pub time_field: Option<String>,
+    pub interval: String,
+    #[serde(default)]
+    pub split: Option<String>,
+    #[serde(default)]
+    pub top: Option<usize>,
+    /// Bearer token of the request, see `auth`.
+    #[serde(skip)]
+    pub token: Option<String>,
+}
+
+impl TimeSeriesParams {
+    pub fn time_range(&self) -> Result<TimeRange, ApiError> {
+        TimeRange::parse(&self.up_date, &self.down_date, self.range.as_ref().map(|x| x.as_str()),
+            self.tz.as_ref().map(|x| x.as_str()), self.time_field.as_ref().map(|x| x.as_str()))
+    }
+}
+
+pub fn timeseries_post((item, req): (Json<TimeSeriesParams>, HttpRequest<AppState>)) -> FutureResponse<HttpResponse> {
+    let mut o = item.into_inner();
+    o.token = auth::bearer(&req);
+    req.state().db
+        .send(o)
+        .from_err()
+        .and_then(|res| match res {
+            Ok(ts) => Ok(HttpResponse::Ok().json(ts)),
+            Err(x) => Err(x.into()),
+        })
+        .responder()
+}
+
+pub fn heavy_get((params, req): (Query<HeavyParams>, HttpRequest<AppState>)) -> FutureResponse<HttpResponse> {
+    let heavy = req.state().heavy.clone();
+    let params = params.into_inner();
+    req.state().db
+        .send(Authorize { token: auth::bearer(&req) })
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(scope) => match heavy.lock() {
+                Ok(heavy) => match heavy.scoped_report(&params, &scope) {
+                    Ok(x) => Ok(HttpResponse::Ok().json(x)),
+                    Err(x) => Err(x.into()),
+                },
+                Err(x) => Err(ApiError::Internal(x.to_string()).into()),
+            },
+            Err(x) => Err(x.into()),
+        })
+        .responder()
+}
+
+/// DDoS incidents overlapping a time range, see `ddos`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IncidentQuery {
+    pub up_date: String,
+    pub down_date: String,
+    /// Bearer token of the request, see `auth`.
+    #[serde(skip)]
+    pub token: Option<String>,
+}
+
+pub fn alerts_post((item, req): (Json<FlowParams>, HttpRequest<AppState>)) -> FutureResponse<HttpResponse> {
+    let o = item.into_inner();
+    req.state().db
+        .send(IncidentQuery {
             up_date: o.up_date,
             down_date: o.down_date,
+            token: auth::bearer(&req),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(incidents) => Ok(HttpResponse::Ok().json(incidents)),
+            Err(x) => Err(x.into()),
+        })
+        .responder()
+}
+
+/// Port scans and host sweeps inside a time range, see `scan`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScanQuery {
+    pub up_date: String,
+    pub down_date: String,
+    /// Bearer token of the request, see `auth`.
+    #[serde(skip)]
+    pub token: Option<String>,
+}
+
+pub fn scans_post((item, req): (Json<FlowParams>, HttpRequest<AppState>)) -> FutureResponse<HttpResponse> {
+    let o = item.into_inner();
+    req.state().db
+        .send(ScanQuery {
+            up_date: o.up_date,
+            down_date: o.down_date,
+            token: auth::bearer(&req),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(events) => Ok(HttpResponse::Ok().json(events)),
+            Err(x) => Err(x.into()),
         })
+        .responder()
+}
+
+/// Alert rule management, see `rules`
+#[derive(Debug, Clone)]
+pub enum RuleCommand {
+    List,
+    Create(NewAlertRule),
+    Delete(i32),
+    /// The one rule, as a list.
+    Get(i32),
+}
+
+/// A `RuleCommand` with the bearer token of the admin sending it.
+#[derive(Debug, Clone)]
+pub struct RuleRequest {
+    pub token: Option<String>,
+    pub cmd: RuleCommand,
+}
+
+pub fn rules_command(cmd: RuleCommand, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    req.state().db
+        .send(RuleRequest { token: auth::bearer(&req), cmd: cmd })
         .from_err()
         .and_then(|res| match res {
-            Ok(user) => Ok(HttpResponse::Ok().json(user)),
-            Err(x) => {
-                let mut hash = HashMap::new();
-                hash.insert("error", x.to_string());
-                Ok(HttpResponse::Ok().json(hash))
+            Ok(rules) => Ok(HttpResponse::Ok().json(rules)),
+            Err(x) => Err(x.into()),
+        })
+        .responder()
+}
+
+pub fn rules_get(req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    rules_command(RuleCommand::List, req)
+}
+
+pub fn rules_post((item, req): (Json<NewAlertRule>, HttpRequest<AppState>)) -> FutureResponse<HttpResponse> {
+    rules_command(RuleCommand::Create(item.into_inner()), req)
+}
+
+pub fn rules_delete((id, req): (Path<i32>, HttpRequest<AppState>)) -> FutureResponse<HttpResponse> {
+    rules_command(RuleCommand::Delete(id.into_inner()), req)
+}
+
+/// Sends a test notification of a rule. The delivery may wait for slow
+/// targets, so it runs on a thread of its own instead of a `DbExecutor`.
+pub fn rules_test((id, req): (Path<i32>, HttpRequest<AppState>)) -> FutureResponse<HttpResponse> {
+    req.state().db
+        .send(RuleRequest { token: auth::bearer(&req), cmd: RuleCommand::Get(id.into_inner()) })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(rules) => Ok(rules),
+            Err(x) => Err(x.into()),
+        })
+        .and_then(|rules| {
+            let (tx, rx) = oneshot::channel();
+            thread::spawn(move || {
+                let errors = rules.iter()
+                    .flat_map(|x| rules::deliver(x, "test", "test", 0.0))
+                    .collect::<Vec<String>>();
+                let _ = tx.send((rules, errors));
+            });
+            rx.map_err(|_| Error::from(ApiError::Internal("rule test delivery stopped".to_string())))
+        })
+        .and_then(|(rules, errors)| {
+            if !errors.is_empty() {
+                return Err(ApiError::BadGateway(errors.join("; ")).into());
+            }
+            Ok(HttpResponse::Ok().json(rules))
+        })
+        .responder()
+}
+
+pub fn billing_get((params, req): (Query<BillingParams>, HttpRequest<AppState>)) -> FutureResponse<HttpResponse> {
+    let csv = params.format.as_ref().map(|x| x == "csv").unwrap_or(false);
+    let mut o = params.into_inner();
+    o.token = auth::bearer(&req);
+    req.state().db
+        .send(o)
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(ref rows) if csv => Ok(HttpResponse::Ok()
+                .content_type("text/csv")
+                .body(billing::to_csv(rows))),
+            Ok(rows) => Ok(HttpResponse::Ok().json(rows)),
+            Err(x) => Err(x.into()),
+        })
+        .responder()
+}
+
+pub fn matrix_post((item, req): (Json<MatrixParams>, HttpRequest<AppState>)) -> FutureResponse<HttpResponse> {
+    let csv = item.format.as_ref().map(|x| x == "csv").unwrap_or(false);
+    let mut o = item.into_inner();
+    o.token = auth::bearer(&req);
+    req.state().db
+        .send(o)
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(ref m) if csv => Ok(HttpResponse::Ok()
+                .content_type("text/csv")
+                .body(matrix::to_csv(m))),
+            Ok(m) => Ok(HttpResponse::Ok().json(m)),
+            Err(x) => Err(x.into()),
+        })
+        .responder()
+}
+
+pub fn l2_bindings_get((params, req): (Query<MacQuery>, HttpRequest<AppState>)) -> FutureResponse<HttpResponse> {
+    let names = req.state().names.clone();
+    let mut o = params.into_inner();
+    o.token = auth::bearer(&req);
+    req.state().db
+        .send(o)
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(mut rows) => {
+                for x in rows.iter_mut() {
+                    x.vendor = names.vendor(&x.mac);
+                }
+                Ok(HttpResponse::Ok().json(rows))
             },
+            Err(x) => Err(x.into()),
+        })
+        .responder()
+}
+
+/// API token management, see `auth`
+#[derive(Debug, Clone)]
+pub enum TokenCommand {
+    List,
+    Create(TokenParams),
+    Revoke(i32),
+}
+
+/// A `TokenCommand` with the bearer token of the admin sending it.
+#[derive(Debug, Clone)]
+pub struct TokenRequest {
+    pub token: Option<String>,
+    pub cmd: TokenCommand,
+}
+
+pub fn tokens_command(cmd: TokenCommand, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    req.state().db
+        .send(TokenRequest { token: auth::bearer(&req), cmd: cmd })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(tokens) => Ok(HttpResponse::Ok().json(tokens)),
+            Err(x) => Err(x.into()),
+        })
+        .responder()
+}
+
+pub fn tokens_get(req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    tokens_command(TokenCommand::List, req)
+}
+
+pub fn tokens_post((item, req): (Json<TokenParams>, HttpRequest<AppState>)) -> FutureResponse<HttpResponse> {
+    tokens_command(TokenCommand::Create(item.into_inner()), req)
+}
+
+pub fn tokens_delete((id, req): (Path<i32>, HttpRequest<AppState>)) -> FutureResponse<HttpResponse> {
+    tokens_command(TokenCommand::Revoke(id.into_inner()), req)
+}
+
+/// Tenant management, see `tenant`
+#[derive(Debug, Clone)]
+pub enum TenantCommand {
+    List,
+    Create(TenantParams),
+    Delete(i32),
+}
+
+#[derive(Debug, Clone)]
+pub struct TenantRequest {
+    pub token: Option<String>,
+    pub cmd: TenantCommand,
+}
+
+pub fn tenants_command(cmd: TenantCommand, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    req.state().db
+        .send(TenantRequest { token: auth::bearer(&req), cmd: cmd })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(tenants) => Ok(HttpResponse::Ok().json(tenants)),
+            Err(x) => Err(x.into()),
         })
         .responder()
 }
+
+pub fn tenants_get(req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    tenants_command(TenantCommand::List, req)
+}
+
+pub fn tenants_post((item, req): (Json<TenantParams>, HttpRequest<AppState>)) -> FutureResponse<HttpResponse> {
+    tenants_command(TenantCommand::Create(item.into_inner()), req)
+}
+
+pub fn tenants_delete((id, req): (Path<i32>, HttpRequest<AppState>)) -> FutureResponse<HttpResponse> {
+    tenants_command(TenantCommand::Delete(id.into_inner()), req)
+}
@@ -0,0 +1,160 @@
+//! Offline GeoIP and ASN enrichment from local MaxMind databases
+use std::collections::BTreeSet;
+use std::fs;
+use std::net::IpAddr;
+use std::time::{Instant, SystemTime};
+use maxminddb::{self, geoip2};
+use sflow::*;
+
+/// Country of an address without GeoIP data.
+pub const UNKNOWN: &'static str = "??";
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct GeoInfo {
+    pub country: String,
+    pub city: String,
+    pub asn: i64,
+    pub org: String,
+}
+
+/// One `.mmdb` file, reopened when its modification time changes.
+struct MmdbFile {
+    path: String,
+    mtime: Option<SystemTime>,
+    reader: Option<maxminddb::Reader<Vec<u8>>>,
+}
+
+impl MmdbFile {
+    fn new(path: String) -> MmdbFile {
+        let mut f = MmdbFile { path: path, mtime: None, reader: None };
+        f.reload();
+        f
+    }
+
+    fn reload(&mut self) {
+        let mtime = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if mtime.is_none() || mtime == self.mtime {
+            return;
+        }
+        match maxminddb::Reader::open_readfile(&self.path) {
+            Ok(r) => {
+                info!("geoip: loaded {}", self.path);
+                self.reader = Some(r);
+                self.mtime = mtime;
+            },
+            // keep the old database while a new one is being written
+            Err(x) => info!("geoip: {}: {:?}", self.path, x),
+        }
+    }
+}
+
+/// City and ASN databases from `GEOIP_CITY` and `GEOIP_ASN`; either may
+/// be missing, the fields it would fill are then left empty.
+pub struct GeoDb {
+    city: MmdbFile,
+    asn: MmdbFile,
+    check_secs: u64,
+    checked: Instant,
+}
+
+impl GeoDb {
+    pub fn load() -> GeoDb {
+        GeoDb {
+            city: MmdbFile::new(get_env_or("GEOIP_CITY", "GeoLite2-City.mmdb".to_string())),
+            asn: MmdbFile::new(get_env_or("GEOIP_ASN", "GeoLite2-ASN.mmdb".to_string())),
+            check_secs: get_env_or("GEOIP_CHECK_SECS", 60),
+            checked: Instant::now(),
+        }
+    }
+
+    /// Reopens the databases updated on disk, at most every `GEOIP_CHECK_SECS`.
+    pub fn refresh(&mut self) {
+        if self.checked.elapsed().as_secs() < self.check_secs {
+            return;
+        }
+        self.checked = Instant::now();
+        self.city.reload();
+        self.asn.reload();
+    }
+
+    pub fn lookup(&self, addr: &str) -> GeoInfo {
+        let mut g = GeoInfo::default();
+        let ip: IpAddr = match addr.parse() {
+            Ok(x) => x,
+            Err(_) => return g,
+        };
+        if let Some(ref r) = self.city.reader {
+            if let Ok(c) = r.lookup::<geoip2::City>(ip) {
+                g.country = c.country.and_then(|x| x.iso_code).unwrap_or_default();
+                g.city = c.city.and_then(|x| x.names)
+                    .and_then(|mut x| x.remove("en"))
+                    .unwrap_or_default();
+            }
+        }
+        if let Some(ref r) = self.asn.reader {
+            if let Ok(a) = r.lookup::<geoip2::Asn>(ip) {
+                g.asn = a.autonomous_system_number.unwrap_or(0) as i64;
+                g.org = a.autonomous_system_organization.unwrap_or_default();
+            }
+        }
+        g
+    }
+
+    /// Fills the geo fields of every flow.
+    pub fn enrich(&mut self, fm: &mut FlowMap) {
+        self.refresh();
+        for fd in fm.values_mut() {
+            fd.src_geo = self.lookup(&fd.source);
+            fd.dst_geo = self.lookup(&fd.target);
+        }
+    }
+}
+
+/// The ASNs of our own network, from the comma separated `HOME_ASNS`.
+pub fn home_asns() -> Result<BTreeSet<i64>, String> {
+    let raw: String = get_env_or("HOME_ASNS", String::new());
+    raw.split(',')
+        .map(|x| x.trim().trim_start_matches("AS").trim_start_matches("as"))
+        .filter(|x| !x.is_empty())
+        .map(|x| x.parse::<u32>().map(|x| x as i64).map_err(|_| format!("bad ASN {} in HOME_ASNS", x)))
+        .collect()
+}
+
+/// Keeps the flows towards a known ASN outside `home`.
+pub fn filter_foreign(fm: &mut FlowMap, home: &BTreeSet<i64>) {
+    let rms: Vec<String> = fm.iter()
+        .filter(|x| x.1.dst_geo.asn == 0 || home.contains(&x.1.dst_geo.asn))
+        .map(|x| x.0.clone())
+        .collect();
+    for n in rms.iter() {
+        fm.remove(n);
+    }
+}
+
+fn group_name(addr: &str, g: &GeoInfo, by: &str) -> String {
+    if addr.parse::<IpAddr>().is_err() {
+        return addr.to_string();
+    }
+    match by {
+        "country" if !g.country.is_empty() => g.country.clone(),
+        "country" => UNKNOWN.to_string(),
+        _ if g.asn == 0 => format!("AS? {}", UNKNOWN),
+        _ => format!("AS{} {}", g.asn, g.org),
+    }
+}
+
+/// Merges the Sankey nodes by `country` or `asn`.
+pub fn group_by(fm: &FlowMap, by: &str) -> Result<FlowMap, String> {
+    if by != "country" && by != "asn" {
+        return Err(format!("cannot group by {}", by));
+    }
+    let mut res = FlowMap::new();
+    for fd in fm.values() {
+        let mut x = fd.clone();
+        x.source = group_name(&fd.source, &fd.src_geo, by);
+        x.target = group_name(&fd.target, &fd.dst_geo, by);
+        let key = format!("{}=>{}|{}", x.source, x.target, x.app);
+        merge_flow(&mut res, &key, &x);
+    }
+    Ok(res)
+}
@@ -0,0 +1,365 @@
+//! Streaming heavy-hitter detection with Count-Min sketches and Space-Saving
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+use sflow::*;
+use auth::Scope;
+use error::ApiError;
+use prefix::{self, Prefix};
+
+const CMS_WIDTH: usize = 2048;
+const CMS_DEPTH: usize = 4;
+/// Number of candidates kept per dimension and agent.
+const TOP_CAPACITY: usize = 100;
+
+/// Count-Min sketch, estimates never undercount.
+pub struct CountMin {
+    table: Vec<Vec<u64>>,
+}
+
+impl CountMin {
+    pub fn new() -> CountMin {
+        CountMin { table: vec![vec![0; CMS_WIDTH]; CMS_DEPTH] }
+    }
+
+    fn index(row: usize, key: &str) -> usize {
+        let mut h = DefaultHasher::new();
+        row.hash(&mut h);
+        key.hash(&mut h);
+        (h.finish() % CMS_WIDTH as u64) as usize
+    }
+
+    pub fn add(&mut self, key: &str, w: u64) {
+        for row in 0..CMS_DEPTH {
+            self.table[row][CountMin::index(row, key)] += w;
+        }
+    }
+
+    pub fn estimate(&self, key: &str) -> u64 {
+        (0..CMS_DEPTH).map(|row| self.table[row][CountMin::index(row, key)]).min().unwrap_or(0)
+    }
+}
+
+/// Space-Saving top-K: keeps `capacity` counters, a new key replaces the
+/// smallest one and inherits its count as error bound.
+pub struct SpaceSaving {
+    capacity: usize,
+    counters: HashMap<String, (u64, u64)>,
+}
+
+impl SpaceSaving {
+    pub fn new(capacity: usize) -> SpaceSaving {
+        SpaceSaving { capacity: capacity, counters: HashMap::new() }
+    }
+
+    pub fn add(&mut self, key: &str, w: u64) {
+        if let Some(x) = self.counters.get_mut(key) {
+            x.0 += w;
+            return;
+        }
+        if self.counters.len() < self.capacity {
+            self.counters.insert(key.to_string(), (w, 0));
+            return;
+        }
+        let min = self.counters.iter()
+            .min_by_key(|x| (x.1).0)
+            .map(|x| (x.0.clone(), (x.1).0));
+        if let Some((k, c)) = min {
+            self.counters.remove(&k);
+            self.counters.insert(key.to_string(), (c + w, c));
+        }
+    }
+
+    pub fn top(&self, k: usize) -> Vec<(String, u64, u64)> {
+        let mut all: Vec<(String, u64, u64)> = self.counters.iter()
+            .map(|x| (x.0.clone(), (x.1).0, (x.1).1))
+            .collect();
+        all.sort_by(|a, b| b.1.cmp(&a.1));
+        all.truncate(k);
+        all
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct HeavyHitter {
+    pub key: String,
+    pub bytes: u64,
+    pub error: u64,
+    pub estimate: u64,
+}
+
+/// One dimension: sketch for arbitrary keys plus top-K candidates.
+pub struct Dimension {
+    sketch: CountMin,
+    top: SpaceSaving,
+}
+
+impl Dimension {
+    fn new() -> Dimension {
+        Dimension { sketch: CountMin::new(), top: SpaceSaving::new(TOP_CAPACITY) }
+    }
+
+    fn add(&mut self, key: &str, w: u64) {
+        self.sketch.add(key, w);
+        self.top.add(key, w);
+    }
+
+    pub fn top(&self, k: usize) -> Vec<HeavyHitter> {
+        self.top.top(k).into_iter().map(|(key, bytes, error)| {
+            let estimate = self.sketch.estimate(&key);
+            HeavyHitter {key: key, bytes: bytes, error: error, estimate: estimate}
+        }).collect()
+    }
+}
+
+pub const DIMENSIONS: [&'static str; 4] = ["src", "dst", "pair", "port"];
+
+pub struct AgentHitters {
+    dims: Vec<Dimension>,
+}
+
+impl AgentHitters {
+    fn new() -> AgentHitters {
+        AgentHitters { dims: DIMENSIONS.iter().map(|_| Dimension::new()).collect() }
+    }
+
+    fn add(&mut self, src: &str, dst: &str, dstport: i32, w: u64) {
+        self.dims[0].add(src, w);
+        self.dims[1].add(dst, w);
+        self.dims[2].add(&format!("{}=>{}", src, dst), w);
+        if dstport >= 0 {
+            self.dims[3].add(&dstport.to_string(), w);
+        }
+    }
+
+    pub fn top(&self, dim: &str, k: usize) -> Option<Vec<HeavyHitter>> {
+        DIMENSIONS.iter().position(|x| *x == dim).map(|i| self.dims[i].top(k))
+    }
+}
+
+/// Heavy hitters per agent, plus `*` over all agents. The counters start
+/// over every `epoch` so they follow the current traffic.
+pub struct HeavyHitters {
+    agents: BTreeMap<String, AgentHitters>,
+    epoch: Duration,
+    since: Instant,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HeavyParams {
+    #[serde(default)]
+    pub agent: Option<String>,
+    #[serde(default)]
+    pub dim: Option<String>,
+    #[serde(default)]
+    pub k: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct HeavyReport {
+    pub agent: String,
+    pub seconds: u64,
+    pub top: BTreeMap<String, Vec<HeavyHitter>>,
+}
+
+impl HeavyReport {
+    /// Keeps the hitters with an address in `prefixes`; a port says nothing
+    /// about addresses, so none is kept.
+    fn retain_prefixes(&mut self, prefixes: &[Prefix]) {
+        for (dim, list) in self.top.iter_mut() {
+            match dim.as_str() {
+                "port" => list.clear(),
+                "pair" => list.retain(|x| x.key.split("=>").any(|a| prefix::any_contains(prefixes, a))),
+                _ => list.retain(|x| prefix::any_contains(prefixes, &x.key)),
+            }
+        }
+    }
+}
+
+impl HeavyHitters {
+    pub fn new(epoch_secs: u64) -> HeavyHitters {
+        HeavyHitters {
+            agents: BTreeMap::new(),
+            epoch: Duration::from_secs(epoch_secs),
+            since: Instant::now(),
+        }
+    }
+
+    pub fn observe(&mut self, dg: &Datagram) {
+        if self.since.elapsed() >= self.epoch {
+            self.agents.clear();
+            self.since = Instant::now();
+        }
+        for s in dg.samplev5.iter() {
+            if let Some(f) = sample_flow(s) {
+                let w = (s.sampledPacketSize as u64) * (s.meanSkipCount.max(1) as u64);
+                for a in [dg.agent.as_str(), "*"].iter() {
+                    if !self.agents.contains_key(*a) {
+                        self.agents.insert(a.to_string(), AgentHitters::new());
+                    }
+                    if let Some(x) = self.agents.get_mut(*a) {
+                        x.add(&f.source, &f.target, f.dstport, w);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn report(&self, params: &HeavyParams) -> Result<HeavyReport, String> {
+        let agent = params.agent.clone().unwrap_or("*".to_string());
+        let k = params.k.unwrap_or(10).min(TOP_CAPACITY);
+        let mut top = BTreeMap::new();
+        let dims: Vec<&str> = match params.dim {
+            Some(ref x) => vec![x.as_str()],
+            None => DIMENSIONS.to_vec(),
+        };
+        for d in dims {
+            if !DIMENSIONS.contains(&d) {
+                return Err(format!("unknown dimension {}", d));
+            }
+            let list = match self.agents.get(&agent) {
+                Some(x) => x.top(d, k).unwrap_or_default(),
+                None => vec![],
+            };
+            top.insert(d.to_string(), list);
+        }
+        Ok(HeavyReport {agent: agent, seconds: self.since.elapsed().as_secs(), top: top})
+    }
+
+    /// `report` within `scope`. The counters are kept per agent only, so a
+    /// token limited to agents has to name one and a tenant token is refused.
+    pub fn scoped_report(&self, params: &HeavyParams, scope: &Scope) -> Result<HeavyReport, ApiError> {
+        if scope.tenant_id.is_some() {
+            return Err(ApiError::Forbidden("heavy hitters are not kept per tenant".to_string()));
+        }
+        match params.agent.as_ref().map(|x| x.as_str()) {
+            None | Some("*") if !scope.agents.is_empty() =>
+                return Err(ApiError::Forbidden("the token is limited to agents, name one".to_string())),
+            Some(x) if x != "*" && !scope.allows_agent(x) =>
+                return Err(ApiError::Forbidden(format!("agent {} is outside the token", x))),
+            _ => {},
+        }
+        let mut report = self.report(params).map_err(ApiError::BadRequest)?;
+        if !scope.prefixes.is_empty() {
+            report.retain_prefixes(&scope.prefixes);
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagram(agent: &str, flows: &[(&str, &str, i32, i32)]) -> Datagram {
+        Datagram {
+            agent: agent.to_string(),
+            samplev5: flows.iter().map(|&(src, dst, port, size)| SampleV5 {
+                srcIP: Some(src.to_string()),
+                dstIP: Some(dst.to_string()),
+                TCPSrcPort: Some(40000),
+                TCPDstPort: Some(port),
+                sampledPacketSize: size,
+                meanSkipCount: 10,
+                ..Default::default()
+            }).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn params(agent: &str, dim: &str) -> HeavyParams {
+        HeavyParams { agent: Some(agent.to_string()), dim: Some(dim.to_string()), k: Some(10) }
+    }
+
+    fn keys(r: &HeavyReport, dim: &str) -> Vec<(String, u64)> {
+        r.top[dim].iter().map(|x| (x.key.clone(), x.bytes)).collect()
+    }
+
+    #[test]
+    fn count_min_never_undercounts() {
+        let mut cms = CountMin::new();
+        let mut total = 0;
+        for i in 0..500u64 {
+            cms.add(&format!("10.0.{}.{}", i / 256, i % 256), (i + 1) * 10);
+            total += (i + 1) * 10;
+        }
+        let mut over = 0;
+        for i in 0..500u64 {
+            let est = cms.estimate(&format!("10.0.{}.{}", i / 256, i % 256));
+            assert!(est >= (i + 1) * 10);
+            over += est - (i + 1) * 10;
+        }
+        // the expected overcount of one row is total / width, the minimum
+        // over the rows stays below it
+        assert!(over / 500 <= total / CMS_WIDTH as u64, "{} {}", over / 500, total);
+    }
+
+    #[test]
+    fn space_saving_evicts_the_smallest() {
+        let mut ss = SpaceSaving::new(3);
+        ss.add("a", 10);
+        ss.add("b", 5);
+        ss.add("c", 1);
+        ss.add("a", 2);
+        assert_eq!(ss.top(3), vec![("a".to_string(), 12, 0), ("b".to_string(), 5, 0), ("c".to_string(), 1, 0)]);
+        // d takes the place of c and its count as error
+        ss.add("d", 2);
+        assert_eq!(ss.top(3), vec![("a".to_string(), 12, 0), ("b".to_string(), 5, 0), ("d".to_string(), 3, 1)]);
+        ss.add("e", 1);
+        assert_eq!(ss.top(3), vec![("a".to_string(), 12, 0), ("b".to_string(), 5, 0), ("e".to_string(), 4, 3)]);
+        assert_eq!(ss.top(1), vec![("a".to_string(), 12, 0)]);
+    }
+
+    #[test]
+    fn space_saving_error_bound() {
+        let mut ss = SpaceSaving::new(10);
+        let mut total = 0;
+        for i in 0..1000u64 {
+            // every third unit of weight goes to the heavy key
+            if i % 3 == 0 {
+                ss.add("heavy", 20);
+                total += 20;
+            }
+            ss.add(&format!("light{}", i), 1 + i % 7);
+            total += 1 + i % 7;
+        }
+        let top = ss.top(10);
+        assert_eq!(top[0].0, "heavy");
+        let truth = 334 * 20;
+        assert!(top[0].1 >= truth && top[0].1 - top[0].2 <= truth);
+        // no counter overestimates by more than total / capacity
+        for x in top.iter() {
+            assert!(x.2 <= total / 10, "{:?}", x);
+        }
+    }
+
+    #[test]
+    fn dimensions_per_agent_and_over_all() {
+        let mut h = HeavyHitters::new(3600);
+        h.observe(&datagram("a", &[("10.0.0.1", "10.0.0.9", 443, 100), ("10.0.0.2", "10.0.0.9", 80, 50)]));
+        h.observe(&datagram("b", &[("10.0.0.1", "10.0.0.8", 443, 30)]));
+        let all = h.report(&HeavyParams { agent: None, dim: None, k: None }).unwrap();
+        assert_eq!(all.agent, "*");
+        assert_eq!(keys(&all, "src"), vec![("10.0.0.1".to_string(), 1300), ("10.0.0.2".to_string(), 500)]);
+        assert_eq!(keys(&all, "port"), vec![("443".to_string(), 1300), ("80".to_string(), 500)]);
+        assert_eq!(keys(&all, "pair")[0], ("10.0.0.1=>10.0.0.9".to_string(), 1000));
+        let b = h.report(&params("b", "dst")).unwrap();
+        assert_eq!(keys(&b, "dst"), vec![("10.0.0.8".to_string(), 300)]);
+        assert!(h.report(&params("b", "vlan")).is_err());
+        assert!(h.report(&params("c", "dst")).unwrap().top["dst"].is_empty());
+    }
+
+    #[test]
+    fn epochs_start_over() {
+        // an epoch of zero seconds is over at every datagram
+        let mut h = HeavyHitters::new(0);
+        h.observe(&datagram("a", &[("10.0.0.1", "10.0.0.9", 443, 100)]));
+        h.observe(&datagram("a", &[("10.0.0.2", "10.0.0.9", 443, 100)]));
+        assert_eq!(keys(&h.report(&params("a", "src")).unwrap(), "src"), vec![("10.0.0.2".to_string(), 1000)]);
+        let mut h = HeavyHitters::new(3600);
+        h.observe(&datagram("a", &[("10.0.0.1", "10.0.0.9", 443, 100)]));
+        h.observe(&datagram("a", &[("10.0.0.2", "10.0.0.9", 443, 100)]));
+        assert_eq!(keys(&h.report(&params("a", "src")).unwrap(), "src").len(), 2);
+    }
+}
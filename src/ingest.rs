@@ -0,0 +1,76 @@
+//! Ingest sources: sflowtool text on stdin or in a file, or sflowtool run
+//! on a UDP port or a capture
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::process::{Command, Stdio};
+use std;
+use diesel::pg::PgConnection;
+use config::IngestConfig;
+use sflow::*;
+
+#[derive(Debug, Clone)]
+pub enum Source {
+    Stdin,
+    File(String),
+    Udp(u16),
+    Pcap(String),
+}
+
+impl Source {
+    pub fn from_config(cfg: &IngestConfig) -> Source {
+        match cfg.source.as_str() {
+            "file" => Source::File(cfg.path.clone()),
+            "udp" => Source::Udp(cfg.udp_port),
+            "pcap" => Source::Pcap(cfg.path.clone()),
+            _ => Source::Stdin,
+        }
+    }
+
+    /// `.pcap` and `.pcapng` files are captures, anything else sflowtool output.
+    pub fn from_path(path: &str) -> Source {
+        let lower = path.to_lowercase();
+        if lower.ends_with(".pcap") || lower.ends_with(".pcapng") {
+            Source::Pcap(path.to_string())
+        } else {
+            Source::File(path.to_string())
+        }
+    }
+}
+
+/// Stores the datagrams of `source` until it ends.
+pub fn run(conn: &PgConnection, sinks: &IngestSinks, cfg: &IngestConfig, source: &Source) -> Result<(), Box<std::error::Error>> {
+    info!("ingest from {:?}", source);
+    match *source {
+        Source::Stdin => input_data(conn, sinks, cfg, BufReader::new(io::stdin())),
+        Source::File(ref path) => {
+            let f = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+            input_data(conn, sinks, cfg, BufReader::new(f))
+        },
+        Source::Udp(port) => {
+            let port = port.to_string();
+            sflowtool(conn, sinks, cfg, &["-p", port.as_str()])
+        },
+        Source::Pcap(ref path) => sflowtool(conn, sinks, cfg, &["-r", path.as_str()]),
+    }
+}
+
+/// Runs sflowtool with `args` and stores what it prints.
+fn sflowtool(conn: &PgConnection, sinks: &IngestSinks, cfg: &IngestConfig, args: &[&str]) -> Result<(), Box<std::error::Error>> {
+    let mut child = Command::new(&cfg.sflowtool)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("{}: {}", cfg.sflowtool, e))?;
+    let out = child.stdout.take().ok_or("sflowtool has no stdout")?;
+    let res = input_data(conn, sinks, cfg, BufReader::new(out));
+    if res.is_err() {
+        let _ = child.kill();
+    }
+    let status = child.wait()?;
+    res?;
+    if !status.success() {
+        return Err(From::from(format!("{} {}: {}", cfg.sflowtool, args.join(" "), status)));
+    }
+    Ok(())
+}
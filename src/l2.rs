@@ -0,0 +1,117 @@
+//! MAC-to-IP bindings and the L2 (VLAN / switch port) graph
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use std;
+use diesel::pg::PgConnection;
+use diesel::sql_query;
+use diesel::query_dsl::*;
+use sflow::*;
+
+/// How often new bindings are written to `mac_ip`.
+const FLUSH_INTERVAL: u64 = 10;
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct MacQuery {
+    #[serde(default)]
+    pub mac: Option<String>,
+    #[serde(default)]
+    pub ip: Option<String>,
+    #[serde(default)]
+    pub vlan: Option<i32>,
+    /// Bearer token of the request, see `auth`.
+    #[serde(skip)]
+    pub token: Option<String>,
+}
+
+/// One MAC as served by `GET /l2/bindings`.
+#[derive(Debug, Serialize, Clone)]
+pub struct MacBinding {
+    pub mac: String,
+    pub vendor: Option<String>,
+    pub ip: String,
+    pub agent: String,
+    pub vlan: i32,
+    pub in_if: i32,
+    pub first_seen: String,
+    pub last_seen: String,
+}
+
+/// Learns which IP addresses sit behind which source MAC, and on which
+/// switch port, from samples carrying both.
+pub struct MacLearner {
+    // (mac, ip) => (agent, vlan, in_if)
+    seen: BTreeMap<(String, String), (String, i32, i32)>,
+    last_flush: Instant,
+}
+
+impl MacLearner {
+    pub fn new() -> MacLearner {
+        MacLearner {
+            seen: BTreeMap::new(),
+            last_flush: Instant::now(),
+        }
+    }
+
+    pub fn observe(&mut self, dg: &Datagram) {
+        for s in dg.samplev5.iter() {
+            let ip = s.srcIP.as_ref().or(s.srcIP6.as_ref());
+            if let (Some(mac), Some(ip)) = (s.srcMAC.as_ref(), ip) {
+                self.seen.insert((mac.clone(), ip.clone()),
+                    (dg.agent.clone(), s.vlan.unwrap_or(0), s.inputPort));
+            }
+        }
+    }
+
+    pub fn need_flush(&self) -> bool {
+        self.last_flush.elapsed() >= Duration::from_secs(FLUSH_INTERVAL)
+    }
+
+    /// Upserts the bindings seen since the last flush into `mac_ip`.
+    pub fn flush(&mut self, conn: &PgConnection) -> Result<(), Box<std::error::Error>> {
+        self.last_flush = Instant::now();
+        if self.seen.is_empty() {
+            return Ok(());
+        }
+        let mut multi_data = String::from("INSERT INTO mac_ip (mac, ip, agent, vlan, in_if) VALUES \n");
+        for (&(ref mac, ref ip), &(ref agent, vlan, in_if)) in self.seen.iter() {
+            multi_data.push_str(&format!("('{}','{}','{}',{},{}),", mac, ip, agent, vlan, in_if));
+        }
+        multi_data.pop();
+        multi_data.push_str("\nON CONFLICT (mac, ip) DO UPDATE SET \
+            last_seen = CURRENT_TIMESTAMP, \
+            agent = EXCLUDED.agent, \
+            vlan = EXCLUDED.vlan, \
+            in_if = EXCLUDED.in_if");
+        sql_query(multi_data).execute(conn)?;
+        self.seen.clear();
+        Ok(())
+    }
+}
+
+fn port_node(agent: &str, ifindex: i32, vlan: i32) -> String {
+    if vlan > 0 {
+        format!("{} if{} vlan{}", agent, ifindex, vlan)
+    } else {
+        format!("{} if{}", agent, ifindex)
+    }
+}
+
+/// Turns every flow into `source => ingress port => egress port`, so a
+/// heavy talker shows up next to the access port it hangs off.
+pub fn l2_graph(fm: &FlowMap) -> FlowMap {
+    let mut res = FlowMap::new();
+    for fd in fm.values() {
+        let inp = port_node(&fd.agent, fd.in_if, fd.vlan);
+        let out = port_node(&fd.agent, fd.out_if, fd.vlan);
+        let mut left = fd.clone();
+        left.target = inp.clone();
+        merge_flow(&mut res, &format!("{}=>{}", left.source, left.target), &left);
+        if fd.out_if > 0 && out != inp {
+            let mut right = fd.clone();
+            right.source = inp;
+            right.target = out;
+            merge_flow(&mut res, &format!("{}=>{}", right.source, right.target), &right);
+        }
+    }
+    res
+}
@@ -0,0 +1,209 @@
+//! Live Sankey snapshots pushed to WebSocket subscribers
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use actix::prelude::*;
+use actix_web::{ws, AsyncResponder, FutureResponse, HttpRequest, HttpResponse, Query};
+use futures::Future;
+use serde_json;
+use auth::{self, Authorize, Scope};
+use db::{AppState, FlowD3};
+use sflow::*;
+use window::SlidingWindow;
+use names::Names;
+
+/// A serialized `FlowD3` snapshot for one subscriber.
+pub struct LiveUpdate(pub String);
+
+impl Message for LiveUpdate {
+    type Result = ();
+}
+
+pub struct Subscribe {
+    pub addr: Recipient<LiveUpdate>,
+    pub filter: LiveParams,
+    pub scope: Scope,
+}
+
+impl Message for Subscribe {
+    type Result = usize;
+}
+
+pub struct Unsubscribe(pub usize);
+
+impl Message for Unsubscribe {
+    type Result = ();
+}
+
+/// Query string of `/flow/live`. `window` is in seconds.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct LiveParams {
+    #[serde(default)]
+    pub agent: Option<String>,
+    #[serde(default)]
+    pub ntype: Option<String>,
+    #[serde(default)]
+    pub addr: Option<String>,
+    #[serde(default)]
+    pub window: Option<u64>,
+    /// Bearer token for browsers, which cannot set headers on a WebSocket.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+impl LiveParams {
+    pub fn accept(&self, fd: &FlowDirection) -> bool {
+        if let Some(ref x) = self.agent {
+            if &fd.agent != x {
+                return false;
+            }
+        }
+        if let Some(ref x) = self.ntype {
+            if &fd.ntype != x {
+                return false;
+            }
+        }
+        if let Some(ref x) = self.addr {
+            if &fd.source != x && &fd.target != x {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Sends every subscriber a snapshot of its own part of the sliding
+/// window once per second.
+pub struct LiveHub {
+    window: Arc<SlidingWindow>,
+    names: Arc<Names>,
+    subscribers: HashMap<usize, Subscribe>,
+    next_id: usize,
+}
+
+impl LiveHub {
+    pub fn new(window: Arc<SlidingWindow>, names: Arc<Names>) -> LiveHub {
+        LiveHub {
+            window: window,
+            names: names,
+            subscribers: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn snapshot(&self, now: i64, filter: &LiveParams, scope: &Scope) -> FlowD3 {
+        let len = filter.window.unwrap_or(60) as i64;
+        let fm = self.window.collect(now - len + 1, now, |x| filter.accept(x) && scope.allows(x));
+        match build_d3_data(&fm) {
+            Ok((mut nodes, links)) => {
+                self.names.annotate(&mut nodes);
+                FlowD3 {nodes: nodes, links: links}
+            },
+            Err(_) => Default::default(),
+        }
+    }
+
+    fn tick(&mut self) {
+        let now = self.window.now();
+        let mut gone: Vec<usize> = vec![];
+        for (id, sub) in self.subscribers.iter() {
+            let text = serde_json::to_string(&self.snapshot(now, &sub.filter, &sub.scope)).unwrap_or_default();
+            if sub.addr.do_send(LiveUpdate(text)).is_err() {
+                gone.push(*id);
+            }
+        }
+        for id in gone.iter() {
+            self.subscribers.remove(id);
+        }
+    }
+}
+
+impl Actor for LiveHub {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(Duration::from_secs(1), |act, _| act.tick());
+    }
+}
+
+impl Handler<Subscribe> for LiveHub {
+    type Result = usize;
+
+    fn handle(&mut self, msg: Subscribe, _: &mut Self::Context) -> usize {
+        self.next_id += 1;
+        self.subscribers.insert(self.next_id, msg);
+        self.next_id
+    }
+}
+
+impl Handler<Unsubscribe> for LiveHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Unsubscribe, _: &mut Self::Context) {
+        self.subscribers.remove(&msg.0);
+    }
+}
+
+/// One WebSocket connection of `/flow/live`.
+pub struct LiveSession {
+    id: usize,
+    filter: LiveParams,
+    scope: Scope,
+}
+
+impl Actor for LiveSession {
+    type Context = ws::WebsocketContext<Self, AppState>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let addr = ctx.address();
+        ctx.state().live
+            .send(Subscribe {addr: addr.recipient(), filter: self.filter.clone(), scope: self.scope.clone()})
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(id) => act.id = id,
+                    _ => ctx.stop(),
+                }
+                actix::fut::ok(())
+            })
+            .wait(ctx);
+    }
+
+    fn stopping(&mut self, ctx: &mut Self::Context) -> Running {
+        ctx.state().live.do_send(Unsubscribe(self.id));
+        Running::Stop
+    }
+}
+
+impl Handler<LiveUpdate> for LiveSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: LiveUpdate, ctx: &mut Self::Context) {
+        ctx.text(msg.0);
+    }
+}
+
+impl StreamHandler<ws::Message, ws::ProtocolError> for LiveSession {
+    fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
+        match msg {
+            ws::Message::Ping(msg) => ctx.pong(&msg),
+            ws::Message::Close(_) => ctx.stop(),
+            _ => (),
+        }
+    }
+}
+
+/// Upgrades to a `LiveSession` once the token of the request is authorized.
+pub fn live_ws((params, req): (Query<LiveParams>, HttpRequest<AppState>)) -> FutureResponse<HttpResponse> {
+    let mut filter = params.into_inner();
+    let token = auth::bearer(&req).or_else(|| filter.token.take());
+    let r = req.clone();
+    req.state().db
+        .send(Authorize { token: token })
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(scope) => ws::start(&r, LiveSession {id: 0, filter: filter, scope: scope}),
+            Err(x) => Err(x.into()),
+        })
+        .responder()
+}
@@ -1,6 +1,5 @@
 #[link(name="openssl", kind="static")]
 extern crate openssl;
-use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
 
 #[macro_use] 
 extern crate text_io;
@@ -21,20 +20,62 @@ extern crate r2d2;
 extern crate uuid;
 extern crate bytes;
 extern crate chrono;
+extern crate chrono_tz;
 extern crate r2d2_diesel;
 #[macro_use]
 extern crate diesel;
+extern crate maxminddb;
+extern crate toml;
+extern crate clap;
+#[macro_use]
+extern crate diesel_migrations;
 //use postgres::types::*;
 
 use std::{thread};
+use std::sync::{Arc, Mutex};
 mod flow;
 mod sflow;
+mod seqtrack;
+mod registry;
+mod timeseries;
+mod window;
+mod live;
+mod heavy;
+mod ddos;
+mod prefix;
+mod scan;
+mod notify;
+mod rules;
+mod baseline;
+mod billing;
+mod matrix;
+mod app;
+mod geo;
+mod names;
+mod oui;
+mod l2;
+mod tls;
+mod auth;
+mod tenant;
+mod config;
+mod ingest;
+mod migrate;
+mod cli;
+mod error;
+mod timerange;
+mod cache;
 mod db;
 mod schema;
 mod models;
 use flow::*;
 use sflow::*;
 use db::*;
+use live::*;
+use window::SlidingWindow;
+use heavy::HeavyHitters;
+use names::{Names, NamesConfig};
+use config::Config;
+use ingest::Source;
 use diesel::pg::PgConnection;
 use diesel::r2d2::{ConnectionManager};
 use actix::prelude::*;
@@ -52,50 +93,160 @@ fn p404(_req: &HttpRequest<AppState>) -> actix_web::Result<actix_web::fs::NamedF
 
 
 fn main() -> Result<(), Box<std::error::Error>> {
-    ::std::env::set_var("RUST_LOG", "info");
+    let matches = cli::app().get_matches();
+    let mut config = Config::load(&cli::config_path(&matches))?;
+    if ::std::env::var("RUST_LOG").is_err() {
+        ::std::env::set_var("RUST_LOG", &config.server.log);
+    }
     env_logger::init();
-        
+    // every subcommand checks the sections it uses only
+    config.validate_database()?;
+    match matches.subcommand() {
+        ("serve", _) => {
+            config.validate_server()?;
+            config.validate_ingest(false)?;
+            serve(&config, false)
+        },
+        ("ingest", Some(m)) => {
+            cli::apply_ingest(&mut config.ingest, m)?;
+            config.validate_ingest(true)?;
+            cli::ingest(&config)
+        },
+        ("migrate", Some(m)) => cli::migrate(&config, m),
+        ("replay", Some(m)) => {
+            config.validate_ingest(false)?;
+            cli::replay(&config, m)
+        },
+        ("query", Some(m)) => cli::query(&config, m),
+        _ => {
+            config.validate_server()?;
+            config.validate_ingest(true)?;
+            serve(&config, true)
+        },
+    }
+}
+
+/// Runs the API, and the configured ingest when `with_ingest` is set.
+fn serve(config: &Config, with_ingest: bool) -> Result<(), Box<std::error::Error>> {
     let sys = actix::System::new("sflow-system");
-    let manager = ConnectionManager::<PgConnection>::new(get_sql_url()?);
-    let pool = r2d2::Pool::new(manager)
+    let manager = ConnectionManager::<PgConnection>::new(config.database_url()?);
+    let pool = r2d2::Pool::builder()
+        .max_size(config.database.pool_size)
+        .build(manager)
         .expect("Failed to create pool.");
+    migrate::ensure(&*pool.get()?, config.database.auto_migrate)?;
     let poolc = pool.clone();
-    let addr = SyncArbiter::start(8, move || DbExecutor(poolc.clone()));
-
-
-    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
-    builder.set_private_key_file("rootAkey.pem", SslFiletype::PEM).unwrap();
-    builder.set_certificate_chain_file("rootA.pem").unwrap();
-
+    let poolr = pool.clone();
+    let poolb = pool.clone();
+    let api_config = config.api_config();
+    let addr = SyncArbiter::start(config.database.executors, move || DbExecutor(poolc.clone(), api_config));
+    let window = Arc::new(if with_ingest {
+        SlidingWindow::new(config.ingest.window_secs)
+    } else {
+        SlidingWindow::unfed(config.ingest.window_secs)
+    });
+    let heavy = Arc::new(Mutex::new(HeavyHitters::new(config.ingest.heavy_epoch_secs)));
+    let sinks = IngestSinks {window: window.clone(), heavy: heavy.clone()};
+    let names = Names::new(NamesConfig::from_env());
+    let live = LiveHub::new(window.clone(), names.clone()).start();
 
-    thread::spawn(move || {
-        let conn: &PgConnection = &pool.clone().get().unwrap();
-        match input_data(conn) {
-            Ok(_x) => {},
-            Err(x) => {
-                info!("{}",x);
+    if with_ingest {
+        let cfg = config.ingest.clone();
+        thread::spawn(move || {
+            let conn: &PgConnection = &pool.clone().get().unwrap();
+            match ingest::run(conn, &sinks, &cfg, &Source::from_config(&cfg)) {
+                Ok(_x) => {},
+                Err(x) => {
+                    info!("{}",x);
+                }
             }
-        }
-    });
+        });
+    }
+
+    thread::spawn(move || rules::run(poolr));
+    thread::spawn(move || baseline::run(poolb));
 
     // Start http server
-    server::new(move || {
-        App::with_state(AppState{db: addr.clone()})
+    let cors = config.cors.clone();
+    let mut srv = server::new(move || {
+        App::with_state(AppState{db: addr.clone(), live: live.clone(), window: window.clone(),
+            heavy: heavy.clone(), names: names.clone()})
             // enable logger
             .middleware(middleware::Logger::default())
             .configure(|app| {
-                Cors::for_app(app)
-                    .allowed_origin("https://10.71.5.59")
+                let mut builder = Cors::for_app(app);
+                // validate_server refuses an empty list, `*` allows any origin
+                for x in cors.origins.iter().filter(|x| *x != "*") {
+                    builder.allowed_origin(x);
+                }
+                builder
                     .allowed_methods(vec!["GET", "POST", "DELETE", "PUT"])
-                    .allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT])
+                    .allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT, header::IF_NONE_MATCH])
+                    .expose_headers(vec![header::ETAG])
                     .allowed_header(header::CONTENT_TYPE)
-                    .max_age(3600)
+                    .max_age(cors.max_age)
                     .resource("/flow", |r| {
+                        r.get().with(flow_get);
                         r.post().with(flow_post);
                     })
+                    .resource("/flow/live", |r| {
+                        r.get().with(live_ws);
+                    })
+                    .resource("/flow/timeseries", |r| {
+                        r.post().with(timeseries_post);
+                    })
                     .resource("/sflow", |r| {
+                        r.get().with(flow_get);
                         r.post().with(flow_post);
                     })
+                    .resource("/alerts", |r| {
+                        r.post().with(alerts_post);
+                    })
+                    .resource("/scans", |r| {
+                        r.post().with(scans_post);
+                    })
+                    .resource("/rules", |r| {
+                        r.get().with(rules_get);
+                        r.post().with(rules_post);
+                    })
+                    .resource("/rules/{id}", |r| {
+                        r.delete().with(rules_delete);
+                    })
+                    .resource("/rules/{id}/test", |r| {
+                        r.post().with(rules_test);
+                    })
+                    .resource("/matrix", |r| {
+                        r.post().with(matrix_post);
+                    })
+                    .resource("/reports/billing", |r| {
+                        r.get().with(billing_get);
+                    })
+                    .resource("/l2/bindings", |r| {
+                        r.get().with(l2_bindings_get);
+                    })
+                    .resource("/admin/tokens", |r| {
+                        r.get().with(tokens_get);
+                        r.post().with(tokens_post);
+                    })
+                    .resource("/admin/tokens/{id}", |r| {
+                        r.delete().with(tokens_delete);
+                    })
+                    .resource("/admin/tenants", |r| {
+                        r.get().with(tenants_get);
+                        r.post().with(tenants_post);
+                    })
+                    .resource("/admin/tenants/{id}", |r| {
+                        r.delete().with(tenants_delete);
+                    })
+                    .resource("/heavy", |r| {
+                        r.get().with(heavy_get);
+                    })
+                    .resource("/agents", |r| {
+                        r.get().with(agents_get);
+                    })
+                    .resource("/loss", |r| {
+                        r.get().with(loss_get);
+                    })
                     .register()
             })
             .default_resource(|r| {
@@ -105,10 +256,16 @@ fn main() -> Result<(), Box<std::error::Error>> {
                     r.route().filter(pred::Not(pred::Get())).f(
                         |_req| HttpResponse::MethodNotAllowed());
                 })
-    }).workers(8)
-        .bind_ssl("10.73.16.244:8080", builder)
-        .unwrap()
-        .start();
+    }).workers(config.server.workers);
+    for x in config.server.listen.iter() {
+        srv = if config.server.tls {
+            srv.bind_ssl(x, tls::acceptor(&config.tls_config())?)?
+        } else {
+            srv.bind(x)?
+        };
+        info!("listening on {}", x);
+    }
+    srv.start();
     let _ = sys.run();
 
     Ok(())
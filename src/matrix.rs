@@ -0,0 +1,170 @@
+//! Traffic matrix between named sites
+use std::collections::BTreeMap;
+use std;
+use prefix::{self, Prefix};
+use sflow::*;
+
+pub const OTHER: &'static str = "other";
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct MatrixParams {
+    pub up_date: String,
+    pub down_date: String,
+    pub sites: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    pub format: Option<String>,
+    /// Adds an `other` site for addresses outside every site.
+    #[serde(default)]
+    pub other: Option<bool>,
+    /// Bearer token of the request, see `auth`.
+    #[serde(skip)]
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct TrafficMatrix {
+    pub sites: Vec<String>,
+    pub bytes: Vec<Vec<i64>>,
+    pub packets: Vec<Vec<i64>>,
+}
+
+/// Parses the site prefixes, failing on the first bad one.
+pub fn parse_sites(sites: &BTreeMap<String, Vec<String>>) -> Result<Vec<(String, Vec<Prefix>)>, String> {
+    let mut res = vec![];
+    for (name, list) in sites.iter() {
+        if name == OTHER {
+            return Err(format!("site name {} is reserved", OTHER));
+        }
+        res.push((name.clone(), prefix::parse_list(&list.join(","))?));
+    }
+    Ok(res)
+}
+
+/// Index of the site with the longest prefix containing `addr`.
+fn site_of(sites: &Vec<(String, Vec<Prefix>)>, addr: &str) -> Option<usize> {
+    let ip = match addr.parse::<std::net::IpAddr>() {
+        Ok(x) => x,
+        Err(_) => return None,
+    };
+    let mut best: Option<(usize, u8)> = None;
+    for (i, &(_, ref prefixes)) in sites.iter().enumerate() {
+        for p in prefixes.iter() {
+            if p.contains(&ip) && best.map(|b| p.len > b.1).unwrap_or(true) {
+                best = Some((i, p.len));
+            }
+        }
+    }
+    best.map(|x| x.0)
+}
+
+/// Sums the edges of a `FlowMap` into the N×N site matrix.
+pub fn build_matrix(fm: &FlowMap, sites: &Vec<(String, Vec<Prefix>)>, other: bool) -> TrafficMatrix {
+    let mut names: Vec<String> = sites.iter().map(|x| x.0.clone()).collect();
+    if other {
+        names.push(OTHER.to_string());
+    }
+    let n = names.len();
+    let mut m = TrafficMatrix {
+        sites: names,
+        bytes: vec![vec![0; n]; n],
+        packets: vec![vec![0; n]; n],
+    };
+    for fd in fm.values() {
+        if fd.source == fd.target || fd.source == "0.0.0.0" || fd.target == "0.0.0.0" {
+            continue;
+        }
+        let s = site_of(sites, &fd.source).or(if other { Some(n - 1) } else { None });
+        let d = site_of(sites, &fd.target).or(if other { Some(n - 1) } else { None });
+        if let (Some(s), Some(d)) = (s, d) {
+            m.bytes[s][d] += fd.size as i64;
+            m.packets[s][d] += fd.packets as i64;
+        }
+    }
+    m
+}
+
+pub fn to_csv(m: &TrafficMatrix) -> String {
+    let mut out = String::from("src_site,dst_site,bytes,packets\n");
+    for (i, s) in m.sites.iter().enumerate() {
+        for (j, d) in m.sites.iter().enumerate() {
+            out.push_str(&format!("\"{}\",\"{}\",{},{}\n",
+                s.replace('"', "\"\""), d.replace('"', "\"\""), m.bytes[i][j], m.packets[i][j]));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sites() -> Vec<(String, Vec<Prefix>)> {
+        let mut m = BTreeMap::new();
+        m.insert("dc".to_string(), vec!["10.0.0.0/8".to_string()]);
+        m.insert("lab".to_string(), vec!["10.1.0.0/16".to_string()]);
+        m.insert("web".to_string(), vec!["192.0.2.0/24".to_string(), "2001:db8::/32".to_string()]);
+        parse_sites(&m).unwrap()
+    }
+
+    fn flows() -> FlowMap {
+        let mut fm = FlowMap::new();
+        for (i, &(s, t, size, packets)) in [
+            ("10.0.0.1", "10.1.2.3", 100, 1),
+            ("10.0.0.2", "10.1.0.9", 30, 1),
+            ("10.1.2.3", "192.0.2.5", 200, 2),
+            ("2001:db8::1", "10.2.0.1", 70, 1),
+            ("10.2.0.1", "10.3.0.1", 50, 1),
+            ("10.0.0.1", "198.51.100.1", 400, 4),
+            ("10.0.0.1", "10.0.0.1", 1000, 10),
+            ("0.0.0.0", "10.0.0.1", 1000, 10),
+        ].iter().enumerate() {
+            fm.insert(i.to_string(), FlowDirection {
+                source: s.to_string(),
+                target: t.to_string(),
+                size: size,
+                packets: packets,
+                ..Default::default()
+            });
+        }
+        fm
+    }
+
+    #[test]
+    fn longest_prefix_picks_the_site() {
+        let m = build_matrix(&flows(), &sites(), false);
+        assert_eq!(m.sites, vec!["dc", "lab", "web"]);
+        assert_eq!(m.bytes, vec![vec![50, 130, 0], vec![0, 0, 200], vec![70, 0, 0]]);
+        assert_eq!(m.packets, vec![vec![1, 2, 0], vec![0, 0, 2], vec![1, 0, 0]]);
+    }
+
+    #[test]
+    fn other_collects_the_rest() {
+        let m = build_matrix(&flows(), &sites(), true);
+        assert_eq!(m.sites, vec!["dc", "lab", "web", OTHER]);
+        assert_eq!(m.bytes[0][3], 400);
+        assert_eq!(m.packets[0][3], 4);
+        let total: i64 = m.bytes.iter().map(|r| r.iter().sum::<i64>()).sum();
+        assert_eq!(total, 850);
+        let without: i64 = build_matrix(&flows(), &sites(), false).bytes.iter()
+            .map(|r| r.iter().sum::<i64>()).sum();
+        assert_eq!(total - without, 400);
+    }
+
+    #[test]
+    fn other_is_reserved() {
+        let mut m = BTreeMap::new();
+        m.insert(OTHER.to_string(), vec!["10.0.0.0/8".to_string()]);
+        assert!(parse_sites(&m).is_err());
+        m.clear();
+        m.insert("dc".to_string(), vec!["10.0.0.0/33".to_string()]);
+        assert!(parse_sites(&m).is_err());
+    }
+
+    #[test]
+    fn csv_lists_every_pair() {
+        let m = build_matrix(&flows(), &sites(), false);
+        let csv = to_csv(&m);
+        assert_eq!(csv.lines().count(), 1 + 9);
+        assert!(csv.contains("\"dc\",\"lab\",130,2\n"));
+    }
+}
@@ -0,0 +1,89 @@
+//! Diesel migrations embedded in the binary, and the schema version check
+//! done before anything touches the database
+use std::io;
+use std;
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use diesel::sql_query;
+use diesel::sql_types::{Bool, Nullable, Text};
+
+embed_migrations!("migrations");
+
+/// Version of the newest directory in `migrations/`, bumped with every
+/// migration added there.
+pub const SCHEMA_VERSION: &'static str = "20261019000014";
+
+#[derive(QueryableByName, Debug)]
+struct Present {
+    #[sql_type = "Bool"]
+    present: bool,
+}
+
+#[derive(QueryableByName, Debug)]
+struct Version {
+    #[sql_type = "Nullable<Text>"]
+    version: Option<String>,
+}
+
+/// Newest migration applied to the database, `None` for an empty one.
+pub fn applied(conn: &PgConnection) -> Result<Option<String>, Box<std::error::Error>> {
+    let table = sql_query("SELECT to_regclass('__diesel_schema_migrations') IS NOT NULL AS present")
+        .get_result::<Present>(conn)?;
+    if !table.present {
+        return Ok(None);
+    }
+    Ok(sql_query("SELECT MAX(version) AS version FROM __diesel_schema_migrations")
+        .get_result::<Version>(conn)?
+        .version)
+}
+
+/// Applies the pending migrations, printing each one.
+pub fn run(conn: &PgConnection) -> Result<(), Box<std::error::Error>> {
+    embedded_migrations::run_with_output(conn, &mut io::stdout())?;
+    Ok(())
+}
+
+/// Refuses a database migrated past this binary, and one behind it unless
+/// `auto` migrates it.
+pub fn ensure(conn: &PgConnection, auto: bool) -> Result<(), Box<std::error::Error>> {
+    let current = applied(conn)?;
+    match current {
+        Some(ref v) if v.as_str() == SCHEMA_VERSION => return Ok(()),
+        Some(ref v) if v.as_str() > SCHEMA_VERSION => {
+            return Err(From::from(format!("database schema {} is newer than {} of this binary, upgrade the binary",
+                v, SCHEMA_VERSION)));
+        },
+        _ => {},
+    }
+    let from = current.unwrap_or_else(|| "empty".to_string());
+    if !auto {
+        return Err(From::from(format!("database schema {} is older than {}, run `sflow_read migrate`",
+            from, SCHEMA_VERSION)));
+    }
+    info!("migrating database schema {} to {}", from, SCHEMA_VERSION);
+    embedded_migrations::run(conn)?;
+    match applied(conn)? {
+        Some(ref v) if v.as_str() == SCHEMA_VERSION => Ok(()),
+        v => Err(From::from(format!("database schema {} after migrating, expected {}",
+            v.unwrap_or_else(|| "empty".to_string()), SCHEMA_VERSION))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::*;
+
+    #[test]
+    fn schema_version_is_newest_migration() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/migrations");
+        let newest = fs::read_dir(dir).unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().into_owned())
+            .max()
+            .unwrap();
+        // `2026-10-19-000013_tenant` is version 20261019000013
+        let version: String = newest.split('_').next().unwrap()
+            .chars().filter(|c| c.is_ascii_digit()).collect();
+        assert_eq!(version, SCHEMA_VERSION);
+    }
+}
@@ -1,8 +1,11 @@
 use chrono;
+use schema::{alert_rule, api_token, tenant};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ErrorMessage {
     pub error: String,
+    /// See `error::ApiError::code`.
+    pub code: String,
 }
 
 #[derive(Serialize, Queryable, Debug)]
@@ -17,4 +20,196 @@ pub struct Flow {
     pub dstport: i32,
     pub ntype: String,
     pub size: i32,
+    pub packets: i32,
+    pub app: String,
+    pub src_country: String,
+    pub src_city: String,
+    pub src_asn: i64,
+    pub src_org: String,
+    pub dst_country: String,
+    pub dst_city: String,
+    pub dst_asn: i64,
+    pub dst_org: String,
+    pub vlan: i32,
+    pub in_if: i32,
+    pub out_if: i32,
+    pub tenant_id: i32,
+}
+
+#[derive(Serialize, Queryable, Debug)]
+pub struct AgentSeq {
+    pub agent: String,
+    pub sub_agent: i32,
+    pub sample_type: String,
+    pub source_id: String,
+    pub last_seq: i64,
+    pub received: i64,
+    pub lost: i64,
+    pub reordered: i64,
+    pub duplicates: i64,
+    pub reboots: i64,
+    pub update_date: chrono::NaiveDateTime,
+}
+
+#[derive(Serialize, Queryable, Debug)]
+pub struct Agent {
+    pub agent: String,
+    pub first_seen: chrono::NaiveDateTime,
+    pub last_seen: chrono::NaiveDateTime,
+    pub datagram_version: i32,
+    pub sys_uptime: i64,
+    pub sample_rates: String,
+    pub sub_agents: String,
+    pub datagrams: i64,
+    pub dps: f32,
+}
+
+#[derive(Serialize, Queryable, Debug)]
+pub struct Incident {
+    pub incident_id: i32,
+    pub start_date: chrono::NaiveDateTime,
+    pub end_date: Option<chrono::NaiveDateTime>,
+    pub agent: String,
+    pub dst: String,
+    pub kind: String,
+    pub peak_bps: i64,
+    pub peak_pps: i64,
+    pub sources: i32,
+    pub baseline_bps: i64,
+    pub detail: String,
+}
+
+#[derive(Serialize, Queryable, Debug)]
+pub struct ScanEvent {
+    pub event_id: i32,
+    pub event_date: chrono::NaiveDateTime,
+    pub agent: String,
+    pub src: String,
+    pub kind: String,
+    pub target: String,
+    pub distinct_count: i32,
+    pub window_secs: i32,
+    pub detail: String,
+}
+
+#[derive(Serialize, Queryable, Debug, Clone)]
+pub struct AlertRule {
+    pub rule_id: i32,
+    pub name: String,
+    pub kind: String,
+    pub agent: String,
+    pub src_prefix: String,
+    pub dst_prefix: String,
+    pub threshold_bps: i64,
+    pub duration_secs: i32,
+    pub hold_secs: i32,
+    pub webhook: String,
+    pub syslog: String,
+    pub enabled: bool,
+}
+
+#[derive(Deserialize, Insertable, Debug, Clone)]
+#[table_name = "alert_rule"]
+pub struct NewAlertRule {
+    pub name: String,
+    pub kind: String,
+    #[serde(default)]
+    pub agent: String,
+    #[serde(default)]
+    pub src_prefix: String,
+    #[serde(default)]
+    pub dst_prefix: String,
+    #[serde(default)]
+    pub threshold_bps: i64,
+    #[serde(default)]
+    pub duration_secs: i32,
+    #[serde(default)]
+    pub hold_secs: i32,
+    #[serde(default)]
+    pub webhook: String,
+    #[serde(default)]
+    pub syslog: String,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Serialize, Queryable, Debug)]
+pub struct EdgeBaseline {
+    pub src: String,
+    pub dst: String,
+    pub how: i16,
+    pub mean: f64,
+    pub stddev: f64,
+    pub samples: i32,
+    pub update_date: chrono::NaiveDateTime,
+}
+
+#[derive(Serialize, Queryable, Debug)]
+pub struct IfCounter {
+    pub counter_id: i32,
+    pub input_date: chrono::NaiveDateTime,
+    pub agent: String,
+    pub if_index: i32,
+    pub if_speed: i64,
+    pub in_octets: i64,
+    pub out_octets: i64,
+}
+
+#[derive(Serialize, Queryable, Debug)]
+pub struct MacIp {
+    pub mac: String,
+    pub ip: String,
+    pub agent: String,
+    pub vlan: i32,
+    pub in_if: i32,
+    pub first_seen: chrono::NaiveDateTime,
+    pub last_seen: chrono::NaiveDateTime,
+}
+
+#[derive(Queryable, Debug)]
+pub struct ApiToken {
+    pub token_id: i32,
+    pub name: String,
+    /// Only compared in SQL by `auth::authorize`, loaded because the struct
+    /// has to match every column of the table.
+    #[allow(dead_code)]
+    pub token_hash: String,
+    pub role: String,
+    pub agents: String,
+    pub prefixes: String,
+    pub create_date: chrono::NaiveDateTime,
+    pub revoked: bool,
+    pub tenant_id: i32,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "api_token"]
+pub struct NewApiToken {
+    pub name: String,
+    pub token_hash: String,
+    pub role: String,
+    pub agents: String,
+    pub prefixes: String,
+    pub tenant_id: i32,
+}
+
+#[derive(Queryable, Debug)]
+pub struct Tenant {
+    pub tenant_id: i32,
+    pub name: String,
+    pub agents: String,
+    pub prefixes: String,
+    pub create_date: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "tenant"]
+pub struct NewTenant {
+    pub name: String,
+    pub agents: String,
+    pub prefixes: String,
 }
@@ -0,0 +1,461 @@
+//! Node labels from a static hosts file and cached reverse DNS
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::net::{IpAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use std;
+use sflow::*;
+use oui::{self, Oui};
+
+const TYPE_PTR: u16 = 12;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u8 = 3;
+/// Upper bound of cached addresses, the cache is cleared when reached.
+const MAX_CACHE: usize = 100000;
+
+#[derive(Debug, Clone)]
+pub struct NamesConfig {
+    pub hosts_file: String,
+    /// `host:port` of the DNS server, reverse DNS is off when empty.
+    pub dns_server: String,
+    pub timeout: Duration,
+    pub ttl: Duration,
+    pub negative_ttl: Duration,
+    /// How often the hosts file is checked for changes.
+    pub hosts_reload: Duration,
+    /// Addresses waiting for reverse DNS; more are dropped until it drains.
+    pub queue: usize,
+}
+
+impl NamesConfig {
+    pub fn from_env() -> NamesConfig {
+        NamesConfig {
+            hosts_file: get_env_or("HOSTS_FILE", "hosts.txt".to_string()),
+            dns_server: get_env_or("DNS_SERVER", String::new()),
+            timeout: Duration::from_millis(get_env_or("DNS_TIMEOUT_MS", 500)),
+            ttl: Duration::from_secs(get_env_or("DNS_CACHE_SECS", 3600)),
+            negative_ttl: Duration::from_secs(get_env_or("DNS_NEGATIVE_SECS", 300)),
+            hosts_reload: Duration::from_secs(get_env_or("HOSTS_RELOAD_SECS", 60)),
+            queue: get_env_or("DNS_QUEUE", 1024),
+        }
+    }
+}
+
+/// `address name...` lines as in `/etc/hosts`, reloaded when the file changes.
+struct HostsFile {
+    path: String,
+    mtime: Option<SystemTime>,
+    names: HashMap<String, String>,
+    reload: Duration,
+    checked: Instant,
+}
+
+impl HostsFile {
+    fn need_reload(&self) -> bool {
+        self.checked.elapsed() >= self.reload
+    }
+
+    fn reload(&mut self) {
+        self.checked = Instant::now();
+        let mtime = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if mtime == self.mtime {
+            return;
+        }
+        self.mtime = mtime;
+        self.names.clear();
+        if let Ok(f) = File::open(&self.path) {
+            for line in BufReader::new(f).lines() {
+                let line = match line {
+                    Ok(x) => x,
+                    Err(_) => break,
+                };
+                let mut it = line.split('#').next().unwrap_or("").split_whitespace();
+                if let (Some(addr), Some(name)) = (it.next(), it.next()) {
+                    self.names.insert(normalize(addr), name.to_string());
+                }
+            }
+        }
+    }
+}
+
+/// Canonical text of an address, so `::1` and `0:0:0:0:0:0:0:1` match.
+fn normalize(addr: &str) -> String {
+    addr.parse::<IpAddr>().map(|x| x.to_string()).unwrap_or(addr.to_string())
+}
+
+struct Cached {
+    name: Option<String>,
+    until: Instant,
+}
+
+/// Labels and MAC vendors for graph nodes. Lookups never wait for DNS: unknown addresses
+/// are queued for a background thread and labelled by later responses, or
+/// dropped while the queue is full.
+pub struct Names {
+    config: NamesConfig,
+    hosts: Mutex<HostsFile>,
+    oui: Oui,
+    cache: Mutex<HashMap<String, Cached>>,
+    pending: Mutex<HashSet<String>>,
+    queue: Mutex<Option<SyncSender<String>>>,
+}
+
+impl Names {
+    pub fn new(config: NamesConfig) -> Arc<Names> {
+        let mut hosts = HostsFile {
+            path: config.hosts_file.clone(),
+            mtime: None,
+            names: HashMap::new(),
+            reload: config.hosts_reload,
+            checked: Instant::now(),
+        };
+        hosts.reload();
+        let dns = !config.dns_server.is_empty();
+        let names = Arc::new(Names {
+            config: config,
+            hosts: Mutex::new(hosts),
+            oui: Oui::load(),
+            cache: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashSet::new()),
+            queue: Mutex::new(None),
+        });
+        if dns {
+            let (tx, rx) = mpsc::sync_channel(names.config.queue.max(1));
+            *names.queue.lock().unwrap() = Some(tx);
+            let n = names.clone();
+            thread::spawn(move || n.resolve_loop(rx));
+        }
+        names
+    }
+
+    /// Label of `addr`, from the hosts file first and then reverse DNS.
+    pub fn label(&self, addr: &str) -> Option<String> {
+        let ip = match addr.parse::<IpAddr>() {
+            Ok(x) => x.to_string(),
+            Err(_) => return None,
+        };
+        if let Ok(mut hosts) = self.hosts.lock() {
+            if hosts.need_reload() {
+                hosts.reload();
+            }
+            if let Some(x) = hosts.names.get(&ip) {
+                return Some(x.clone());
+            }
+        }
+        if let Ok(cache) = self.cache.lock() {
+            if let Some(x) = cache.get(&ip) {
+                if x.until > Instant::now() {
+                    return x.name.clone();
+                }
+            }
+        }
+        self.enqueue(ip);
+        None
+    }
+
+    pub fn vendor(&self, mac: &str) -> Option<String> {
+        self.oui.vendor(mac)
+    }
+
+    pub fn annotate(&self, nodes: &mut Vec<FlowName>) {
+        for n in nodes.iter_mut() {
+            if oui::is_mac(&n.name) {
+                n.vendor = self.vendor(&n.name);
+            } else {
+                n.label = self.label(&n.name);
+            }
+        }
+    }
+
+    fn enqueue(&self, ip: String) {
+        if let Ok(queue) = self.queue.lock() {
+            if let Some(ref tx) = *queue {
+                if self.pending.lock().map(|mut p| p.insert(ip.clone())).unwrap_or(false) {
+                    if let Err(TrySendError::Full(ip)) = tx.try_send(ip) {
+                        debug!("reverse dns queue full, dropping {}", ip);
+                        if let Ok(mut p) = self.pending.lock() {
+                            p.remove(&ip);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn resolve_loop(&self, rx: Receiver<String>) {
+        for ip in rx.iter() {
+            let (name, ttl) = match reverse_lookup(&self.config.dns_server, &ip, self.config.timeout) {
+                Ok(Some(x)) => (Some(x), self.config.ttl),
+                Ok(None) => (None, self.config.negative_ttl),
+                Err(x) => {
+                    info!("reverse dns {}: {}", ip, x);
+                    (None, self.config.negative_ttl)
+                },
+            };
+            if let Ok(mut cache) = self.cache.lock() {
+                if cache.len() >= MAX_CACHE {
+                    cache.clear();
+                }
+                cache.insert(ip.clone(), Cached { name: name, until: Instant::now() + ttl });
+            }
+            if let Ok(mut p) = self.pending.lock() {
+                p.remove(&ip);
+            }
+        }
+    }
+}
+
+/// `in-addr.arpa` / `ip6.arpa` name of an address.
+pub fn ptr_name(ip: &IpAddr) -> String {
+    match *ip {
+        IpAddr::V4(x) => {
+            let o = x.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", o[3], o[2], o[1], o[0])
+        },
+        IpAddr::V6(x) => {
+            let mut s = String::new();
+            for b in x.octets().iter().rev() {
+                s.push_str(&format!("{:x}.{:x}.", b & 0x0f, b >> 4));
+            }
+            s + "ip6.arpa"
+        },
+    }
+}
+
+fn query_packet(id: u16, name: &str) -> Vec<u8> {
+    let mut p = vec![(id >> 8) as u8, id as u8, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    for part in name.split('.') {
+        p.push(part.len() as u8);
+        p.extend_from_slice(part.as_bytes());
+    }
+    p.push(0);
+    p.extend_from_slice(&[0, TYPE_PTR as u8, 0, CLASS_IN as u8]);
+    p
+}
+
+fn be16(p: &[u8], i: usize) -> Result<u16, String> {
+    if i + 1 < p.len() {
+        Ok(((p[i] as u16) << 8) | p[i + 1] as u16)
+    } else {
+        Err("truncated dns response".to_string())
+    }
+}
+
+/// Reads a possibly compressed name at `i`, returns it and the offset after it.
+fn read_name(p: &[u8], mut i: usize) -> Result<(String, usize), String> {
+    let mut labels: Vec<String> = vec![];
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let len = *p.get(i).ok_or("truncated dns name")? as usize;
+        if len & 0xc0 == 0xc0 {
+            jumps += 1;
+            if jumps > 16 {
+                return Err("dns name loop".to_string());
+            }
+            if end.is_none() {
+                end = Some(i + 2);
+            }
+            i = (be16(p, i)? & 0x3fff) as usize;
+        } else if len == 0 {
+            return Ok((labels.join("."), end.unwrap_or(i + 1)));
+        } else {
+            let label = p.get(i + 1..i + 1 + len).ok_or("truncated dns label")?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            i += 1 + len;
+        }
+    }
+}
+
+/// First PTR name in a response to query `id`, `None` for NXDOMAIN or no answer.
+fn parse_response(p: &[u8], id: u16) -> Result<Option<String>, String> {
+    if p.len() < 12 {
+        return Err("truncated dns response".to_string());
+    }
+    if be16(p, 0)? != id {
+        return Err("dns response id mismatch".to_string());
+    }
+    let rcode = p[3] & 0x0f;
+    if rcode == RCODE_NXDOMAIN {
+        return Ok(None);
+    }
+    if rcode != 0 {
+        return Err(format!("dns rcode {}", rcode));
+    }
+    let qd = be16(p, 4)?;
+    let an = be16(p, 6)?;
+    let mut i = 12;
+    for _ in 0..qd {
+        i = read_name(p, i)?.1 + 4;
+    }
+    for _ in 0..an {
+        i = read_name(p, i)?.1;
+        let typ = be16(p, i)?;
+        let len = be16(p, i + 8)? as usize;
+        i += 10;
+        if typ == TYPE_PTR {
+            let (name, _) = read_name(p, i)?;
+            return Ok(Some(name.trim_end_matches('.').to_string()));
+        }
+        i += len;
+    }
+    Ok(None)
+}
+
+/// Asks `server` for the PTR record of `addr`, waiting at most `timeout`.
+pub fn reverse_lookup(server: &str, addr: &str, timeout: Duration) -> Result<Option<String>, Box<std::error::Error>> {
+    let ip: IpAddr = addr.parse()?;
+    let sock = UdpSocket::bind(if server.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" })?;
+    sock.set_read_timeout(Some(timeout))?;
+    sock.connect(server)?;
+    let id = (SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.subsec_nanos() & 0xffff) as u16;
+    sock.send(&query_packet(id, &ptr_name(&ip)))?;
+    let mut buf = [0u8; 1500];
+    let deadline = Instant::now() + timeout;
+    loop {
+        let n = sock.recv(&mut buf)?;
+        // skip late answers to earlier queries
+        if be16(&buf[..n], 0)? != id && Instant::now() < deadline {
+            continue;
+        }
+        return Ok(parse_response(&buf[..n], id)?);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Write;
+
+    /// Answers PTR queries on 127.0.0.1 from `names`, NXDOMAIN for the rest,
+    /// after `delay`; returns its address.
+    fn dns_stand_in(names: Vec<(&'static str, &'static str)>, delay: Duration) -> String {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            loop {
+                let (n, peer) = match sock.recv_from(&mut buf) {
+                    Ok(x) => x,
+                    Err(_) => return,
+                };
+                thread::sleep(delay);
+                let q = &buf[..n];
+                let (qname, end) = read_name(q, 12).unwrap();
+                let mut p = vec![q[0], q[1], 0x81, 0x80, 0, 1, 0, 0, 0, 0, 0, 0];
+                p.extend_from_slice(&q[12..end + 4]);
+                match names.iter().find(|x| ptr_name(&x.0.parse().unwrap()) == qname) {
+                    Some(&(_, name)) => {
+                        p[7] = 1;
+                        let mut rdata = vec![];
+                        for part in name.split('.') {
+                            rdata.push(part.len() as u8);
+                            rdata.extend_from_slice(part.as_bytes());
+                        }
+                        rdata.push(0);
+                        p.extend_from_slice(&[0xc0, 0x0c, 0, TYPE_PTR as u8, 0, CLASS_IN as u8, 0, 0, 0x0e, 0x10]);
+                        p.extend_from_slice(&[0, rdata.len() as u8]);
+                        p.extend_from_slice(&rdata);
+                    },
+                    None => p[3] |= RCODE_NXDOMAIN,
+                }
+                let _ = sock.send_to(&p, peer);
+            }
+        });
+        addr
+    }
+
+    fn config(dns_server: &str, hosts_file: &str) -> NamesConfig {
+        NamesConfig {
+            hosts_file: hosts_file.to_string(),
+            dns_server: dns_server.to_string(),
+            timeout: Duration::from_millis(500),
+            ttl: Duration::from_secs(3600),
+            negative_ttl: Duration::from_secs(300),
+            hosts_reload: Duration::from_secs(3600),
+            queue: 16,
+        }
+    }
+
+    fn eventually<F: Fn() -> bool>(f: F) -> bool {
+        for _ in 0..100 {
+            if f() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        false
+    }
+
+    #[test]
+    fn ptr_names() {
+        assert_eq!(ptr_name(&"10.1.2.3".parse().unwrap()), "3.2.1.10.in-addr.arpa");
+        assert!(ptr_name(&"2001:db8::1".parse().unwrap())
+            .starts_with("1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2."));
+    }
+
+    #[test]
+    fn reverse_lookups() {
+        let server = dns_stand_in(vec![("10.0.0.1", "gw.example.net"), ("2001:db8::1", "v6.example.net")],
+            Duration::from_millis(0));
+        let t = Duration::from_millis(500);
+        assert_eq!(reverse_lookup(&server, "10.0.0.1", t).unwrap(), Some("gw.example.net".to_string()));
+        assert_eq!(reverse_lookup(&server, "2001:db8::1", t).unwrap(), Some("v6.example.net".to_string()));
+        assert_eq!(reverse_lookup(&server, "10.0.0.2", t).unwrap(), None);
+        assert!(reverse_lookup(&server, "not an address", t).is_err());
+    }
+
+    #[test]
+    fn slow_server_times_out() {
+        let server = dns_stand_in(vec![("10.0.0.1", "gw.example.net")], Duration::from_millis(300));
+        assert!(reverse_lookup(&server, "10.0.0.1", Duration::from_millis(50)).is_err());
+    }
+
+    #[test]
+    fn labels_come_from_later_answers() {
+        let server = dns_stand_in(vec![("10.0.0.1", "gw.example.net")], Duration::from_millis(0));
+        let names = Names::new(config(&server, "/nonexistent/hosts.txt"));
+        assert_eq!(names.label("10.0.0.1"), None);
+        assert!(eventually(|| names.label("10.0.0.1") == Some("gw.example.net".to_string())));
+        assert!(eventually(|| names.pending.lock().unwrap().is_empty()));
+        assert_eq!(names.label("10.0.0.2"), None);
+        assert!(eventually(|| names.pending.lock().unwrap().is_empty()));
+        assert_eq!(names.label("10.0.0.2"), None);
+        assert_eq!(names.label("not an address"), None);
+    }
+
+    #[test]
+    fn full_queue_drops_lookups() {
+        let server = dns_stand_in(vec![], Duration::from_millis(200));
+        let mut cfg = config(&server, "/nonexistent/hosts.txt");
+        cfg.queue = 2;
+        let names = Names::new(cfg);
+        for i in 0..50 {
+            names.label(&format!("10.0.1.{}", i));
+        }
+        // one in flight and two queued at most
+        assert!(names.pending.lock().unwrap().len() <= 3);
+        assert!(eventually(|| names.pending.lock().unwrap().is_empty()));
+    }
+
+    #[test]
+    fn hosts_file_is_checked_on_an_interval() {
+        let path = env::temp_dir().join(format!("sflow-hosts-{}.txt", std::process::id()));
+        let path_s = path.to_str().unwrap().to_string();
+        File::create(&path).unwrap().write_all(b"10.0.0.1 first # comment\n").unwrap();
+        let names = Names::new(config("", &path_s));
+        assert_eq!(names.label("10.0.0.1"), Some("first".to_string()));
+        thread::sleep(Duration::from_millis(1100));
+        File::create(&path).unwrap().write_all(b"10.0.0.1 second\n").unwrap();
+        // not before the interval is over
+        assert_eq!(names.label("10.0.0.1"), Some("first".to_string()));
+        names.hosts.lock().unwrap().reload = Duration::from_secs(0);
+        assert_eq!(names.label("10.0.0.1"), Some("second".to_string()));
+        let _ = fs::remove_file(&path);
+    }
+}
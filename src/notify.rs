@@ -0,0 +1,118 @@
+//! Delivery of alert notifications by HTTP webhook and RFC 5424 syslog
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+use std;
+use chrono::Local;
+use openssl::ssl::{SslConnector, SslMethod};
+use serde_json;
+
+const TIMEOUT: u64 = 5;
+/// local0
+const SYSLOG_FACILITY: u8 = 16;
+pub const SEVERITY_WARNING: u8 = 4;
+pub const SEVERITY_NOTICE: u8 = 5;
+
+struct Url {
+    tls: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+fn parse_url(url: &str) -> Result<Url, Box<std::error::Error>> {
+    let (tls, rest) = if url.starts_with("https://") {
+        (true, &url[8..])
+    } else if url.starts_with("http://") {
+        (false, &url[7..])
+    } else {
+        return Err(From::from(format!("unsupported webhook url: {}", url)));
+    };
+    let (hostport, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match hostport.rfind(':') {
+        Some(i) if !hostport.ends_with(']') => (&hostport[..i], hostport[i + 1..].parse()?),
+        _ => (hostport, if tls { 443 } else { 80 }),
+    };
+    Ok(Url {
+        tls: tls,
+        host: host.trim_matches(|c| c == '[' || c == ']').to_string(),
+        port: port,
+        path: path.to_string(),
+    })
+}
+
+fn connect(host: &str, port: u16) -> Result<TcpStream, Box<std::error::Error>> {
+    let addr = (host, port).to_socket_addrs()?.next()
+        .ok_or_else(|| format!("cannot resolve {}", host))?;
+    let stream = TcpStream::connect_timeout(&addr, Duration::from_secs(TIMEOUT))?;
+    stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(TIMEOUT)))?;
+    Ok(stream)
+}
+
+fn http_exchange<S: Read + Write>(stream: &mut S, url: &Url, body: &str) -> Result<(), Box<std::error::Error>> {
+    let req = format!("POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: sflow_read\r\n\
+        Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        url.path, url.host, body.len(), body);
+    stream.write_all(req.as_bytes())?;
+    stream.flush()?;
+    let mut resp = String::new();
+    let _ = stream.read_to_string(&mut resp);
+    let status: u16 = resp.split_whitespace().nth(1).and_then(|x| x.parse().ok()).unwrap_or(0);
+    if status < 200 || status >= 300 {
+        return Err(From::from(format!("webhook {}:{}{} answered {}", url.host, url.port, url.path, status)));
+    }
+    Ok(())
+}
+
+/// POSTs `payload` as JSON to an `http://` or `https://` URL.
+pub fn send_webhook(url: &str, payload: &serde_json::Value) -> Result<(), Box<std::error::Error>> {
+    let url = parse_url(url)?;
+    let body = payload.to_string();
+    let mut stream = connect(&url.host, url.port)?;
+    if url.tls {
+        let connector = SslConnector::builder(SslMethod::tls())?.build();
+        let mut stream = connector.connect(&url.host, stream)
+            .map_err(|e| format!("tls handshake with {}: {}", url.host, e))?;
+        http_exchange(&mut stream, &url, &body)
+    } else {
+        http_exchange(&mut stream, &url, &body)
+    }
+}
+
+fn sd_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace(']', "\\]")
+}
+
+/// Formats one RFC 5424 message with the fields in an `alert` SD-ELEMENT.
+pub fn syslog_message(severity: u8, msgid: &str, fields: &[(&str, String)], msg: &str) -> String {
+    let host = std::env::var("HOSTNAME").unwrap_or("-".to_string());
+    let sd: String = fields.iter()
+        .map(|&(k, ref v)| format!(" {}=\"{}\"", k, sd_escape(v)))
+        .collect();
+    format!("<{}>1 {} {} sflow_read {} {} [alert@32473{}] {}",
+        SYSLOG_FACILITY * 8 + severity,
+        Local::now().to_rfc3339(),
+        host, std::process::id(), msgid, sd, msg)
+}
+
+/// Sends to `udp://host:port`, `tcp://host:port` or plain `host:port` (UDP).
+pub fn send_syslog(target: &str, message: &str) -> Result<(), Box<std::error::Error>> {
+    if target.starts_with("tcp://") {
+        let addr = &target[6..];
+        let mut it = addr.rsplitn(2, ':');
+        let port: u16 = it.next().unwrap_or("514").parse()?;
+        let host = it.next().unwrap_or(addr);
+        let mut stream = connect(host, port)?;
+        // octet counting framing of RFC 6587
+        stream.write_all(format!("{} {}", message.len(), message).as_bytes())?;
+        return Ok(());
+    }
+    let addr = if target.starts_with("udp://") { &target[6..] } else { target };
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.send_to(message.as_bytes(), addr)?;
+    Ok(())
+}
@@ -0,0 +1,131 @@
+//! MAC vendor names from the IEEE OUI registry
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use sflow::*;
+
+/// The IEEE MA-L registry, refreshed with `data/update-oui.sh`.
+const IEEE_OUI: &'static str = include_str!("../data/oui.txt");
+
+/// 12 hex digits, how sflowtool prints a MAC address.
+pub fn is_mac(s: &str) -> bool {
+    s.len() == 12 && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// `AABBCC` prefix of a MAC in any of the usual notations.
+fn prefix_of(s: &str) -> Option<String> {
+    let hex: String = s.chars().filter(|c| c.is_ascii_hexdigit()).take(6).collect();
+    if hex.len() == 6 {
+        Some(hex.to_uppercase())
+    } else {
+        None
+    }
+}
+
+/// Parses `oui.txt` from the IEEE (`00-00-0C   (hex)\t\tCISCO SYSTEMS, INC.`)
+/// and the short `00000C Cisco` form; other lines, such as the indented
+/// addresses of the IEEE file, are skipped.
+fn parse_line(line: &str) -> Option<(String, String)> {
+    if line.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let (key, rest) = if let Some(i) = line.find("(hex)") {
+        (&line[..i], &line[i + 5..])
+    } else {
+        let i = line.find(char::is_whitespace)?;
+        (&line[..i], &line[i..])
+    };
+    let key = key.trim();
+    if (key.len() != 6 && key.len() != 8) || !key.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+        return None;
+    }
+    let prefix = prefix_of(key)?;
+    let name = rest.trim().trim_start_matches("(base 16)").trim();
+    if name.is_empty() {
+        return None;
+    }
+    Some((prefix, name.to_string()))
+}
+
+struct OuiFile {
+    path: String,
+    mtime: Option<SystemTime>,
+    vendors: HashMap<String, String>,
+    reload: Duration,
+    checked: Instant,
+}
+
+impl OuiFile {
+    fn need_reload(&self) -> bool {
+        self.checked.elapsed() >= self.reload
+    }
+
+    fn reload(&mut self) {
+        self.checked = Instant::now();
+        let mtime = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if mtime.is_none() || mtime == self.mtime {
+            return;
+        }
+        if let Ok(f) = File::open(&self.path) {
+            let mut vendors = builtin();
+            for line in BufReader::new(f).lines() {
+                match line {
+                    Ok(x) => if let Some((k, v)) = parse_line(&x) {
+                        vendors.insert(k, v);
+                    },
+                    Err(_) => break,
+                }
+            }
+            info!("oui: loaded {} vendors from {}", vendors.len(), self.path);
+            self.vendors = vendors;
+            self.mtime = mtime;
+        }
+    }
+}
+
+fn builtin() -> HashMap<String, String> {
+    IEEE_OUI.lines().filter_map(parse_line).collect()
+}
+
+/// Vendor table, the built-in registry extended by `OUI_FILE` (default
+/// `oui.txt`), which is checked for updates every `OUI_RELOAD_SECS`.
+pub struct Oui {
+    file: Mutex<OuiFile>,
+}
+
+impl Oui {
+    pub fn load() -> Oui {
+        let mut file = OuiFile {
+            path: get_env_or("OUI_FILE", "oui.txt".to_string()),
+            mtime: None,
+            vendors: builtin(),
+            reload: Duration::from_secs(get_env_or("OUI_RELOAD_SECS", 60)),
+            checked: Instant::now(),
+        };
+        file.reload();
+        Oui { file: Mutex::new(file) }
+    }
+
+    pub fn vendor(&self, mac: &str) -> Option<String> {
+        let prefix = prefix_of(mac)?;
+        let mut file = self.file.lock().ok()?;
+        if file.need_reload() {
+            file.reload();
+        }
+        if let Some(x) = file.vendors.get(&prefix) {
+            return Some(x.clone());
+        }
+        // second lowest bit of the first octet
+        let first = u8::from_str_radix(&prefix[..2], 16).ok()?;
+        if first & 0x02 != 0 {
+            return Some("(locally administered)".to_string());
+        }
+        None
+    }
+}
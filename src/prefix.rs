@@ -0,0 +1,69 @@
+//! IPv4/IPv6 prefixes for allowlists and address ownership
+use std::net::IpAddr;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prefix {
+    pub addr: IpAddr,
+    pub len: u8,
+}
+
+fn bits(ip: &IpAddr) -> (u128, u8) {
+    match *ip {
+        IpAddr::V4(x) => (u32::from(x) as u128, 32),
+        IpAddr::V6(x) => (u128::from(x), 128),
+    }
+}
+
+impl Prefix {
+    /// Parses `10.71.5.0/24`, `2001:db8::/32` or a bare address.
+    pub fn parse(s: &str) -> Result<Prefix, String> {
+        let s = s.trim();
+        let mut it = s.splitn(2, '/');
+        let addr: IpAddr = it.next().unwrap_or("").parse()
+            .map_err(|_| format!("bad prefix address: {}", s))?;
+        let max = bits(&addr).1;
+        let len = match it.next() {
+            Some(x) => x.parse::<u8>().map_err(|_| format!("bad prefix length: {}", s))?,
+            None => max,
+        };
+        if len > max {
+            return Err(format!("prefix length too long: {}", s));
+        }
+        Ok(Prefix {addr: addr, len: len})
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let (a, n) = bits(&self.addr);
+        let (b, m) = bits(ip);
+        if n != m {
+            return false;
+        }
+        if self.len == 0 {
+            return true;
+        }
+        let shift = (n - self.len) as u32;
+        (a >> shift) == (b >> shift)
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+/// Parses a comma or whitespace separated list of prefixes.
+pub fn parse_list(s: &str) -> Result<Vec<Prefix>, String> {
+    s.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|x| !x.is_empty())
+        .map(Prefix::parse)
+        .collect()
+}
+
+pub fn any_contains(list: &[Prefix], s: &str) -> bool {
+    match s.parse::<IpAddr>() {
+        Ok(ip) => list.iter().any(|p| p.contains(&ip)),
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,257 @@
+//! Registry of the sFlow agents seen by the ingest path
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
+use std;
+use chrono::{Local, NaiveDateTime};
+use diesel::pg::PgConnection;
+use diesel::result::QueryResult;
+use diesel::sql_query;
+use diesel::sql_types::Timestamp;
+use diesel::query_dsl::*;
+use schema;
+use sflow::Datagram;
+use models;
+
+/// How often the registry is written to the `agents` table.
+const FLUSH_INTERVAL: u64 = 10;
+
+#[derive(Debug, Default, Clone)]
+pub struct AgentInfo {
+    pub datagram_version: i32,
+    pub sys_uptime: i64,
+    pub sample_rates: BTreeSet<i32>,
+    pub sub_agents: BTreeSet<i32>,
+    pub datagrams: i64,
+    pub dps: f32,
+    pub dirty: bool,
+}
+
+/// One agent as served by `GET /agents`.
+#[derive(Debug, Serialize, Clone)]
+pub struct AgentStatus {
+    pub agent: String,
+    pub first_seen: String,
+    pub last_seen: String,
+    pub datagram_version: i32,
+    pub sys_uptime: i64,
+    pub sample_rates: Vec<i32>,
+    pub sub_agents: Vec<i32>,
+    pub datagrams: i64,
+    pub dps: f32,
+    pub stale: bool,
+}
+
+fn split_ids(s: &str) -> BTreeSet<i32> {
+    s.split(',').filter_map(|x| x.trim().parse().ok()).collect()
+}
+
+fn join_ids(s: &BTreeSet<i32>) -> String {
+    s.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(",")
+}
+
+impl AgentStatus {
+    pub fn new(x: &models::Agent, now: NaiveDateTime, stale_secs: i64) -> AgentStatus {
+        AgentStatus {
+            agent: x.agent.clone(),
+            first_seen: x.first_seen.format("%Y-%m-%d %H:%M:%S").to_string(),
+            last_seen: x.last_seen.format("%Y-%m-%d %H:%M:%S").to_string(),
+            datagram_version: x.datagram_version,
+            sys_uptime: x.sys_uptime,
+            sample_rates: split_ids(&x.sample_rates).into_iter().collect(),
+            sub_agents: split_ids(&x.sub_agents).into_iter().collect(),
+            datagrams: x.datagrams,
+            dps: x.dps,
+            stale: (now - x.last_seen).num_seconds() > stale_secs,
+        }
+    }
+}
+
+pub struct AgentRegistry {
+    agents: BTreeMap<String, AgentInfo>,
+    period: BTreeMap<String, i64>,
+    last_flush: Instant,
+}
+
+impl AgentRegistry {
+    /// Starts from the agents already stored so the sets of sample rates and
+    /// sub-agents survive a restart.
+    pub fn load(conn: &PgConnection) -> Result<AgentRegistry, Box<std::error::Error>> {
+        use self::schema::agents::dsl::*;
+        let mut reg = AgentRegistry {
+            agents: BTreeMap::new(),
+            period: BTreeMap::new(),
+            last_flush: Instant::now(),
+        };
+        for x in agents.load::<models::Agent>(conn)? {
+            reg.agents.insert(x.agent.clone(), AgentInfo {
+                datagram_version: x.datagram_version,
+                sys_uptime: x.sys_uptime,
+                sample_rates: split_ids(&x.sample_rates),
+                sub_agents: split_ids(&x.sub_agents),
+                datagrams: x.datagrams,
+                dps: x.dps,
+                dirty: false,
+            });
+        }
+        Ok(reg)
+    }
+
+    pub fn observe(&mut self, dg: &Datagram) {
+        let a = self.agents.entry(dg.agent.clone()).or_insert_with(Default::default);
+        a.datagram_version = dg.datagramVersion as i32;
+        a.sys_uptime = dg.sysUpTime;
+        a.sub_agents.insert(dg.agentSubId);
+        for s in dg.samplev5.iter() {
+            if s.meanSkipCount > 0 {
+                a.sample_rates.insert(s.meanSkipCount);
+            }
+        }
+        a.datagrams += 1;
+        a.dirty = true;
+        *self.period.entry(dg.agent.clone()).or_insert(0) += 1;
+    }
+
+    pub fn need_flush(&self) -> bool {
+        self.last_flush.elapsed() >= Duration::from_secs(FLUSH_INTERVAL)
+    }
+
+    /// Writes every agent seen since the last flush to `agents`.
+    pub fn flush(&mut self, conn: &PgConnection) -> Result<(), Box<std::error::Error>> {
+        let elapsed = self.last_flush.elapsed();
+        let secs = elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 / 1e9;
+        self.last_flush = Instant::now();
+        if let Some(q) = self.upsert(secs) {
+            sql_query(q).execute(conn)?;
+        }
+        Ok(())
+    }
+
+    /// Rates of the `secs` seconds since the last flush and the statement
+    /// storing the agents seen in them, `None` when there were none.
+    fn upsert(&mut self, secs: f32) -> Option<String> {
+        let mut multi_data = String::from("INSERT INTO agents (agent, datagram_version, sys_uptime, sample_rates, sub_agents, datagrams, dps) VALUES \n");
+        let mut count = 0;
+        for (k, v) in self.agents.iter_mut() {
+            if !v.dirty {
+                continue;
+            }
+            let n = self.period.get(k).cloned().unwrap_or(0);
+            v.dps = if secs > 0.0 { n as f32 / secs } else { 0.0 };
+            v.dirty = false;
+            multi_data.push_str(&format!("('{}',{},{},'{}','{}',{},{}),",
+                k, v.datagram_version, v.sys_uptime, join_ids(&v.sample_rates),
+                join_ids(&v.sub_agents), v.datagrams, v.dps));
+            count += 1;
+        }
+        self.period.clear();
+        if count == 0 {
+            return None;
+        }
+        multi_data.pop();
+        multi_data.push_str("\nON CONFLICT (agent) DO UPDATE SET \
+            last_seen = CURRENT_TIMESTAMP, \
+            datagram_version = EXCLUDED.datagram_version, \
+            sys_uptime = EXCLUDED.sys_uptime, \
+            sample_rates = EXCLUDED.sample_rates, \
+            sub_agents = EXCLUDED.sub_agents, \
+            datagrams = EXCLUDED.datagrams, \
+            dps = EXCLUDED.dps");
+        Some(multi_data)
+    }
+}
+
+/// Current local time in the same clock as `CURRENT_TIMESTAMP`.
+pub fn now() -> NaiveDateTime {
+    Local::now().naive_local()
+}
+
+#[derive(QueryableByName, Debug)]
+struct DbNow {
+    #[sql_type = "Timestamp"]
+    now: NaiveDateTime,
+}
+
+/// Current time of the database, the clock `last_seen` is written in, so
+/// staleness does not depend on the clock of the host serving the API.
+pub fn db_now(conn: &PgConnection) -> QueryResult<NaiveDateTime> {
+    Ok(sql_query("SELECT LOCALTIMESTAMP AS now").get_result::<DbNow>(conn)?.now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration as ChronoDuration, NaiveDate};
+    use sflow::SampleV5;
+
+    fn registry() -> AgentRegistry {
+        AgentRegistry { agents: BTreeMap::new(), period: BTreeMap::new(), last_flush: Instant::now() }
+    }
+
+    fn datagram(agent: &str, sub_agent: i32, rate: i32) -> Datagram {
+        Datagram {
+            agent: agent.to_string(),
+            agentSubId: sub_agent,
+            datagramVersion: 5,
+            sysUpTime: 1000,
+            samplev5: vec![SampleV5 { meanSkipCount: rate, ..Default::default() }],
+            ..Default::default()
+        }
+    }
+
+    fn agent(last_seen: NaiveDateTime) -> models::Agent {
+        models::Agent {
+            agent: "192.0.2.1".to_string(),
+            first_seen: last_seen,
+            last_seen: last_seen,
+            datagram_version: 5,
+            sys_uptime: 0,
+            sample_rates: "512,1024".to_string(),
+            sub_agents: "0".to_string(),
+            datagrams: 10,
+            dps: 1.5,
+        }
+    }
+
+    #[test]
+    fn stale_after_the_configured_silence() {
+        let seen = NaiveDate::from_ymd(2026, 10, 19).and_hms(10, 0, 0);
+        let x = AgentStatus::new(&agent(seen), seen + ChronoDuration::seconds(300), 300);
+        assert!(!x.stale);
+        assert_eq!(x.last_seen, "2026-10-19 10:00:00");
+        assert_eq!(x.sample_rates, vec![512, 1024]);
+        assert!(AgentStatus::new(&agent(seen), seen + ChronoDuration::seconds(301), 300).stale);
+        // a database clock behind the agent is not staleness
+        assert!(!AgentStatus::new(&agent(seen), seen - ChronoDuration::seconds(60), 300).stale);
+    }
+
+    #[test]
+    fn flush_rates_the_datagrams_of_the_period() {
+        let mut reg = registry();
+        for _ in 0..30 {
+            reg.observe(&datagram("a", 0, 512));
+        }
+        reg.observe(&datagram("b", 1, 1024));
+        reg.observe(&datagram("b", 2, 1024));
+        let q = reg.upsert(10.0).unwrap();
+        assert_eq!(reg.agents["a"].dps, 3.0);
+        assert_eq!(reg.agents["b"].dps, 0.2);
+        assert!(q.contains("('a',5,1000,'512','0',30,3)"), "{}", q);
+        assert!(q.contains("('b',5,1000,'1024','1,2',2,0.2)"), "{}", q);
+        // nothing new, nothing to write
+        assert_eq!(reg.upsert(10.0), None);
+        // the totals go on, the rate is of the new period only
+        reg.observe(&datagram("a", 0, 512));
+        let q = reg.upsert(0.5).unwrap();
+        assert!(q.contains("('a',5,1000,'512','0',31,2)"), "{}", q);
+        assert!(!q.contains("'b'"));
+        assert_eq!(reg.agents["b"].dps, 0.2);
+    }
+
+    #[test]
+    fn no_time_is_no_rate() {
+        let mut reg = registry();
+        reg.observe(&datagram("a", 0, 512));
+        reg.upsert(0.0).unwrap();
+        assert_eq!(reg.agents["a"].dps, 0.0);
+    }
+}
@@ -0,0 +1,347 @@
+//! Periodic evaluation of user-defined alert rules
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::{Duration, Instant};
+use std;
+use chrono::{Duration as ChronoDuration, NaiveDateTime};
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use db::DBPool;
+use notify;
+use prefix;
+use registry;
+use schema;
+use sflow::get_env_or;
+use models;
+
+pub const KINDS: [&'static str; 2] = ["bytes", "agent_silent"];
+
+/// Checks a rule posted to `/rules` before it is stored.
+pub fn validate(rule: &models::NewAlertRule) -> Result<(), String> {
+    if !KINDS.contains(&rule.kind.as_str()) {
+        return Err(format!("unknown rule kind {}", rule.kind));
+    }
+    prefix::parse_list(&rule.src_prefix)?;
+    prefix::parse_list(&rule.dst_prefix)?;
+    if rule.duration_secs < 0 || rule.hold_secs < 0 || rule.threshold_bps < 0 {
+        return Err("duration_secs, hold_secs and threshold_bps must not be negative".to_string());
+    }
+    if rule.webhook.is_empty() && rule.syslog.is_empty() {
+        return Err("a rule needs a webhook or a syslog target".to_string());
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct RuleState {
+    since: Option<Instant>,
+    firing: bool,
+    recovered: Option<Instant>,
+}
+
+/// Sends one notification through every target of the rule and returns
+/// the delivery errors, which are logged as well.
+pub fn deliver(rule: &models::AlertRule, key: &str, state: &str, value: f64) -> Vec<String> {
+    let mut errors = vec![];
+    let now = registry::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    if !rule.webhook.is_empty() {
+        let payload = json!({
+            "rule_id": rule.rule_id,
+            "name": rule.name,
+            "kind": rule.kind,
+            "key": key,
+            "state": state,
+            "value": value,
+            "threshold_bps": rule.threshold_bps,
+            "duration_secs": rule.duration_secs,
+            "time": now,
+        });
+        if let Err(x) = notify::send_webhook(&rule.webhook, &payload) {
+            info!("rule {} webhook: {}", rule.name, x);
+            errors.push(format!("webhook: {}", x));
+        }
+    }
+    if !rule.syslog.is_empty() {
+        let severity = if state == "resolved" { notify::SEVERITY_NOTICE } else { notify::SEVERITY_WARNING };
+        let msg = notify::syslog_message(severity, "ALERT", &[
+            ("rule", rule.name.clone()),
+            ("kind", rule.kind.clone()),
+            ("key", key.to_string()),
+            ("state", state.to_string()),
+            ("value", format!("{:.0}", value)),
+        ], &format!("{} {} {}", rule.name, key, state));
+        if let Err(x) = notify::send_syslog(&rule.syslog, &msg) {
+            info!("rule {} syslog: {}", rule.name, x);
+            errors.push(format!("syslog: {}", x));
+        }
+    }
+    errors
+}
+
+/// Current value of every key of a rule and whether it is breached, `now`
+/// being the database clock the flows and agents were stamped with.
+fn measure(rule: &models::AlertRule, flows: &Vec<models::Flow>, agents: &Vec<models::Agent>,
+        interval: i64, now: NaiveDateTime) -> Vec<(String, f64, bool)> {
+    let mut res = vec![];
+    match rule.kind.as_str() {
+        "bytes" => {
+            let src = prefix::parse_list(&rule.src_prefix).unwrap_or_default();
+            let dst = prefix::parse_list(&rule.dst_prefix).unwrap_or_default();
+            let bytes: i64 = flows.iter()
+                .filter(|x| rule.agent.is_empty() || rule.agent == x.agent)
+                .filter(|x| src.is_empty() || prefix::any_contains(&src, &x.src))
+                .filter(|x| dst.is_empty() || prefix::any_contains(&dst, &x.dst))
+                .map(|x| x.size as i64)
+                .sum();
+            let bps = bytes as f64 * 8.0 / interval.max(1) as f64;
+            res.push((String::new(), bps, bps > rule.threshold_bps as f64));
+        },
+        "agent_silent" => {
+            for a in agents.iter() {
+                if !rule.agent.is_empty() && rule.agent != a.agent {
+                    continue;
+                }
+                let silent = (now - a.last_seen).num_seconds();
+                res.push((a.agent.clone(), silent as f64, silent > rule.duration_secs as i64));
+            }
+        },
+        _ => {},
+    }
+    res
+}
+
+/// Evaluates the enabled rules every `RULE_INTERVAL` seconds. A breach has
+/// to last `duration_secs` before it fires, fires once until it recovers,
+/// and may not fire again within `hold_secs` of the recovery.
+pub fn run(pool: DBPool) {
+    let interval: i64 = get_env_or("RULE_INTERVAL", 30);
+    let mut states: BTreeMap<(i32, String), RuleState> = BTreeMap::new();
+    loop {
+        thread::sleep(Duration::from_secs(interval as u64));
+        if let Err(x) = evaluate(&pool, interval, &mut states) {
+            info!("rule evaluation: {}", x);
+        }
+    }
+}
+
+fn evaluate(pool: &DBPool, interval: i64, states: &mut BTreeMap<(i32, String), RuleState>)
+        -> Result<(), Box<std::error::Error>> {
+    let conn: &PgConnection = &*pool.get()?;
+    let now = registry::db_now(conn)?;
+    let rules = {
+        use self::schema::alert_rule::dsl::*;
+        alert_rule.filter(enabled.eq(true)).load::<models::AlertRule>(conn)?
+    };
+    let flows = {
+        use self::schema::flow::dsl::*;
+        flow.filter(input_date.ge(now - ChronoDuration::seconds(interval)))
+            .load::<models::Flow>(conn)?
+    };
+    let agents = {
+        use self::schema::agents::dsl::*;
+        agents.load::<models::Agent>(conn)?
+    };
+    states.retain(|k, _| rules.iter().any(|r| r.rule_id == k.0));
+    for rule in rules.iter() {
+        for (key, value, breached) in measure(rule, &flows, &agents, interval, now) {
+            let st = states.entry((rule.rule_id, key.clone())).or_insert_with(Default::default);
+            if breached {
+                let since = *st.since.get_or_insert_with(Instant::now);
+                let held = st.recovered
+                    .map(|x| x.elapsed() < Duration::from_secs(rule.hold_secs as u64))
+                    .unwrap_or(false);
+                // agent_silent already measures the silence against duration_secs
+                let lasted = rule.kind == "agent_silent"
+                    || since.elapsed() >= Duration::from_secs(rule.duration_secs as u64);
+                if !st.firing && lasted && !held {
+                    st.firing = true;
+                    deliver(rule, &key, "firing", value);
+                }
+            } else {
+                st.since = None;
+                if st.firing {
+                    st.firing = false;
+                    st.recovered = Some(Instant::now());
+                    deliver(rule, &key, "resolved", value);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, UdpSocket};
+    use std::sync::mpsc;
+    use chrono::NaiveDate;
+    use serde_json;
+
+    fn rule(kind: &str, agent: &str) -> models::AlertRule {
+        models::AlertRule {
+            rule_id: 1,
+            name: "test".to_string(),
+            kind: kind.to_string(),
+            agent: agent.to_string(),
+            src_prefix: String::new(),
+            dst_prefix: "10.0.0.0/8".to_string(),
+            threshold_bps: 1000,
+            duration_secs: 60,
+            hold_secs: 0,
+            webhook: String::new(),
+            syslog: String::new(),
+            enabled: true,
+        }
+    }
+
+    fn flow(agent: &str, dst: &str, size: i32) -> models::Flow {
+        models::Flow {
+            flow_id: 0,
+            input_date: registry::now(),
+            agent: agent.to_string(),
+            utc: 0,
+            src: "192.0.2.1".to_string(),
+            dst: dst.to_string(),
+            srcport: 1234,
+            dstport: 80,
+            ntype: "ipv4".to_string(),
+            size: size,
+            packets: 1,
+            app: String::new(),
+            src_country: String::new(),
+            src_city: String::new(),
+            src_asn: 0,
+            src_org: String::new(),
+            dst_country: String::new(),
+            dst_city: String::new(),
+            dst_asn: 0,
+            dst_org: String::new(),
+            vlan: 0,
+            in_if: 0,
+            out_if: 0,
+            tenant_id: 0,
+        }
+    }
+
+    #[test]
+    fn bytes_count_the_agent_of_the_rule() {
+        let flows = vec![flow("a", "10.1.1.1", 1000), flow("b", "10.1.1.1", 4000), flow("a", "192.0.2.9", 8000)];
+        let all = measure(&rule("bytes", ""), &flows, &vec![], 10, registry::now());
+        assert_eq!(all, vec![(String::new(), 4000.0, true)]);
+        let a = measure(&rule("bytes", "a"), &flows, &vec![], 10, registry::now());
+        assert_eq!(a, vec![(String::new(), 800.0, false)]);
+    }
+
+    #[test]
+    fn silence_is_measured_against_the_given_clock() {
+        let seen = NaiveDate::from_ymd(2026, 10, 19).and_hms(10, 0, 0);
+        let agents = vec![models::Agent {
+            agent: "a".to_string(),
+            first_seen: seen,
+            last_seen: seen,
+            datagram_version: 5,
+            sys_uptime: 0,
+            sample_rates: String::new(),
+            sub_agents: String::new(),
+            datagrams: 1,
+            dps: 0.0,
+        }];
+        let now = seen + ChronoDuration::seconds(90);
+        assert_eq!(measure(&rule("agent_silent", ""), &vec![], &agents, 10, now), vec![("a".to_string(), 90.0, true)]);
+        let now = seen + ChronoDuration::seconds(30);
+        assert_eq!(measure(&rule("agent_silent", ""), &vec![], &agents, 10, now), vec![("a".to_string(), 30.0, false)]);
+    }
+
+    /// Answers one HTTP request on 127.0.0.1 with `status` and hands over
+    /// its body.
+    fn http_stand_in(status: u16) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut r = BufReader::new(stream);
+            let mut len = 0;
+            loop {
+                let mut line = String::new();
+                r.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if line.to_lowercase().starts_with("content-length:") {
+                    len = line[15..].trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0u8; len];
+            r.read_exact(&mut body).unwrap();
+            let _ = r.get_mut().write_all(format!("HTTP/1.1 {} X\r\nContent-Length: 0\r\n\r\n", status).as_bytes());
+            tx.send(String::from_utf8(body).unwrap()).unwrap();
+        });
+        (url, rx)
+    }
+
+    #[test]
+    fn webhook_delivery() {
+        let (url, rx) = http_stand_in(200);
+        let mut r = rule("bytes", "");
+        r.webhook = url;
+        assert!(deliver(&r, "key", "firing", 42.0).is_empty());
+        let body: serde_json::Value = serde_json::from_str(&rx.recv_timeout(Duration::from_secs(5)).unwrap()).unwrap();
+        assert_eq!(body["rule_id"], 1);
+        assert_eq!(body["key"], "key");
+        assert_eq!(body["state"], "firing");
+        assert_eq!(body["value"], 42.0);
+    }
+
+    #[test]
+    fn webhook_errors_are_returned() {
+        let (url, _rx) = http_stand_in(500);
+        let mut r = rule("bytes", "");
+        r.webhook = url;
+        let errors = deliver(&r, "key", "firing", 42.0);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("webhook:"));
+
+        // nobody listens on a port just released
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        r.webhook = format!("http://127.0.0.1:{}/hook", port);
+        assert_eq!(deliver(&r, "key", "firing", 42.0).len(), 1);
+    }
+
+    #[test]
+    fn udp_syslog_delivery() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut r = rule("bytes", "");
+        r.syslog = format!("udp://{}", sock.local_addr().unwrap());
+        assert!(deliver(&r, "10.0.0.1", "resolved", 0.0).is_empty());
+        let mut buf = [0u8; 2048];
+        let n = sock.recv(&mut buf).unwrap();
+        let msg = String::from_utf8_lossy(&buf[..n]).into_owned();
+        // local0.notice
+        assert!(msg.starts_with("<133>1 "), "{}", msg);
+        assert!(msg.contains("state=\"resolved\""), "{}", msg);
+        assert!(msg.contains("key=\"10.0.0.1\""), "{}", msg);
+    }
+
+    #[test]
+    fn tcp_syslog_delivery() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut r = rule("bytes", "");
+        r.syslog = format!("tcp://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut msg = String::new();
+            stream.read_to_string(&mut msg).unwrap();
+            msg
+        });
+        assert!(deliver(&r, "key", "firing", 1.0).is_empty());
+        let msg = server.join().unwrap();
+        let (len, rest) = msg.split_at(msg.find(' ').unwrap());
+        // octet counting, local0.warning
+        assert_eq!(len.parse::<usize>().unwrap(), rest.len() - 1);
+        assert!(rest[1..].starts_with("<132>1 "), "{}", msg);
+    }
+}
@@ -0,0 +1,225 @@
+//! Port-scan and host-sweep detection from per-source fan-out
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::time::{Duration, Instant};
+use std;
+use diesel::pg::PgConnection;
+use diesel::sql_query;
+use diesel::query_dsl::*;
+use prefix::{self, Prefix};
+use sflow::*;
+
+/// Cap of distinct keys remembered per source and window.
+const MAX_FANOUT: usize = 4096;
+/// Ports or hosts listed in the `detail` column of an event.
+const DETAIL_COUNT: usize = 20;
+
+#[derive(Debug, Clone)]
+pub struct ScanConfig {
+    pub window: u64,
+    pub ports: usize,
+    pub hosts: usize,
+    pub allowlist: Vec<Prefix>,
+}
+
+impl ScanConfig {
+    pub fn from_env() -> Result<ScanConfig, Box<std::error::Error>> {
+        Ok(ScanConfig {
+            window: get_env_or("SCAN_WINDOW", 60),
+            ports: get_env_or("SCAN_PORTS", 100),
+            hosts: get_env_or("SCAN_HOSTS", 50),
+            allowlist: prefix::parse_list(&get_env_or("SCAN_ALLOWLIST", String::new()))?,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct Fanout {
+    agent: String,
+    ports: HashMap<String, HashSet<i32>>,
+    hosts: HashMap<i32, HashSet<String>>,
+}
+
+/// Counts, per source and tumbling window, the distinct ports probed on
+/// each destination (vertical scan) and the distinct destinations probed
+/// on each port (horizontal sweep).
+pub struct ScanDetector {
+    conf: ScanConfig,
+    sources: BTreeMap<String, Fanout>,
+    since: Instant,
+}
+
+fn detail<T: ToString + Ord>(set: &BTreeSet<T>) -> String {
+    set.iter().take(DETAIL_COUNT).map(|x| x.to_string()).collect::<Vec<String>>().join(",")
+}
+
+impl ScanDetector {
+    pub fn new(conf: ScanConfig) -> ScanDetector {
+        ScanDetector {
+            conf: conf,
+            sources: BTreeMap::new(),
+            since: Instant::now(),
+        }
+    }
+
+    pub fn observe(&mut self, dg: &Datagram) {
+        for s in dg.samplev5.iter() {
+            let f = match sample_flow(s) {
+                Some(f) => f,
+                None => continue,
+            };
+            if f.ntype == "mac" || f.dstport < 0 {
+                continue;
+            }
+            if prefix::any_contains(&self.conf.allowlist, &f.source) {
+                continue;
+            }
+            let x = self.sources.entry(f.source.clone()).or_insert_with(Default::default);
+            x.agent = dg.agent.clone();
+            if x.ports.len() < MAX_FANOUT {
+                let ports = x.ports.entry(f.target.clone()).or_insert_with(HashSet::new);
+                if ports.len() < MAX_FANOUT {
+                    ports.insert(f.dstport);
+                }
+            }
+            if x.hosts.len() < MAX_FANOUT {
+                let hosts = x.hosts.entry(f.dstport).or_insert_with(HashSet::new);
+                if hosts.len() < MAX_FANOUT {
+                    hosts.insert(f.target.clone());
+                }
+            }
+        }
+    }
+
+    pub fn need_tick(&self) -> bool {
+        self.since.elapsed() >= Duration::from_secs(self.conf.window)
+    }
+
+    /// Ends the window and records a `scan_event` for every source over
+    /// one of the thresholds.
+    pub fn tick(&mut self, conn: &PgConnection) -> Result<(), Box<std::error::Error>> {
+        self.since = Instant::now();
+        if let Some(q) = self.close_window() {
+            sql_query(q).execute(conn)?;
+        }
+        Ok(())
+    }
+
+    /// The statement recording the events of the window that ends, `None`
+    /// without any.
+    fn close_window(&mut self) -> Option<String> {
+        let mut multi_data = String::from("INSERT INTO scan_event (agent, src, kind, target, distinct_count, window_secs, detail) VALUES \n");
+        let mut count = 0;
+        for (src, x) in self.sources.iter() {
+            for (dst, ports) in x.ports.iter() {
+                if ports.len() >= self.conf.ports {
+                    let set: BTreeSet<i32> = ports.iter().cloned().collect();
+                    info!("vertical scan from {} on {}: {} ports", src, dst, ports.len());
+                    multi_data.push_str(&format!("('{}','{}','vertical','{}',{},{},'{}'),",
+                        x.agent, src, dst, ports.len(), self.conf.window, detail(&set)));
+                    count += 1;
+                }
+            }
+            for (port, hosts) in x.hosts.iter() {
+                if hosts.len() >= self.conf.hosts {
+                    let set: BTreeSet<String> = hosts.iter().cloned().collect();
+                    info!("horizontal sweep from {} on port {}: {} hosts", src, port, hosts.len());
+                    multi_data.push_str(&format!("('{}','{}','horizontal','{}',{},{},'{}'),",
+                        x.agent, src, port, hosts.len(), self.conf.window, detail(&set)));
+                    count += 1;
+                }
+            }
+        }
+        self.sources.clear();
+        if count == 0 {
+            return None;
+        }
+        multi_data.pop();
+        Some(multi_data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector(allowlist: &str) -> ScanDetector {
+        ScanDetector::new(ScanConfig {
+            window: 60,
+            ports: 5,
+            hosts: 3,
+            allowlist: prefix::parse_list(allowlist).unwrap(),
+        })
+    }
+
+    /// TCP probes of `src` to every `dst:port`.
+    fn probes(src: &str, dsts: &[&str], ports: &[i32]) -> Datagram {
+        let mut dg = Datagram { agent: "192.0.2.254".to_string(), ..Default::default() };
+        for dst in dsts.iter() {
+            for port in ports.iter() {
+                dg.samplev5.push(SampleV5 {
+                    srcIP: Some(src.to_string()),
+                    dstIP: Some(dst.to_string()),
+                    TCPSrcPort: Some(40000),
+                    TCPDstPort: Some(*port),
+                    meanSkipCount: 1,
+                    ..Default::default()
+                });
+            }
+        }
+        dg
+    }
+
+    #[test]
+    fn vertical_scan_at_the_port_threshold() {
+        let mut d = detector("");
+        d.observe(&probes("203.0.113.7", &["10.0.0.1"], &[22, 23, 80, 443]));
+        d.observe(&probes("203.0.113.8", &["10.0.0.1"], &[22, 23, 80, 443, 8080]));
+        // a repeated port is no new port
+        d.observe(&probes("203.0.113.7", &["10.0.0.1"], &[22]));
+        let q = d.close_window().unwrap();
+        assert!(q.contains("('192.0.2.254','203.0.113.8','vertical','10.0.0.1',5,60,'22,23,80,443,8080')"), "{}", q);
+        assert!(!q.contains("203.0.113.7"), "{}", q);
+    }
+
+    #[test]
+    fn horizontal_sweep_at_the_host_threshold() {
+        let mut d = detector("");
+        d.observe(&probes("203.0.113.7", &["10.0.0.1", "10.0.0.2", "10.0.0.3"], &[445]));
+        d.observe(&probes("203.0.113.7", &["10.0.0.1", "10.0.0.2"], &[3389]));
+        let q = d.close_window().unwrap();
+        assert!(q.contains("('192.0.2.254','203.0.113.7','horizontal','445',3,60,'10.0.0.1,10.0.0.2,10.0.0.3')"), "{}", q);
+        assert!(!q.contains("'3389'"), "{}", q);
+        assert!(!q.contains("vertical"), "{}", q);
+    }
+
+    #[test]
+    fn allowlisted_sources_are_not_counted() {
+        let mut d = detector("203.0.113.0/28, 2001:db8::/32");
+        d.observe(&probes("203.0.113.7", &["10.0.0.1"], &[1, 2, 3, 4, 5, 6]));
+        assert_eq!(d.close_window(), None);
+        d.observe(&probes("203.0.113.17", &["10.0.0.1"], &[1, 2, 3, 4, 5, 6]));
+        assert!(d.close_window().unwrap().contains("'203.0.113.17','vertical'"));
+    }
+
+    #[test]
+    fn windows_start_empty() {
+        let mut d = detector("");
+        d.observe(&probes("203.0.113.7", &["10.0.0.1"], &[1, 2, 3]));
+        assert_eq!(d.close_window(), None);
+        // the three ports of the last window do not count any more
+        d.observe(&probes("203.0.113.7", &["10.0.0.1"], &[4, 5]));
+        assert_eq!(d.close_window(), None);
+    }
+
+    #[test]
+    fn samples_without_ports_are_ignored() {
+        let mut d = detector("");
+        let mut dg = probes("203.0.113.7", &["10.0.0.1", "10.0.0.2", "10.0.0.3"], &[0]);
+        for s in dg.samplev5.iter_mut() {
+            s.TCPSrcPort = None;
+            s.TCPDstPort = None;
+        }
+        d.observe(&dg);
+        assert_eq!(d.close_window(), None);
+    }
+}
@@ -10,5 +10,165 @@ table! {
         dstport -> Int4,
         ntype -> Text,
         size -> Int4,
+        packets -> Int4,
+        app -> Text,
+        src_country -> Text,
+        src_city -> Text,
+        src_asn -> Int8,
+        src_org -> Text,
+        dst_country -> Text,
+        dst_city -> Text,
+        dst_asn -> Int8,
+        dst_org -> Text,
+        vlan -> Int4,
+        in_if -> Int4,
+        out_if -> Int4,
+        tenant_id -> Int4,
+    }
+}
+
+table! {
+    agent_seq (agent, sub_agent, sample_type, source_id) {
+        agent -> Text,
+        sub_agent -> Int4,
+        sample_type -> Text,
+        source_id -> Text,
+        last_seq -> Int8,
+        received -> Int8,
+        lost -> Int8,
+        reordered -> Int8,
+        duplicates -> Int8,
+        reboots -> Int8,
+        update_date -> Timestamp,
+    }
+}
+
+table! {
+    agents (agent) {
+        agent -> Text,
+        first_seen -> Timestamp,
+        last_seen -> Timestamp,
+        datagram_version -> Int4,
+        sys_uptime -> Int8,
+        sample_rates -> Text,
+        sub_agents -> Text,
+        datagrams -> Int8,
+        dps -> Float4,
+    }
+}
+
+table! {
+    incident (incident_id) {
+        incident_id -> Int4,
+        start_date -> Timestamp,
+        end_date -> Nullable<Timestamp>,
+        agent -> Text,
+        dst -> Text,
+        kind -> Text,
+        peak_bps -> Int8,
+        peak_pps -> Int8,
+        sources -> Int4,
+        baseline_bps -> Int8,
+        detail -> Text,
+    }
+}
+
+table! {
+    scan_event (event_id) {
+        event_id -> Int4,
+        event_date -> Timestamp,
+        agent -> Text,
+        src -> Text,
+        kind -> Text,
+        target -> Text,
+        distinct_count -> Int4,
+        window_secs -> Int4,
+        detail -> Text,
+    }
+}
+
+table! {
+    alert_rule (rule_id) {
+        rule_id -> Int4,
+        name -> Text,
+        kind -> Text,
+        agent -> Text,
+        src_prefix -> Text,
+        dst_prefix -> Text,
+        threshold_bps -> Int8,
+        duration_secs -> Int4,
+        hold_secs -> Int4,
+        webhook -> Text,
+        syslog -> Text,
+        enabled -> Bool,
+    }
+}
+
+table! {
+    flow_hourly (hour, src, dst) {
+        hour -> Timestamp,
+        src -> Text,
+        dst -> Text,
+        bytes -> Int8,
+    }
+}
+
+table! {
+    edge_baseline (src, dst, how) {
+        src -> Text,
+        dst -> Text,
+        how -> Int2,
+        mean -> Float8,
+        stddev -> Float8,
+        samples -> Int4,
+        update_date -> Timestamp,
+    }
+}
+
+table! {
+    if_counters (counter_id) {
+        counter_id -> Int4,
+        input_date -> Timestamp,
+        agent -> Text,
+        if_index -> Int4,
+        if_speed -> Int8,
+        in_octets -> Int8,
+        out_octets -> Int8,
+    }
+}
+
+table! {
+    mac_ip (mac, ip) {
+        mac -> Text,
+        ip -> Text,
+        agent -> Text,
+        vlan -> Int4,
+        in_if -> Int4,
+        first_seen -> Timestamp,
+        last_seen -> Timestamp,
+    }
+}
+
+table! {
+    api_token (token_id) {
+        token_id -> Int4,
+        name -> Text,
+        token_hash -> Text,
+        role -> Text,
+        agents -> Text,
+        prefixes -> Text,
+        create_date -> Timestamp,
+        revoked -> Bool,
+        tenant_id -> Int4,
+    }
+}
+
+table! {
+    tenant (tenant_id) {
+        tenant_id -> Int4,
+        name -> Text,
+        agents -> Text,
+        prefixes -> Text,
+        create_date -> Timestamp,
     }
 }
@@ -0,0 +1,344 @@
+//! Datagram and sample sequence number tracking per agent
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use std;
+use diesel::pg::PgConnection;
+use diesel::sql_query;
+use diesel::query_dsl::*;
+use sflow::Datagram;
+use models;
+
+/// sFlow sequence numbers are unsigned 32 bit counters.
+const SEQ_MODULO: i64 = 1 << 32;
+/// A jump larger than this is treated as a counter reset, not as loss.
+const MAX_GAP: i64 = 100000;
+/// How often the loss counters are written to the `agent_seq` table.
+const FLUSH_INTERVAL: u64 = 10;
+
+/// Key of one sequence number space. `sample_type` is empty for the
+/// datagram sequence of an agent/sub-agent.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SeqKey {
+    pub agent: String,
+    pub sub_agent: i32,
+    pub sample_type: String,
+    pub source_id: String,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SeqCounter {
+    pub last_seq: Option<i64>,
+    pub received: i64,
+    pub lost: i64,
+    pub reordered: i64,
+    pub duplicates: i64,
+    pub reboots: i64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SeqEvent {
+    First,
+    InOrder,
+    Gap(i64),
+    Reordered,
+    Duplicate,
+    Reset,
+}
+
+/// Signed distance from `last` to `seq`, taking the 32 bit wrap into account.
+pub fn seq_diff(last: i64, seq: i64) -> i64 {
+    let mut d = (seq - last) % SEQ_MODULO;
+    if d < 0 {
+        d += SEQ_MODULO;
+    }
+    if d > SEQ_MODULO / 2 {
+        d -= SEQ_MODULO;
+    }
+    d
+}
+
+impl SeqCounter {
+    pub fn observe(&mut self, seq: i64) -> SeqEvent {
+        self.received += 1;
+        let last = match self.last_seq {
+            None => {
+                self.last_seq = Some(seq);
+                return SeqEvent::First;
+            },
+            Some(x) => x,
+        };
+        let d = seq_diff(last, seq);
+        if d == 1 {
+            self.last_seq = Some(seq);
+            SeqEvent::InOrder
+        } else if d > 1 && d <= MAX_GAP {
+            self.lost += d - 1;
+            self.last_seq = Some(seq);
+            SeqEvent::Gap(d - 1)
+        } else if d == 0 {
+            self.duplicates += 1;
+            SeqEvent::Duplicate
+        } else if d < 0 && -d <= MAX_GAP {
+            // a late packet fills a gap we already counted as lost
+            self.reordered += 1;
+            if self.lost > 0 {
+                self.lost -= 1;
+            }
+            SeqEvent::Reordered
+        } else {
+            self.last_seq = Some(seq);
+            SeqEvent::Reset
+        }
+    }
+
+    fn delta(&self, old: &SeqCounter) -> SeqCounter {
+        SeqCounter {
+            last_seq: self.last_seq,
+            received: self.received - old.received,
+            lost: self.lost - old.lost,
+            reordered: self.reordered - old.reordered,
+            duplicates: self.duplicates - old.duplicates,
+            reboots: self.reboots - old.reboots,
+        }
+    }
+}
+
+/// Loss accounting of one sequence space as served by `GET /loss`.
+#[derive(Debug, Serialize, Clone)]
+pub struct AgentLoss {
+    pub agent: String,
+    pub sub_agent: i32,
+    pub sample_type: String,
+    pub source_id: String,
+    pub last_seq: i64,
+    pub received: i64,
+    pub lost: i64,
+    pub reordered: i64,
+    pub duplicates: i64,
+    pub reboots: i64,
+    pub loss_ratio: f64,
+    pub update_date: String,
+}
+
+impl<'a> From<&'a models::AgentSeq> for AgentLoss {
+    fn from(x: &'a models::AgentSeq) -> AgentLoss {
+        let total = x.received + x.lost;
+        AgentLoss {
+            agent: x.agent.clone(),
+            sub_agent: x.sub_agent,
+            sample_type: x.sample_type.clone(),
+            source_id: x.source_id.clone(),
+            last_seq: x.last_seq,
+            received: x.received,
+            lost: x.lost,
+            reordered: x.reordered,
+            duplicates: x.duplicates,
+            reboots: x.reboots,
+            loss_ratio: if total > 0 { x.lost as f64 / total as f64 } else { 0.0 },
+            update_date: x.update_date.format("%Y-%m-%d %H:%M:%S").to_string(),
+        }
+    }
+}
+
+pub struct SeqTracker {
+    counters: BTreeMap<SeqKey, SeqCounter>,
+    flushed: BTreeMap<SeqKey, SeqCounter>,
+    uptime: BTreeMap<(String, i32), i64>,
+    last_flush: Instant,
+}
+
+impl SeqTracker {
+    pub fn new() -> SeqTracker {
+        SeqTracker {
+            counters: BTreeMap::new(),
+            flushed: BTreeMap::new(),
+            uptime: BTreeMap::new(),
+            last_flush: Instant::now(),
+        }
+    }
+
+    /// Checks the datagram sequence number, the sysUpTime and the sequence
+    /// number of every sample in the datagram.
+    pub fn observe(&mut self, dg: &Datagram) {
+        let agent_key = (dg.agent.clone(), dg.agentSubId);
+        let rebooted = match self.uptime.get(&agent_key) {
+            Some(x) => dg.sysUpTime < *x,
+            None => false,
+        };
+        self.uptime.insert(agent_key, dg.sysUpTime);
+        if rebooted {
+            info!("agent {} sub-agent {} rebooted, sysUpTime {}", dg.agent, dg.agentSubId, dg.sysUpTime);
+            for (k, v) in self.counters.iter_mut() {
+                if k.agent == dg.agent && k.sub_agent == dg.agentSubId {
+                    v.last_seq = None;
+                }
+            }
+        }
+        let key = SeqKey {
+            agent: dg.agent.clone(),
+            sub_agent: dg.agentSubId,
+            sample_type: String::new(),
+            source_id: String::new(),
+        };
+        let c = self.counters.entry(key).or_insert_with(Default::default);
+        if rebooted {
+            c.reboots += 1;
+        }
+        if let SeqEvent::Gap(n) = c.observe(dg.datagramSequenceNo) {
+            info!("agent {} lost {} datagrams before {}", dg.agent, n, dg.datagramSequenceNo);
+        }
+        for s in dg.samplev5.iter() {
+            let key = SeqKey {
+                agent: dg.agent.clone(),
+                sub_agent: dg.agentSubId,
+                sample_type: s.sampleType.clone(),
+                source_id: s.sourceId.clone(),
+            };
+            self.counters.entry(key).or_insert_with(Default::default)
+                .observe(s.sampleSequenceNo);
+        }
+    }
+
+    pub fn need_flush(&self) -> bool {
+        self.last_flush.elapsed() >= Duration::from_secs(FLUSH_INTERVAL)
+    }
+
+    /// Adds the counters collected since the last flush to `agent_seq`.
+    pub fn flush(&mut self, conn: &PgConnection) -> Result<(), Box<std::error::Error>> {
+        self.last_flush = Instant::now();
+        let mut multi_data = String::from("INSERT INTO agent_seq (agent, sub_agent, sample_type, source_id, last_seq, received, lost, reordered, duplicates, reboots) VALUES \n");
+        let mut count = 0;
+        for (k, v) in self.counters.iter() {
+            let d = match self.flushed.get(k) {
+                Some(old) => v.delta(old),
+                None => v.clone(),
+            };
+            if d.received == 0 && d.reboots == 0 {
+                continue;
+            }
+            multi_data.push_str(&format!("('{}',{},'{}','{}',{},{},{},{},{},{}),",
+                k.agent, k.sub_agent, k.sample_type, k.source_id, d.last_seq.unwrap_or(0),
+                d.received, d.lost, d.reordered, d.duplicates, d.reboots));
+            count += 1;
+        }
+        if count > 0 {
+            multi_data.pop();
+            multi_data.push_str("\nON CONFLICT (agent, sub_agent, sample_type, source_id) DO UPDATE SET \
+                last_seq = EXCLUDED.last_seq, \
+                received = agent_seq.received + EXCLUDED.received, \
+                lost = agent_seq.lost + EXCLUDED.lost, \
+                reordered = agent_seq.reordered + EXCLUDED.reordered, \
+                duplicates = agent_seq.duplicates + EXCLUDED.duplicates, \
+                reboots = agent_seq.reboots + EXCLUDED.reboots, \
+                update_date = CURRENT_TIMESTAMP");
+            sql_query(multi_data).execute(conn)?;
+        }
+        self.flushed = self.counters.clone();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sflow::SampleV5;
+
+    const MAX: i64 = SEQ_MODULO - 1;
+
+    fn datagram(seq: i64, uptime: i64, samples: &[i64]) -> Datagram {
+        Datagram {
+            agent: "192.0.2.1".to_string(),
+            datagramSequenceNo: seq,
+            sysUpTime: uptime,
+            samplev5: samples.iter().map(|x| SampleV5 {
+                sampleType: "FLOWSAMPLE".to_string(),
+                sourceId: "0:3".to_string(),
+                sampleSequenceNo: *x,
+                ..Default::default()
+            }).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn counter(t: &SeqTracker, sample_type: &str) -> SeqCounter {
+        let key = SeqKey {
+            agent: "192.0.2.1".to_string(),
+            sub_agent: 0,
+            sample_type: sample_type.to_string(),
+            source_id: if sample_type.is_empty() { String::new() } else { "0:3".to_string() },
+        };
+        t.counters[&key].clone()
+    }
+
+    #[test]
+    fn differences_wrap_at_32_bits() {
+        assert_eq!(seq_diff(10, 11), 1);
+        assert_eq!(seq_diff(11, 10), -1);
+        assert_eq!(seq_diff(MAX, 0), 1);
+        assert_eq!(seq_diff(MAX - 1, 2), 4);
+        assert_eq!(seq_diff(2, MAX - 1), -4);
+        assert_eq!(seq_diff(0, SEQ_MODULO / 2), SEQ_MODULO / 2);
+        assert_eq!(seq_diff(0, SEQ_MODULO / 2 + 1), -(SEQ_MODULO / 2 - 1));
+    }
+
+    #[test]
+    fn in_order() {
+        let mut c = SeqCounter::default();
+        assert_eq!(c.observe(5), SeqEvent::First);
+        assert_eq!(c.observe(6), SeqEvent::InOrder);
+        assert_eq!(c.observe(7), SeqEvent::InOrder);
+        assert_eq!((c.received, c.lost, c.last_seq), (3, 0, Some(7)));
+    }
+
+    #[test]
+    fn lost_late_and_duplicated() {
+        let mut c = SeqCounter::default();
+        c.observe(1);
+        assert_eq!(c.observe(5), SeqEvent::Gap(3));
+        assert_eq!(c.lost, 3);
+        // 3 arrives late and was not lost after all
+        assert_eq!(c.observe(3), SeqEvent::Reordered);
+        assert_eq!((c.lost, c.reordered, c.last_seq), (2, 1, Some(5)));
+        assert_eq!(c.observe(5), SeqEvent::Duplicate);
+        assert_eq!(c.duplicates, 1);
+        assert_eq!(c.observe(6), SeqEvent::InOrder);
+        assert_eq!(c.received, 5);
+    }
+
+    #[test]
+    fn wrapped() {
+        let mut c = SeqCounter::default();
+        c.observe(MAX - 1);
+        assert_eq!(c.observe(MAX), SeqEvent::InOrder);
+        assert_eq!(c.observe(0), SeqEvent::InOrder);
+        assert_eq!(c.observe(2), SeqEvent::Gap(1));
+        assert_eq!((c.lost, c.last_seq), (1, Some(2)));
+    }
+
+    #[test]
+    fn reset() {
+        let mut c = SeqCounter::default();
+        c.observe(5_000_000);
+        // a jump back past MAX_GAP is a restarted counter, not a late packet
+        assert_eq!(c.observe(1), SeqEvent::Reset);
+        assert_eq!(c.observe(2), SeqEvent::InOrder);
+        // and so is a jump forward
+        assert_eq!(c.observe(2 + MAX_GAP + 1), SeqEvent::Reset);
+        assert_eq!((c.lost, c.reordered, c.last_seq), (0, 0, Some(2 + MAX_GAP + 1)));
+    }
+
+    #[test]
+    fn reboots_restart_every_sequence_of_the_agent() {
+        let mut t = SeqTracker::new();
+        t.observe(&datagram(1000, 50000, &[700]));
+        t.observe(&datagram(1001, 51000, &[702]));
+        assert_eq!(counter(&t, "FLOWSAMPLE").lost, 1);
+        // sysUpTime went back: the agent restarted counting from 1
+        t.observe(&datagram(1, 10, &[1]));
+        t.observe(&datagram(2, 1010, &[2]));
+        let dg = counter(&t, "");
+        assert_eq!((dg.received, dg.lost, dg.reboots, dg.last_seq), (4, 0, 1, Some(2)));
+        let samples = counter(&t, "FLOWSAMPLE");
+        assert_eq!((samples.received, samples.lost, samples.last_seq), (4, 1, Some(2)));
+    }
+}
@@ -1,7 +1,7 @@
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
 use std::str;
-use std::io;
+use std::io::BufRead;
 use std;
 use regex::Regex;
 use diesel::pg::PgConnection;
@@ -9,6 +9,21 @@ use diesel::sql_query;
 use diesel::query_dsl::*;
 use dotenv;
 use models;
+use seqtrack::SeqTracker;
+use registry::AgentRegistry;
+use window::SlidingWindow;
+use heavy::HeavyHitters;
+use ddos::{DdosDetector, DdosConfig};
+use scan::{ScanDetector, ScanConfig};
+use app::Classifier;
+use geo::{GeoDb, GeoInfo};
+use l2::MacLearner;
+use tenant::TenantMap;
+use config::IngestConfig;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
 
 #[allow(non_snake_case)]
 #[derive(Debug, Default)]
@@ -18,12 +33,16 @@ pub struct Datagram {
     pub datagramSourceIP: String,
     pub unixSecondsUTC: i32,
     pub datagramVersion: i8,
+    pub datagramSequenceNo: i64,
+    pub agentSubId: i32,
     pub sysUpTime: i64,
 }
 
 #[allow(non_snake_case)]
 #[derive(Debug, Default)]
 pub struct SampleV5 {
+    pub sampleType: String,
+    pub sourceId: String,
     pub srcMAC: Option<String>,
     pub dstMAC: Option<String>,
     pub srcIP: Option<String>,
@@ -34,11 +53,19 @@ pub struct SampleV5 {
     pub UDPDstPort: Option<i32>,
     pub TCPSrcPort: Option<i32>,
     pub TCPDstPort: Option<i32>,
+    pub IPProtocol: Option<i32>,
+    pub TCPFlags: Option<i32>,
+    pub headerBytes: Option<String>,
     pub meanSkipCount: i32,
-    pub inputPort: i8,
-    pub outputPort: i8,
-    pub sampleSequenceNo: i32,
+    pub inputPort: i32,
+    pub outputPort: i32,
+    pub vlan: Option<i32>,
+    pub sampleSequenceNo: i64,
     pub sampledPacketSize: i32,
+    pub ifIndex: Option<i32>,
+    pub ifSpeed: Option<i64>,
+    pub ifInOctets: Option<i64>,
+    pub ifOutOctets: Option<i64>,
 }
 #[derive(Debug, Default)]
 pub struct FlowPoint {
@@ -56,14 +83,28 @@ pub struct FlowDirection {
     pub dstport: i32,
     pub ntype: String,
     pub size: i32,
+    pub packets: i32,
+    pub app: String,
+    pub src_geo: GeoInfo,
+    pub dst_geo: GeoInfo,
+    pub vlan: i32,
+    pub in_if: i32,
+    pub out_if: i32,
+    pub tenant_id: i32,
 }
-type FlowMap = BTreeMap<String, FlowDirection>;
+pub type FlowMap = BTreeMap<String, FlowDirection>;
 
 #[allow(non_snake_case)]
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct FlowName {
     pub nodeId: i32,
     pub name: String,
+    /// Host name of the address, see `names`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Vendor of a MAC address, see `oui`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vendor: Option<String>,
 }
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct FlowDirection2 {
@@ -71,6 +112,8 @@ pub struct FlowDirection2 {
     pub target: i32,
     pub ntype: String,
     pub value: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub anomaly: Option<f32>,
 }
 
 pub fn filter_multicast_ipv6(fm: &mut FlowMap) {
@@ -113,6 +156,24 @@ pub fn filter_ff_mac(fm: &mut FlowMap) {
     }
 }
 
+/// Adds one entry of a `FlowMap` into another one.
+/// Key raw flows are merged under: one conversation and application seen
+/// on the same agent, VLAN and ports, for the same tenant. Views such as
+/// `l2::l2_graph` rely on the ports staying apart.
+pub fn flow_key(fd: &FlowDirection) -> String {
+    format!("{}=>{}|{}|{}|{}|{}|{}|{}", fd.source, fd.target, fd.app, fd.agent, fd.vlan,
+        fd.in_if, fd.out_if, fd.tenant_id)
+}
+
+pub fn merge_flow(fm: &mut FlowMap, key: &str, fd: &FlowDirection) {
+    if let Some(x) = fm.get_mut(key) {
+        x.size += fd.size;
+        x.packets += fd.packets;
+        return;
+    }
+    fm.insert(key.to_string(), fd.clone());
+}
+
 pub fn build_d3_data(fm: &FlowMap) -> Result<(Vec<FlowName>, Vec<FlowDirection2>), Box<std::error::Error>> {
     let mut pointset: BTreeSet<String> = BTreeSet::new();
     for fd in fm.iter() {
@@ -122,24 +183,29 @@ pub fn build_d3_data(fm: &FlowMap) -> Result<(Vec<FlowName>, Vec<FlowDirection2>
     let mut points: Vec<FlowName> = vec![];
     let mut count = 0;
     for fd in pointset.iter() {
-        points.push(FlowName {nodeId: count, name:fd.clone() });
+        points.push(FlowName {nodeId: count, name:fd.clone(), label: None, vendor: None });
         count += 1;
     }
     let mut pmap: BTreeMap<String, i32> = BTreeMap::new();
     for p in points.iter() {
         pmap.insert(p.name.clone(), p.nodeId);
     }
-    let mut fmap: Vec<FlowDirection2> = vec![];
+    // flows of several applications share one link
+    let mut lmap: BTreeMap<(i32, i32), FlowDirection2> = BTreeMap::new();
     for fd in fm.iter() {
         if fd.1.source != fd.1.target && fd.1.source != "0.0.0.0" && fd.1.target != "0.0.0.0" {
-            fmap.push(FlowDirection2 {
-                source: *pmap.get(&fd.1.source).unwrap(),
-                target: *pmap.get(&fd.1.target).unwrap(),
+            let source = *pmap.get(&fd.1.source).unwrap();
+            let target = *pmap.get(&fd.1.target).unwrap();
+            lmap.entry((source, target)).or_insert(FlowDirection2 {
+                source: source,
+                target: target,
                 ntype: fd.1.ntype.clone(),
-                value: fd.1.size as f32,
-            });
+                value: 0.0,
+                anomaly: None,
+            }).value += fd.1.size as f32;
         }
     }
+    let fmap: Vec<FlowDirection2> = lmap.into_iter().map(|x| x.1).collect();
     Ok( (points, fmap) )
 }
 
@@ -152,105 +218,135 @@ pub fn build_d3_json(fm: &FlowMap) -> Result<(String, String), Box<std::error::E
 pub fn build_graph_from_db(data: &Vec<models::Flow>) -> Result<FlowMap, Box<std::error::Error>> {
     let mut fm: FlowMap = FlowMap::new();
     for s in data.iter() {
-        let key = s.src.clone() + "=>" + &s.dst;
-        let mut fd: Option<FlowDirection> = None;
-        
-        match fm.get_mut(&key) {
-            None => {
-                fd = Some(FlowDirection {
-                    agent: s.agent.clone(),
-                    utc: s.utc,
-                    source: s.src.clone(),
-                    target: s.dst.clone(),
-                    srcport: s.srcport.clone(),
-                    dstport: s.dstport.clone(),
-                    ntype: s.ntype.clone(), 
-                    size: s.size
-                });
+        let fd = FlowDirection {
+            agent: s.agent.clone(),
+            utc: s.utc,
+            source: s.src.clone(),
+            target: s.dst.clone(),
+            srcport: s.srcport.clone(),
+            dstport: s.dstport.clone(),
+            ntype: s.ntype.clone(), 
+            size: s.size,
+            packets: s.packets,
+            app: s.app.clone(),
+            src_geo: GeoInfo {
+                country: s.src_country.clone(),
+                city: s.src_city.clone(),
+                asn: s.src_asn,
+                org: s.src_org.clone(),
             },
-            Some(ref mut x) => {
-                x.size += s.size;
-            }
-        }
-        if fd.is_some() {
-            let fd = fd.unwrap();
-            fm.insert(key, fd);
-        }
-    
+            dst_geo: GeoInfo {
+                country: s.dst_country.clone(),
+                city: s.dst_city.clone(),
+                asn: s.dst_asn,
+                org: s.dst_org.clone(),
+            },
+            vlan: s.vlan,
+            in_if: s.in_if,
+            out_if: s.out_if,
+            tenant_id: s.tenant_id,
+        };
+        merge_flow(&mut fm, &flow_key(&fd), &fd);
     }
     Ok(fm)
 }
 
-pub fn build_graph(data: &Vec<Datagram>) -> Result<FlowMap, Box<std::error::Error>> {
+/// Endpoints, ports and scaled size of one flow sample. `agent` and `utc`
+/// are left for the caller.
+pub fn sample_flow(s: &SampleV5) -> Option<FlowDirection> {
+    let mut src:Option<String> = None;
+    let mut dst:Option<String> = None;
+    let mut srcport:Option<i32> = None;
+    let mut dstport:Option<i32> = None;
+    let size = s.sampledPacketSize;
+    let mut ntype = "N/A";
+    if s.srcIP.is_some() && s.dstIP.is_some() {
+        src = s.srcIP.clone();
+        dst = s.dstIP.clone();
+        ntype = "ipv4";
+    } else if s.srcIP6.is_some() && s.srcIP6.is_some() {
+        src = s.srcIP6.clone();
+        dst = s.dstIP6.clone();
+        ntype = "ipv6";
+    }
+    if s.UDPSrcPort.is_some() && s.UDPDstPort.is_some() {
+        srcport = s.UDPSrcPort.clone();
+        dstport = s.UDPDstPort.clone();
+    } else if s.TCPSrcPort.is_some() && s.TCPDstPort.is_some() {
+        srcport = s.TCPSrcPort.clone();
+        dstport = s.TCPDstPort.clone();
+    }
+    if src.is_none() {
+        src = s.srcMAC.clone();
+        dst = s.dstMAC.clone();
+        ntype = "mac";
+    }
+    if src.is_some() && dst.is_some() {
+        return Some(FlowDirection {
+            agent: String::new(),
+            utc: 0,
+            source: src.unwrap(),
+            target: dst.unwrap(),
+            srcport: srcport.unwrap_or(-1),
+            dstport: dstport.unwrap_or(-1),
+            ntype: ntype.to_string(),
+            size: size * s.meanSkipCount,
+            packets: s.meanSkipCount,
+            app: String::new(),
+            src_geo: GeoInfo::default(),
+            dst_geo: GeoInfo::default(),
+            vlan: s.vlan.unwrap_or(0),
+            in_if: s.inputPort,
+            out_if: s.outputPort,
+            tenant_id: 0,
+        });
+    }
+    None
+}
+
+pub fn build_graph(data: &Vec<Datagram>, classifier: &Classifier) -> Result<FlowMap, Box<std::error::Error>> {
     let mut fm: FlowMap = FlowMap::new();
     for dg in data.iter() {
         for s in dg.samplev5.iter() {
-            let mut src:Option<String> = None;
-            let mut dst:Option<String> = None;
-            let mut srcport:Option<i32> = None;
-            let mut dstport:Option<i32> = None;
-            let size = s.sampledPacketSize;
-            let mut ntype = "N/A";
-            if s.srcIP.is_some() && s.dstIP.is_some() {
-                src = s.srcIP.clone();
-                dst = s.dstIP.clone();
-                ntype = "ipv4";
-            } else if s.srcIP6.is_some() && s.srcIP6.is_some() {
-                src = s.srcIP6.clone();
-                dst = s.dstIP6.clone();
-                ntype = "ipv6";
-            }
-            if s.UDPSrcPort.is_some() && s.UDPDstPort.is_some() {
-                srcport = s.UDPSrcPort.clone();
-                dstport = s.UDPDstPort.clone();
-            } else if s.TCPSrcPort.is_some() && s.TCPDstPort.is_some() {
-                srcport = s.TCPSrcPort.clone();
-                dstport = s.TCPDstPort.clone();
+            if let Some(mut f) = sample_flow(s) {
+                f.app = classifier.classify(s, &f);
+                f.agent = dg.agent.clone();
+                f.utc = dg.unixSecondsUTC;
+                merge_flow(&mut fm, &flow_key(&f), &f);
             }
-            if src.is_none() {
-                src = s.srcMAC.clone();
-                dst = s.dstMAC.clone();
-                ntype = "mac";
-            }
-            if src.is_some() && dst.is_some() {
-                let src = src.unwrap();
-                let dst = dst.unwrap();
-                let mut fd: Option<FlowDirection> = None;
-                let key = src.clone() + "=>" + &dst;
-                match fm.get_mut(&key) {
-                    None => {
-                        fd = Some(FlowDirection {
-                            agent: dg.agent.clone(),
-                            utc: dg.unixSecondsUTC,
-                            source: src, 
-                            target: dst, 
-                            srcport: srcport.unwrap_or(-1),
-                            dstport: dstport.unwrap_or(-1),
-                            ntype: ntype.to_string(), 
-                            size: size * s.meanSkipCount
-                        });
-                    },
-                    Some(ref mut x) => {
-                        x.size += size * s.meanSkipCount;
-                    }
-                }
-                if fd.is_some() {
-                    let fd = fd.unwrap();
-                    fm.insert(key, fd);
-                }
-            }
-            
         }
     }
     Ok(fm)
 }
 
+/// TCP flags are printed in decimal or as `0x..` hex.
+fn parse_flags(s: &str) -> Option<i32> {
+    if s.starts_with("0x") {
+        i32::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// Reads the next line of a datagram, which must not end before `endDatagram`.
+fn next_line<R: BufRead>(r: &mut R, input: &mut String) -> Result<(), Box<std::error::Error>> {
+    input.clear();
+    if r.read_line(input)? == 0 {
+        return Err(From::from("input ended inside a datagram"));
+    }
+    Ok(())
+}
+
 #[allow(non_snake_case)]
-pub fn read_sample_v5(s: &mut SampleV5) -> Result<(), Box<std::error::Error>> {
+pub fn read_sample_v5<R: BufRead>(r: &mut R, s: &mut SampleV5) -> Result<(), Box<std::error::Error>> {
+    let mut input = String::new();
     loop {
-        let mut input = String::new();
-        io::stdin().read_line(&mut input).expect("failed to read from pipe");
-        if let Some(_) = input.find("srcMAC ") {
+        next_line(r, &mut input)?;
+        if let Some(_) = input.find("sampleType ") {
+            try_scan!(input.bytes() => "sampleType {}", s.sampleType);
+        } else if let Some(_) = input.find("sourceId ") {
+            try_scan!(input.bytes() => "sourceId {}", s.sourceId);
+        } else if let Some(_) = input.find("srcMAC ") {
             let srcMAC:String;
             try_scan!(input.bytes() => "srcMAC {}", srcMAC);
             s.srcMAC = Some(srcMAC);
@@ -290,16 +386,57 @@ pub fn read_sample_v5(s: &mut SampleV5) -> Result<(), Box<std::error::Error>> {
             let TCPDstPort:i32;
             try_scan!(input.bytes() => "TCPDstPort {}", TCPDstPort);
             s.TCPDstPort = Some(TCPDstPort);
+        } else if let Some(_) = input.find("headerBytes ") {
+            let headerBytes:String;
+            try_scan!(input.bytes() => "headerBytes {}", headerBytes);
+            s.headerBytes = Some(headerBytes);
+        } else if let Some(_) = input.find("IPProtocol ") {
+            let IPProtocol:i32;
+            try_scan!(input.bytes() => "IPProtocol {}", IPProtocol);
+            s.IPProtocol = Some(IPProtocol);
+        } else if let Some(_) = input.find("TCPFlags ") {
+            let TCPFlags:String;
+            try_scan!(input.bytes() => "TCPFlags {}", TCPFlags);
+            s.TCPFlags = parse_flags(&TCPFlags);
         } else if let Some(_) = input.find("meanSkipCount ") {
             try_scan!(input.bytes() => "meanSkipCount {}", s.meanSkipCount);
         } else if let Some(_) = input.find("inputPort ") {
-            try_scan!(input.bytes() => "inputPort {}", s.inputPort);
-        } else if let Some(_) = input.find("outputPort ") {
-            try_scan!(input.bytes() => "outputPort {}", s.outputPort);
+            let inputPort:String;
+            try_scan!(input.bytes() => "inputPort {}", inputPort);
+            s.inputPort = inputPort.parse().unwrap_or(0);
         } else if let Some(_) = input.find("outputPort ") {
+            // `multiple N` and `format N` ports are not an ifIndex
+            let outputPort:String;
+            try_scan!(input.bytes() => "outputPort {}", outputPort);
+            s.outputPort = outputPort.parse().unwrap_or(0);
+        } else if let Some(_) = input.find("in_vlan ") {
+            let vlan:i32;
+            try_scan!(input.bytes() => "in_vlan {}", vlan);
+            s.vlan = Some(vlan);
+        } else if let Some(_) = input.find("decodedVLAN ") {
+            let vlan:i32;
+            try_scan!(input.bytes() => "decodedVLAN {}", vlan);
+            s.vlan = s.vlan.or(Some(vlan));
+        } else if let Some(_) = input.find("sampleSequenceNo ") {
             try_scan!(input.bytes() => "sampleSequenceNo {}", s.sampleSequenceNo);
         } else if let Some(_) = input.find("sampledPacketSize ") {
             try_scan!(input.bytes() => "sampledPacketSize {}", s.sampledPacketSize);
+        } else if let Some(_) = input.find("ifIndex ") {
+            let ifIndex:i32;
+            try_scan!(input.bytes() => "ifIndex {}", ifIndex);
+            s.ifIndex = Some(ifIndex);
+        } else if let Some(_) = input.find("ifSpeed ") {
+            let ifSpeed:i64;
+            try_scan!(input.bytes() => "ifSpeed {}", ifSpeed);
+            s.ifSpeed = Some(ifSpeed);
+        } else if let Some(_) = input.find("ifInOctets ") {
+            let ifInOctets:i64;
+            try_scan!(input.bytes() => "ifInOctets {}", ifInOctets);
+            s.ifInOctets = Some(ifInOctets);
+        } else if let Some(_) = input.find("ifOutOctets ") {
+            let ifOutOctets:i64;
+            try_scan!(input.bytes() => "ifOutOctets {}", ifOutOctets);
+            s.ifOutOctets = Some(ifOutOctets);
         } else if let Some(_) = input.find("endSample") {
             break;
         }
@@ -307,10 +444,10 @@ pub fn read_sample_v5(s: &mut SampleV5) -> Result<(), Box<std::error::Error>> {
     Ok(())
 }
 
-pub fn read_datagram(dg: &mut Datagram) -> Result<(), Box<std::error::Error>> {
+pub fn read_datagram<R: BufRead>(r: &mut R, dg: &mut Datagram) -> Result<(), Box<std::error::Error>> {
+    let mut input = String::new();
     loop {
-        let mut input = String::new();
-        io::stdin().read_line(&mut input).expect("failed to read from pipe");
+        next_line(r, &mut input)?;
         if let Some(_) = input.find("agent ") {
             try_scan!(input.bytes() => "agent {}", dg.agent);
         } else if let Some(_) = input.find("datagramSourceIP ") {
@@ -319,11 +456,15 @@ pub fn read_datagram(dg: &mut Datagram) -> Result<(), Box<std::error::Error>> {
             try_scan!(input.bytes() => "unixSecondsUTC {}", dg.unixSecondsUTC);
         } else if let Some(_) = input.find("datagramVersion ") {
             try_scan!(input.bytes() => "datagramVersion {}", dg.datagramVersion);
+        } else if let Some(_) = input.find("datagramSequenceNo ") {
+            try_scan!(input.bytes() => "datagramSequenceNo {}", dg.datagramSequenceNo);
+        } else if let Some(_) = input.find("agentSubId ") {
+            try_scan!(input.bytes() => "agentSubId {}", dg.agentSubId);
         } else if let Some(_) = input.find("sysUpTime ") {
             try_scan!(input.bytes() => "sysUpTime {}", dg.sysUpTime);
         } else if let Some(_) = input.find("startSample") {
             let mut s:SampleV5 = Default::default();
-            read_sample_v5(&mut s)?;
+            read_sample_v5(r, &mut s)?;
             dg.samplev5.push(s);
         } else if let Some(_) = input.find("endDatagram") {
             break;
@@ -341,29 +482,171 @@ pub fn get_sql_url() -> Result<String, Box<std::error::Error>> {
     Ok(url)
 }
 
-pub fn input_data(conn: &PgConnection) -> Result<(), Box<std::error::Error>> {
+/// Reads an optional setting from the environment (or `.env`).
+pub fn get_env_or<T: str::FromStr>(name: &str, default: T) -> T {
+    let _ = dotenv::dotenv();
+    std::env::var(name).ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(default)
+}
+
+/// In-memory consumers of the ingested datagrams, shared with the HTTP side.
+pub struct IngestSinks {
+    pub window: Arc<SlidingWindow>,
+    pub heavy: Arc<Mutex<HeavyHitters>>,
+}
+
+/// SQL value of `input_date` for a datagram sent at `utc`: the time it is
+/// stored, or the time it was sent when replaying.
+fn input_date(cfg: &IngestConfig, utc: i32) -> String {
+    if cfg.replay {
+        format!("to_timestamp({})::timestamp", utc)
+    } else {
+        "CURRENT_TIMESTAMP".to_string()
+    }
+}
+
+/// Stores the interface counters of the counter samples of a datagram.
+pub fn insert_counters(conn: &PgConnection, cfg: &IngestConfig, dg: &Datagram) -> Result<(), Box<std::error::Error>> {
+    let date = input_date(cfg, dg.unixSecondsUTC);
+    let mut multi_data = String::from("INSERT INTO if_counters (input_date, agent, if_index, if_speed, in_octets, out_octets) VALUES \n");
+    let mut count = 0;
+    for s in dg.samplev5.iter() {
+        if let (Some(idx), Some(i), Some(o)) = (s.ifIndex, s.ifInOctets, s.ifOutOctets) {
+            multi_data.push_str(&format!("({},'{}',{},{},{},{}),",
+                date, dg.agent, idx, s.ifSpeed.unwrap_or(0), i, o));
+            count += 1;
+        }
+    }
+    if count > 0 {
+        multi_data.pop();
+        sql_query(multi_data).execute(conn)?;
+    }
+    Ok(())
+}
+
+/// `country, city, asn, org` SQL values of an address.
+fn geo_values(g: &GeoInfo) -> String {
+    format!("'{}','{}',{},'{}'", g.country.replace('\'', "''"), g.city.replace('\'', "''"),
+        g.asn, g.org.replace('\'', "''"))
+}
+
+/// How often the ingest loop wakes up to flush and tick when no datagram
+/// arrives.
+const IDLE_WAKEUP: u64 = 1;
+
+/// Reads the datagrams of `r` on a thread of its own so the ingest loop is
+/// not stuck in a read while the source is quiet. The channel closes at the
+/// end of `r`, after an error if there was one.
+fn read_datagrams<R: BufRead + Send + 'static>(mut r: R) -> Receiver<Result<Datagram, String>> {
+    let (tx, rx) = sync_channel(1024);
+    thread::spawn(move || {
+        let mut input = String::new();
+        loop {
+            input.clear();
+            match r.read_line(&mut input) {
+                Ok(0) => return,
+                Ok(_) => {},
+                Err(e) => {
+                    let _ = tx.send(Err(e.to_string()));
+                    return;
+                },
+            }
+            if let Some(_) = input.find("startDatagram") {
+                let mut dg:Datagram = Default::default();
+                let res = read_datagram(&mut r, &mut dg).map(|_| dg).map_err(|e| e.to_string());
+                let failed = res.is_err();
+                if tx.send(res).is_err() || failed {
+                    return;
+                }
+            }
+        }
+    });
+    rx
+}
+
+/// Stores the datagrams of sflowtool text output until it ends.
+pub fn input_data<R: BufRead + Send + 'static>(conn: &PgConnection, sinks: &IngestSinks, cfg: &IngestConfig, r: R) -> Result<(), Box<std::error::Error>> {
     let mut data: Vec<Datagram> = vec![];
-    let mut input = String::new();
+    let mut seq = SeqTracker::new();
+    let mut registry = AgentRegistry::load(conn)?;
+    let mut ddos = DdosDetector::new(DdosConfig::from_env());
+    ddos.close_open(conn)?;
+    let mut scan = ScanDetector::new(ScanConfig::from_env()?);
+    let classifier = Classifier::load()?;
+    let mut geo = GeoDb::load();
+    let mut macs = MacLearner::new();
+    let mut tenants = TenantMap::load(conn)?;
+    let datagrams = read_datagrams(r);
     loop {
-        io::stdin().read_line(&mut input).expect("failed to read from pipe");
-        if let Some(_) = input.find("startDatagram") {
-            let mut dg:Datagram = Default::default();
-            read_datagram(&mut dg)?;
+        let next = match datagrams.recv_timeout(Duration::from_secs(IDLE_WAKEUP)) {
+            Ok(x) => Some(x?),
+            // nothing arrived, the flushes and ticks below still run
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => {
+                // keep what the last datagrams taught
+                seq.flush(conn)?;
+                registry.flush(conn)?;
+                macs.flush(conn)?;
+                return Ok(());
+            },
+        };
+        if let Some(dg) = next {
+            seq.observe(&dg);
+            registry.observe(&dg);
+            if let Ok(mut heavy) = sinks.heavy.lock() {
+                heavy.observe(&dg);
+            }
+            ddos.observe(&dg);
+            scan.observe(&dg);
+            macs.observe(&dg);
+            insert_counters(conn, cfg, &dg)?;
+            let (agent, sub_agent) = (dg.agent.clone(), dg.agentSubId);
             data.push(dg);
-            let mut fm = build_graph(&data)?;
-            filter_multicast_ipv6(&mut fm);
-            filter_local_ipv6(&mut fm);
-            filter_ff_mac(&mut fm);
+            let mut fm = build_graph(&data, &classifier)?;
+            if cfg.drop_multicast_ipv6 {
+                filter_multicast_ipv6(&mut fm);
+            }
+            if cfg.drop_link_local_ipv6 {
+                filter_local_ipv6(&mut fm);
+            }
+            if cfg.drop_broadcast_mac {
+                filter_ff_mac(&mut fm);
+            }
+            geo.enrich(&mut fm);
+            tenants.tag(&mut fm, &agent, sub_agent);
             if fm.len() > 0 {
-                let mut multi_data = String::from("INSERT INTO flow (agent, utc, src, dst, srcport, dstport, ntype, size) VALUES \n");
+                let mut multi_data = String::from("INSERT INTO flow (input_date, agent, utc, src, dst, srcport, dstport, ntype, size, packets, app, \
+                    src_country, src_city, src_asn, src_org, dst_country, dst_city, dst_asn, dst_org, vlan, in_if, out_if, tenant_id) VALUES \n");
                 for (_k,v) in fm.iter() {
-                    multi_data.push_str(&format!("('{}',{},'{}','{}',{},{},'{}',{}),", 
-                        v.agent, v.utc, v.source, v.target, v.srcport, v.dstport, v.ntype, v.size));
+                    multi_data.push_str(&format!("({},'{}',{},'{}','{}',{},{},'{}',{},{},'{}',{},{},{},{},{},{}),",
+                        input_date(cfg, v.utc), v.agent, v.utc, v.source, v.target, v.srcport, v.dstport, v.ntype, v.size, v.packets,
+                        v.app.replace('\'', "''"), geo_values(&v.src_geo), geo_values(&v.dst_geo),
+                        v.vlan, v.in_if, v.out_if, v.tenant_id));
                 }
                 multi_data.pop();
                 sql_query(multi_data).execute(conn)?;
+                sinks.window.add(&fm);
             }
-        };
-        data.clear();
+            data.clear();
+        }
+        if seq.need_flush() {
+            seq.flush(conn)?;
+        }
+        if registry.need_flush() {
+            registry.flush(conn)?;
+        }
+        if macs.need_flush() {
+            macs.flush(conn)?;
+        }
+        if tenants.need_reload() {
+            tenants.reload(conn)?;
+        }
+        if ddos.need_tick() {
+            ddos.tick(conn)?;
+        }
+        if scan.need_tick() {
+            scan.tick(conn)?;
+        }
     }
 }
@@ -0,0 +1,173 @@
+//! Tenants owning agents, sub-agents and prefixes; every flow row is
+//! tagged with the tenant it belongs to
+use std::time::{Duration, Instant};
+use std;
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use prefix::{self, Prefix};
+use schema;
+use sflow::*;
+use models;
+
+/// `tenant_id` of flows owned by no tenant.
+pub const NO_TENANT: i32 = 0;
+
+/// A tenant to create, as posted to `/admin/tenants`.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct TenantParams {
+    pub name: String,
+    /// `agent` or `agent/sub-agent` entries.
+    #[serde(default)]
+    pub agents: Vec<String>,
+    #[serde(default)]
+    pub prefixes: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct TenantInfo {
+    pub tenant_id: i32,
+    pub name: String,
+    pub agents: Vec<String>,
+    pub prefixes: Vec<String>,
+    pub create_date: String,
+}
+
+fn split_list(s: &str) -> Vec<String> {
+    s.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()).map(|x| x.to_string()).collect()
+}
+
+impl TenantInfo {
+    pub fn new(x: &models::Tenant) -> TenantInfo {
+        TenantInfo {
+            tenant_id: x.tenant_id,
+            name: x.name.clone(),
+            agents: split_list(&x.agents),
+            prefixes: split_list(&x.prefixes),
+            create_date: x.create_date.format("%Y-%m-%d %H:%M:%S").to_string(),
+        }
+    }
+}
+
+/// `agent` or `agent/sub-agent`, the latter only matching that sub-agent.
+fn parse_agent(s: &str) -> Result<(String, Option<i32>), String> {
+    let mut it = s.splitn(2, '/');
+    let agent = it.next().unwrap_or("").trim().to_string();
+    match it.next() {
+        None => Ok((agent, None)),
+        Some(x) => x.trim().parse().map(|x| (agent, Some(x)))
+            .map_err(|_| format!("bad sub-agent in {}", s)),
+    }
+}
+
+pub fn validate(p: &TenantParams) -> Result<(), String> {
+    if p.name.trim().is_empty() {
+        return Err("name is required".to_string());
+    }
+    if p.agents.is_empty() && p.prefixes.is_empty() {
+        return Err("a tenant needs agents or prefixes".to_string());
+    }
+    for x in p.agents.iter() {
+        parse_agent(x)?;
+    }
+    for x in p.prefixes.iter() {
+        Prefix::parse(x)?;
+    }
+    Ok(())
+}
+
+struct Owner {
+    tenant_id: i32,
+    agents: Vec<(String, Option<i32>)>,
+    prefixes: Vec<Prefix>,
+}
+
+impl Owner {
+    /// Length of the most specific prefix holding one of `addrs`, 0 for
+    /// tenants without prefixes; `None` when the traffic is not theirs. A
+    /// missing `sub_agent` only matches entries without one.
+    fn matches(&self, agent: &str, sub_agent: Option<i32>, addrs: &[&str]) -> Option<u8> {
+        if !self.agents.is_empty() &&
+            !self.agents.iter().any(|x| x.0 == agent && (x.1.is_none() || x.1 == sub_agent)) {
+            return None;
+        }
+        if self.prefixes.is_empty() {
+            return Some(0);
+        }
+        let mut best = None;
+        for addr in addrs.iter() {
+            if let Ok(ip) = addr.parse::<std::net::IpAddr>() {
+                for p in self.prefixes.iter() {
+                    if p.contains(&ip) && best.map(|b| p.len > b).unwrap_or(true) {
+                        best = Some(p.len);
+                    }
+                }
+            }
+        }
+        best
+    }
+}
+
+/// Tenants as seen by the ingest path, reloaded every `TENANT_RELOAD_SECS`.
+pub struct TenantMap {
+    owners: Vec<Owner>,
+    reload: Duration,
+    loaded: Instant,
+}
+
+impl TenantMap {
+    pub fn load(conn: &PgConnection) -> Result<TenantMap, Box<std::error::Error>> {
+        let mut m = TenantMap {
+            owners: vec![],
+            reload: Duration::from_secs(get_env_or("TENANT_RELOAD_SECS", 60)),
+            loaded: Instant::now(),
+        };
+        m.reload(conn)?;
+        Ok(m)
+    }
+
+    pub fn reload(&mut self, conn: &PgConnection) -> Result<(), Box<std::error::Error>> {
+        use self::schema::tenant::dsl::*;
+        self.loaded = Instant::now();
+        let mut owners = vec![];
+        for x in tenant.order(tenant_id.asc()).load::<models::Tenant>(conn)? {
+            owners.push(Owner {
+                tenant_id: x.tenant_id,
+                agents: split_list(&x.agents).iter().map(|a| parse_agent(a)).collect::<Result<Vec<_>, String>>()?,
+                prefixes: prefix::parse_list(&x.prefixes)?,
+            });
+        }
+        self.owners = owners;
+        Ok(())
+    }
+
+    pub fn need_reload(&self) -> bool {
+        self.loaded.elapsed() >= self.reload
+    }
+
+    /// Tenant of traffic of `agent` between `addrs`: with several owners
+    /// the most specific prefix wins, then the lowest tenant id.
+    fn owner_of(&self, agent: &str, sub_agent: Option<i32>, addrs: &[&str]) -> i32 {
+        let mut best: Option<(u8, i32)> = None;
+        for o in self.owners.iter() {
+            if let Some(len) = o.matches(agent, sub_agent, addrs) {
+                if best.map(|b| len > b.0).unwrap_or(true) {
+                    best = Some((len, o.tenant_id));
+                }
+            }
+        }
+        best.map(|x| x.1).unwrap_or(NO_TENANT)
+    }
+
+    /// Sets `tenant_id` of the flows of one datagram.
+    pub fn tag(&self, fm: &mut FlowMap, agent: &str, sub_agent: i32) {
+        for fd in fm.values_mut() {
+            fd.tenant_id = self.owner_of(agent, Some(sub_agent), &[fd.source.as_str(), fd.target.as_str()]);
+        }
+    }
+
+    /// Tenant of an incident, scan or MAC binding, which do not keep the
+    /// sub-agent that saw them.
+    pub fn owner(&self, agent: &str, addrs: &[&str]) -> i32 {
+        self.owner_of(agent, None, addrs)
+    }
+}
@@ -0,0 +1,388 @@
+//! Time ranges of queries: dates as RFC 3339, Unix epoch, `%Y-%m-%d %H:%M:%S`
+//! in the time zone of the request or `now-15m`, and ranges such as
+//! `last 15m`, `today` and `yesterday`
+use chrono::{Datelike, DateTime, Duration, FixedOffset, Local, LocalResult, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use error::ApiError;
+
+/// Seconds after its end until a range is closed, to let the last flows in.
+const CLOSE_GRACE_SECS: i64 = 60;
+/// Epoch values above this are milliseconds, as Grafana sends them.
+const EPOCH_MILLIS: i64 = 100_000_000_000;
+/// Unix seconds of 9999-12-31 23:59:59, the last date a range may hold, so
+/// shifting it into any zone stays within chrono.
+const MAX_SECS: i64 = 253_402_300_799;
+
+fn bad(msg: String) -> ApiError {
+    ApiError::BadRequest(msg)
+}
+
+/// Zone of dates without an offset and of `today`.
+#[derive(Debug, Clone, Copy)]
+pub enum Zone {
+    /// The clock of the collector, which `flow.input_date` is in.
+    Local,
+    Fixed(FixedOffset),
+    Named(Tz),
+}
+
+/// `+08:00`, `+0800` or `+08`.
+fn parse_offset(s: &str) -> Option<FixedOffset> {
+    let sign = if s.starts_with('-') { -1 } else { 1 };
+    let digits: String = s[1..].chars().filter(|x| *x != ':').collect();
+    if !digits.chars().all(|x| x.is_ascii_digit()) {
+        return None;
+    }
+    let (h, m) = match digits.len() {
+        2 => (digits.parse::<i32>().ok()?, 0),
+        4 => (digits[..2].parse::<i32>().ok()?, digits[2..].parse::<i32>().ok()?),
+        _ => return None,
+    };
+    if m >= 60 {
+        return None;
+    }
+    FixedOffset::east_opt(sign * (h * 3600 + m * 60))
+}
+
+fn earliest<T: TimeZone>(r: LocalResult<DateTime<T>>) -> Option<DateTime<Utc>> {
+    match r {
+        LocalResult::Single(x) | LocalResult::Ambiguous(x, _) => Some(x.with_timezone(&Utc)),
+        LocalResult::None => None,
+    }
+}
+
+impl Zone {
+    /// `local` (the default), `UTC`, offsets such as `+08:00` and IANA
+    /// names such as `Asia/Taipei`.
+    pub fn parse(s: Option<&str>) -> Result<Zone, ApiError> {
+        let s = match s.map(|x| x.trim()) {
+            None | Some("") => return Ok(Zone::Local),
+            Some(x) => x,
+        };
+        if s.eq_ignore_ascii_case("local") {
+            Ok(Zone::Local)
+        } else if s.eq_ignore_ascii_case("utc") || s == "Z" {
+            Ok(Zone::Fixed(FixedOffset::east(0)))
+        } else if s.starts_with('+') || s.starts_with('-') {
+            parse_offset(s).map(Zone::Fixed).ok_or_else(|| bad(format!("bad time zone offset {}", s)))
+        } else {
+            s.parse::<Tz>().map(Zone::Named).map_err(|_| bad(format!("unknown time zone {}", s)))
+        }
+    }
+
+    /// The instant of a wall clock time, the earlier one when a DST change
+    /// repeats it.
+    pub fn instant(&self, t: NaiveDateTime) -> Result<DateTime<Utc>, ApiError> {
+        if t.year() < 1 || t.year() > 9999 {
+            return Err(bad(format!("{} is out of range", t)));
+        }
+        let x = match *self {
+            Zone::Local => earliest(Local.from_local_datetime(&t)),
+            Zone::Fixed(z) => earliest(z.from_local_datetime(&t)),
+            Zone::Named(z) => earliest(z.from_local_datetime(&t)),
+        };
+        x.ok_or_else(|| bad(format!("{} does not exist in the time zone", t)))
+    }
+
+    pub fn wall(&self, t: DateTime<Utc>) -> NaiveDateTime {
+        match *self {
+            Zone::Local => t.with_timezone(&Local).naive_local(),
+            Zone::Fixed(z) => t.with_timezone(&z).naive_local(),
+            Zone::Named(z) => t.with_timezone(&z).naive_local(),
+        }
+    }
+
+    /// Start of the day holding `t`.
+    fn midnight(&self, t: DateTime<Utc>) -> Result<DateTime<Utc>, ApiError> {
+        self.instant(self.wall(t).date().and_hms(0, 0, 0))
+    }
+}
+
+/// Column a range filters on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeField {
+    /// When the collector stored the flow, the default.
+    InputDate,
+    /// `unixSecondsUTC` of the datagram, the clock of the agent.
+    Utc,
+}
+
+impl TimeField {
+    pub fn parse(s: Option<&str>) -> Result<TimeField, ApiError> {
+        match s.map(|x| x.trim()) {
+            None | Some("") | Some("input_date") => Ok(TimeField::InputDate),
+            Some("utc") => Ok(TimeField::Utc),
+            Some(x) => Err(bad(format!("unknown time_field {}, expected input_date or utc", x))),
+        }
+    }
+}
+
+/// `30s`, `15m`, `2h`, `7d` or `1w`; `None` as well when it does not fit
+/// a `Duration`.
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let unit = s.chars().last()?;
+    let digits = s[..s.len() - unit.len_utf8()].trim();
+    if digits.is_empty() || !digits.chars().all(|x| x.is_ascii_digit()) {
+        return None;
+    }
+    let n: i64 = digits.parse().ok()?;
+    let secs = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 86400,
+        'w' => 7 * 86400,
+        _ => return None,
+    };
+    n.checked_mul(secs)?.checked_mul(1000).map(Duration::milliseconds)
+}
+
+fn parse_epoch(s: &str) -> Option<DateTime<Utc>> {
+    if s.is_empty() || !s.chars().all(|x| x.is_ascii_digit() || x == '.') {
+        return None;
+    }
+    let x: f64 = s.parse().ok()?;
+    let millis = if x > EPOCH_MILLIS as f64 { x } else { x * 1000.0 };
+    if !millis.is_finite() || millis >= i64::max_value() as f64 {
+        return None;
+    }
+    let millis = millis as i64;
+    Utc.timestamp_opt(millis / 1000, (millis % 1000 * 1_000_000) as u32).single()
+}
+
+fn shift(t: DateTime<Utc>, d: Duration, forward: bool) -> Result<DateTime<Utc>, ApiError> {
+    let x = if forward { t.checked_add_signed(d) } else { t.checked_sub_signed(d) };
+    x.ok_or_else(|| bad("date out of range".to_string()))
+}
+
+/// One end of a range.
+pub fn parse_point(s: &str, zone: &Zone, now: DateTime<Utc>) -> Result<DateTime<Utc>, ApiError> {
+    let s = s.trim();
+    match s {
+        "now" => return Ok(now),
+        "today" => return zone.midnight(now),
+        "yesterday" => return shift(zone.midnight(now)?, Duration::days(1), false),
+        _ => {},
+    }
+    if s.starts_with("now-") || s.starts_with("now+") {
+        let d = parse_duration(&s[4..]).ok_or_else(|| bad(format!("bad duration in {}", s)))?;
+        return shift(now, d, s.starts_with("now+"));
+    }
+    if let Some(x) = parse_epoch(s) {
+        return Ok(x);
+    }
+    if let Ok(x) = DateTime::parse_from_rfc3339(s) {
+        return Ok(x.with_timezone(&Utc));
+    }
+    for f in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"].iter() {
+        if let Ok(x) = NaiveDateTime::parse_from_str(s, f) {
+            return zone.instant(x);
+        }
+    }
+    if let Ok(x) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return zone.instant(x.and_hms(0, 0, 0));
+    }
+    Err(bad(format!("bad date `{}`, expected RFC 3339, epoch seconds, YYYY-MM-DD HH:MM:SS, today or now-15m", s)))
+}
+
+/// `last 15m`, `today` (until now) or `yesterday`.
+pub fn parse_span(s: &str, zone: &Zone, now: DateTime<Utc>) -> Result<(DateTime<Utc>, DateTime<Utc>), ApiError> {
+    let s = s.trim();
+    match s {
+        "today" => return Ok((zone.midnight(now)?, now)),
+        "yesterday" => {
+            let m = zone.midnight(now)?;
+            return Ok((shift(m, Duration::days(1), false)?, m));
+        },
+        _ => {},
+    }
+    if s.starts_with("last") {
+        if let Some(d) = parse_duration(&s[4..]) {
+            return Ok((shift(now, d, false)?, now));
+        }
+    }
+    Err(bad(format!("bad range `{}`, expected last 15m, today or yesterday", s)))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TimeRange {
+    pub up: DateTime<Utc>,
+    pub down: DateTime<Utc>,
+    pub field: TimeField,
+}
+
+impl TimeRange {
+    /// `range` when set, else `up` to `down`, an empty `down` being now.
+    pub fn parse(up: &str, down: &str, range: Option<&str>, tz: Option<&str>, field: Option<&str>) -> Result<TimeRange, ApiError> {
+        let zone = Zone::parse(tz)?;
+        let now = Utc::now();
+        let (u, d) = match range.map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            Some(x) => parse_span(x, &zone, now)?,
+            None if up.trim().is_empty() => return Err(bad("up_date or range is required".to_string())),
+            None if down.trim().is_empty() => (parse_point(up, &zone, now)?, now),
+            None => (parse_point(up, &zone, now)?, parse_point(down, &zone, now)?),
+        };
+        for x in [u, d].iter() {
+            if x.timestamp() < 0 || x.timestamp() > MAX_SECS {
+                return Err(bad("dates have to be between 1970 and 9999".to_string()));
+            }
+        }
+        if u > d {
+            return Err(bad(format!("the range starts at {} after its end {}", u.to_rfc3339(), d.to_rfc3339())));
+        }
+        Ok(TimeRange { up: u, down: d, field: TimeField::parse(field)? })
+    }
+
+    /// Bounds in the clock of the collector, as `flow.input_date` holds them.
+    pub fn local(&self) -> (NaiveDateTime, NaiveDateTime) {
+        (Zone::Local.wall(self.up), Zone::Local.wall(self.down))
+    }
+
+    /// Bounds in the clock of `field`: the collector's for `input_date`,
+    /// UTC for `utc`.
+    pub fn bounds(&self) -> (NaiveDateTime, NaiveDateTime) {
+        match self.field {
+            TimeField::InputDate => self.local(),
+            TimeField::Utc => (self.up.naive_utc(), self.down.naive_utc()),
+        }
+    }
+
+    /// Whether the range is over, so new flows can no longer change its answer.
+    pub fn is_closed(&self) -> bool {
+        self.is_closed_at(Utc::now())
+    }
+
+    /// `is_closed` as seen at `now`.
+    pub fn is_closed_at(&self, now: DateTime<Utc>) -> bool {
+        self.down.checked_add_signed(Duration::seconds(CLOSE_GRACE_SECS))
+            .map(|x| x < now)
+            .unwrap_or(false)
+    }
+
+    /// Bounds in Unix seconds, as `flow.utc` holds them.
+    pub fn epoch(&self) -> (i32, i32) {
+        let clamp = |x: i64| x.max(0).min(i32::max_value() as i64) as i32;
+        (clamp(self.up.timestamp()), clamp(self.down.timestamp()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        Utc.ymd(2026, 10, 19).and_hms(12, 30, 0)
+    }
+
+    fn utc(y: i32, mo: u32, d: u32, h: u32, mi: u32, s: u32) -> DateTime<Utc> {
+        Utc.ymd(y, mo, d).and_hms(h, mi, s)
+    }
+
+    fn zone(s: &str) -> Zone {
+        Zone::parse(Some(s)).unwrap()
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("30s"), Some(Duration::seconds(30)));
+        assert_eq!(parse_duration(" 15m"), Some(Duration::minutes(15)));
+        assert_eq!(parse_duration("2h"), Some(Duration::hours(2)));
+        assert_eq!(parse_duration("7d"), Some(Duration::days(7)));
+        assert_eq!(parse_duration("1w"), Some(Duration::weeks(1)));
+        assert_eq!(parse_duration("5y"), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("-5m"), None);
+        assert_eq!(parse_duration("5é"), None);
+        assert_eq!(parse_duration("99999999999999w"), None);
+        assert_eq!(parse_duration("9223372036854775807s"), None);
+    }
+
+    #[test]
+    fn relative_points() {
+        let z = zone("UTC");
+        assert_eq!(parse_point("now", &z, now()).unwrap(), now());
+        assert_eq!(parse_point("now-15m", &z, now()).unwrap(), utc(2026, 10, 19, 12, 15, 0));
+        assert_eq!(parse_point("now+1h", &z, now()).unwrap(), utc(2026, 10, 19, 13, 30, 0));
+        assert_eq!(parse_point("today", &z, now()).unwrap(), utc(2026, 10, 19, 0, 0, 0));
+        assert_eq!(parse_point("yesterday", &z, now()).unwrap(), utc(2026, 10, 18, 0, 0, 0));
+        assert_eq!(parse_point("today", &zone("Asia/Taipei"), now()).unwrap(), utc(2026, 10, 18, 16, 0, 0));
+    }
+
+    #[test]
+    fn absolute_points() {
+        let z = zone("UTC");
+        assert_eq!(parse_point("2026-10-19T10:00:00+08:00", &z, now()).unwrap(), utc(2026, 10, 19, 2, 0, 0));
+        assert_eq!(parse_point("2026-10-19T10:00:00Z", &z, now()).unwrap(), utc(2026, 10, 19, 10, 0, 0));
+        assert_eq!(parse_point("1760000000", &z, now()).unwrap(), Utc.timestamp(1760000000, 0));
+        assert_eq!(parse_point("1760000000123", &z, now()).unwrap(), Utc.timestamp(1760000000, 123_000_000));
+        assert_eq!(parse_point("2026-10-19 10:00:00", &z, now()).unwrap(), utc(2026, 10, 19, 10, 0, 0));
+        assert_eq!(parse_point("2026-10-19T10:00:00", &z, now()).unwrap(), utc(2026, 10, 19, 10, 0, 0));
+        assert_eq!(parse_point("2026-10-19 10:00", &z, now()).unwrap(), utc(2026, 10, 19, 10, 0, 0));
+        assert_eq!(parse_point("2026-10-19", &z, now()).unwrap(), utc(2026, 10, 19, 0, 0, 0));
+        assert!(parse_point("19/10/2026", &z, now()).is_err());
+    }
+
+    #[test]
+    fn time_zones() {
+        let t = "2026-10-19 10:00:00";
+        assert_eq!(parse_point(t, &zone("+08:00"), now()).unwrap(), utc(2026, 10, 19, 2, 0, 0));
+        assert_eq!(parse_point(t, &zone("+0800"), now()).unwrap(), utc(2026, 10, 19, 2, 0, 0));
+        assert_eq!(parse_point(t, &zone("-05"), now()).unwrap(), utc(2026, 10, 19, 15, 0, 0));
+        assert_eq!(parse_point(t, &zone("Asia/Taipei"), now()).unwrap(), utc(2026, 10, 19, 2, 0, 0));
+        assert_eq!(parse_point(t, &zone("Europe/Berlin"), now()).unwrap(), utc(2026, 10, 19, 8, 0, 0));
+        assert!(Zone::parse(Some("Mars/Olympus")).is_err());
+        assert!(Zone::parse(Some("+25:00")).is_err());
+        assert!(Zone::parse(Some("+08:75")).is_err());
+    }
+
+    #[test]
+    fn dst_changes() {
+        let ny = zone("America/New_York");
+        // clocks jump from 02:00 to 03:00
+        assert!(parse_point("2026-03-08 02:30:00", &ny, now()).is_err());
+        assert_eq!(parse_point("2026-03-08 03:30:00", &ny, now()).unwrap(), utc(2026, 3, 8, 7, 30, 0));
+        // 01:30 happens twice, the earlier one is taken
+        assert_eq!(parse_point("2026-11-01 01:30:00", &ny, now()).unwrap(), utc(2026, 11, 1, 5, 30, 0));
+    }
+
+    #[test]
+    fn spans() {
+        let z = zone("UTC");
+        assert_eq!(parse_span("last 15m", &z, now()).unwrap(), (utc(2026, 10, 19, 12, 15, 0), now()));
+        assert_eq!(parse_span("last1h", &z, now()).unwrap(), (utc(2026, 10, 19, 11, 30, 0), now()));
+        assert_eq!(parse_span("today", &z, now()).unwrap(), (utc(2026, 10, 19, 0, 0, 0), now()));
+        assert_eq!(parse_span("yesterday", &z, now()).unwrap(),
+            (utc(2026, 10, 18, 0, 0, 0), utc(2026, 10, 19, 0, 0, 0)));
+        assert!(parse_span("last week", &z, now()).is_err());
+    }
+
+    #[test]
+    fn out_of_range_is_a_bad_request() {
+        let z = zone("UTC");
+        for x in ["99999999999999999999", "now-99999999999999d", "now+99999999999999d",
+                "now-9223372036854775807s", "+262143-12-31 23:00:00"].iter() {
+            assert!(parse_point(x, &z, now()).is_err(), "{}", x);
+        }
+        assert!(parse_span("last 99999999999999w", &z, now()).is_err());
+        assert!(TimeRange::parse("99999999999999999999", "", None, None, None).is_err());
+        assert!(TimeRange::parse("9999999999999999", "", None, None, None).is_err());
+        assert!(TimeRange::parse("", "", Some("last 99999999999999w"), None, None).is_err());
+    }
+
+    #[test]
+    fn ranges() {
+        let r = TimeRange::parse("2026-10-19 10:00:00", "2026-10-19 11:00:00", None, Some("UTC"), Some("utc")).unwrap();
+        assert_eq!(r.up, utc(2026, 10, 19, 10, 0, 0));
+        assert_eq!(r.down, utc(2026, 10, 19, 11, 0, 0));
+        assert_eq!(r.field, TimeField::Utc);
+        assert!(r.is_closed_at(now()));
+        assert!(!r.is_closed_at(utc(2026, 10, 19, 11, 0, 0)));
+        assert_eq!(r.epoch(), (1792404000, 1792407600));
+        assert!(TimeRange::parse("2020-01-01 00:00:00", "2020-01-02 00:00:00", None, Some("UTC"), None).unwrap().is_closed());
+        assert!(!TimeRange::parse("now-5m", "", None, None, None).unwrap().is_closed());
+        assert!(TimeRange::parse("now", "now-1h", None, None, None).is_err());
+        assert!(TimeRange::parse("", "", None, None, None).is_err());
+        assert!(TimeRange::parse("now-1h", "", None, None, Some("start_date")).is_err());
+    }
+}
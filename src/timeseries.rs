@@ -0,0 +1,215 @@
+//! Bucketing of flow rows into a time series for line/area charts
+use std::collections::BTreeMap;
+use std;
+use chrono::{NaiveDateTime, Duration};
+use models;
+use timerange::{self, TimeField};
+
+/// Upper bound of buckets in one response.
+const MAX_BUCKETS: i64 = 10000;
+const DEFAULT_TOP: usize = 10;
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct Series {
+    pub name: String,
+    pub values: Vec<i64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct TimeSeries {
+    pub interval: i64,
+    pub buckets: Vec<String>,
+    pub total: Vec<i64>,
+    pub series: Vec<Series>,
+}
+
+/// Parses intervals like `30s`, `1m`, `5m`, `1h` or `1d` into seconds, see
+/// `timerange::parse_duration`.
+pub fn parse_interval(s: &str) -> Result<i64, Box<std::error::Error>> {
+    let secs = timerange::parse_duration(s)
+        .ok_or_else(|| format!("bad interval {}, expected 30s, 5m, 1h or 1d", s.trim()))?
+        .num_seconds();
+    if secs <= 0 {
+        return Err(From::from(format!("interval must be positive: {}", s.trim())));
+    }
+    Ok(secs)
+}
+
+fn split_key(s: &models::Flow, split: &str) -> Result<String, Box<std::error::Error>> {
+    Ok(match split {
+        "src" => s.src.clone(),
+        "dst" => s.dst.clone(),
+        "ntype" => s.ntype.clone(),
+        "agent" => s.agent.clone(),
+        "app" => s.app.clone(),
+        _ => return Err(From::from(format!("cannot split by {}", split))),
+    })
+}
+
+/// Time of a flow in the clock of `field`.
+fn flow_time(s: &models::Flow, field: TimeField) -> NaiveDateTime {
+    match field {
+        TimeField::InputDate => s.input_date,
+        TimeField::Utc => NaiveDateTime::from_timestamp(s.utc as i64, 0),
+    }
+}
+
+/// Sums `size` per bucket of `interval` seconds from `up` to `dn`, both in
+/// the clock of `field`, optionally split by the `top` values of `split`;
+/// the remaining values are summed into an `other` series.
+pub fn build_timeseries(data: &Vec<models::Flow>, up: NaiveDateTime, dn: NaiveDateTime, field: TimeField,
+        interval: i64, split: Option<&str>, top: Option<usize>) -> Result<TimeSeries, Box<std::error::Error>> {
+    let span = (dn - up).num_seconds();
+    if span < 0 {
+        return Err(From::from("down_date is before up_date"));
+    }
+    let count = span / interval + 1;
+    if count > MAX_BUCKETS {
+        return Err(From::from(format!("too many buckets: {}", count)));
+    }
+    let count = count as usize;
+    let mut ts = TimeSeries {
+        interval: interval,
+        buckets: (0..count).map(|i| {
+            (up + Duration::seconds(i as i64 * interval)).format("%Y-%m-%d %H:%M:%S").to_string()
+        }).collect(),
+        total: vec![0; count],
+        series: vec![],
+    };
+    let mut split_map: BTreeMap<String, Vec<i64>> = BTreeMap::new();
+    for s in data.iter() {
+        if s.src == s.dst || s.src == "0.0.0.0" || s.dst == "0.0.0.0" {
+            continue;
+        }
+        let t = flow_time(s, field);
+        if t < up || t > dn {
+            continue;
+        }
+        let i = ((t - up).num_seconds() / interval) as usize;
+        ts.total[i] += s.size as i64;
+        if let Some(split) = split {
+            let key = split_key(s, split)?;
+            split_map.entry(key).or_insert_with(|| vec![0; count])[i] += s.size as i64;
+        }
+    }
+    if split.is_some() {
+        let mut all: Vec<(String, Vec<i64>)> = split_map.into_iter().collect();
+        all.sort_by_key(|x| -x.1.iter().sum::<i64>());
+        let top = top.unwrap_or(DEFAULT_TOP);
+        let mut other = vec![0; count];
+        let mut has_other = false;
+        for (n, (name, values)) in all.into_iter().enumerate() {
+            if n < top {
+                ts.series.push(Series {name: name, values: values});
+            } else {
+                has_other = true;
+                for (o, v) in other.iter_mut().zip(values.iter()) {
+                    *o += *v;
+                }
+            }
+        }
+        if has_other {
+            ts.series.push(Series {name: "other".to_string(), values: other});
+        }
+    }
+    Ok(ts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn at(h: u32, m: u32, s: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(2026, 10, 19).and_hms(h, m, s)
+    }
+
+    /// A flow stored at `input_date` whose agent stamped it `utc`.
+    fn flow(input_date: NaiveDateTime, utc: i64, dst: &str, size: i32) -> models::Flow {
+        models::Flow {
+            flow_id: 0,
+            input_date: input_date,
+            agent: "192.0.2.1".to_string(),
+            utc: utc as i32,
+            src: "10.0.0.1".to_string(),
+            dst: dst.to_string(),
+            srcport: 1234,
+            dstport: 80,
+            ntype: "ipv4".to_string(),
+            size: size,
+            packets: 1,
+            app: String::new(),
+            src_country: String::new(),
+            src_city: String::new(),
+            src_asn: 0,
+            src_org: String::new(),
+            dst_country: String::new(),
+            dst_city: String::new(),
+            dst_asn: 0,
+            dst_org: String::new(),
+            vlan: 0,
+            in_if: 0,
+            out_if: 0,
+            tenant_id: 0,
+        }
+    }
+
+    #[test]
+    fn intervals() {
+        assert_eq!(parse_interval("30s").unwrap(), 30);
+        assert_eq!(parse_interval(" 5m ").unwrap(), 300);
+        assert_eq!(parse_interval("1d").unwrap(), 86400);
+        assert!(parse_interval("0m").is_err());
+        assert!(parse_interval("5").is_err());
+        assert!(parse_interval("-5m").is_err());
+    }
+
+    #[test]
+    fn buckets_start_at_up() {
+        let data = vec![
+            flow(at(10, 0, 0), 0, "10.0.0.2", 1),
+            flow(at(10, 4, 59), 0, "10.0.0.2", 2),
+            flow(at(10, 5, 0), 0, "10.0.0.2", 4),
+            flow(at(10, 15, 0), 0, "10.0.0.2", 8),
+            // just before and after the range
+            flow(at(9, 59, 59), 0, "10.0.0.2", 16),
+            flow(at(10, 15, 1), 0, "10.0.0.2", 32),
+        ];
+        let ts = build_timeseries(&data, at(10, 0, 0), at(10, 15, 0), TimeField::InputDate, 300, None, None).unwrap();
+        assert_eq!(ts.interval, 300);
+        assert_eq!(ts.buckets, vec!["2026-10-19 10:00:00", "2026-10-19 10:05:00",
+            "2026-10-19 10:10:00", "2026-10-19 10:15:00"]);
+        assert_eq!(ts.total, vec![3, 4, 0, 8]);
+    }
+
+    #[test]
+    fn utc_buckets_follow_the_agent_clock() {
+        let up = at(10, 0, 0);
+        let epoch = up.timestamp();
+        // stored an hour after the agent sent them
+        let data = vec![
+            flow(at(11, 0, 0), epoch + 30, "10.0.0.2", 1),
+            flow(at(11, 0, 0), epoch + 90, "10.0.0.2", 2),
+        ];
+        let ts = build_timeseries(&data, up, at(10, 1, 59), TimeField::Utc, 60, None, None).unwrap();
+        assert_eq!(ts.total, vec![1, 2]);
+        let ts = build_timeseries(&data, up, at(10, 1, 59), TimeField::InputDate, 60, None, None).unwrap();
+        assert_eq!(ts.total, vec![0, 0]);
+    }
+
+    #[test]
+    fn top_values_and_other() {
+        let data = vec![
+            flow(at(10, 0, 0), 0, "10.0.0.2", 100),
+            flow(at(10, 1, 0), 0, "10.0.0.3", 50),
+            flow(at(10, 1, 0), 0, "10.0.0.4", 10),
+            flow(at(10, 0, 0), 0, "10.0.0.5", 5),
+        ];
+        let ts = build_timeseries(&data, at(10, 0, 0), at(10, 1, 0), TimeField::InputDate, 60, Some("dst"), Some(2)).unwrap();
+        let series: Vec<(&str, Vec<i64>)> = ts.series.iter().map(|x| (x.name.as_str(), x.values.clone())).collect();
+        assert_eq!(series, vec![("10.0.0.2", vec![100, 0]), ("10.0.0.3", vec![0, 50]), ("other", vec![5, 10])]);
+        assert_eq!(ts.total, vec![105, 60]);
+        assert!(build_timeseries(&data, at(10, 0, 0), at(10, 1, 0), TimeField::InputDate, 60, Some("port"), None).is_err());
+        assert!(build_timeseries(&data, at(10, 1, 0), at(10, 0, 0), TimeField::InputDate, 60, None, None).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+//! Server certificate and optional client certificate authentication
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std;
+use openssl::nid::Nid;
+use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod, SslVerifyMode};
+use openssl::x509::{X509Name, X509Ref};
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub key: String,
+    pub cert: String,
+    /// CA bundle for client certificates, client authentication is off when empty.
+    pub client_ca: String,
+    /// `common-name role` lines mapping client certificates to roles.
+    pub client_roles: String,
+    /// Roles allowed to use the API.
+    pub allowed_roles: Vec<String>,
+}
+
+/// Reads `common-name role` lines; the name may contain spaces, the role
+/// is the last word.
+pub fn load_roles(path: &str) -> Result<HashMap<String, String>, Box<std::error::Error>> {
+    let mut roles = HashMap::new();
+    for (n, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        match line.rfind(char::is_whitespace) {
+            Some(i) => {
+                roles.insert(line[..i].trim().to_string(), line[i..].trim().to_string());
+            },
+            None => return Err(From::from(format!("{}:{}: expected `common-name role`", path, n + 1))),
+        }
+    }
+    Ok(roles)
+}
+
+/// Common name of the subject of a certificate.
+pub fn common_name(cert: &X509Ref) -> Option<String> {
+    cert.subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|x| x.data().as_utf8().ok())
+        .map(|x| x.to_string())
+}
+
+/// Acceptor with the server certificate. With a client CA every client
+/// has to present a certificate signed by it whose common name maps to
+/// one of the allowed roles; others fail the handshake.
+pub fn acceptor(cfg: &TlsConfig) -> Result<SslAcceptorBuilder, Box<std::error::Error>> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_private_key_file(&cfg.key, SslFiletype::PEM)?;
+    builder.set_certificate_chain_file(&cfg.cert)?;
+    if cfg.client_ca.is_empty() {
+        return Ok(builder);
+    }
+    builder.set_ca_file(&cfg.client_ca)?;
+    builder.set_client_ca_list(X509Name::load_client_ca_file(&cfg.client_ca)?);
+    let roles = load_roles(&cfg.client_roles)?;
+    let allowed = cfg.allowed_roles.clone();
+    info!("tls: client certificates required, {} subjects known", roles.len());
+    builder.set_verify_callback(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT, move |ok, ctx| {
+        // only the leaf decides the role, the chain is checked by OpenSSL
+        if !ok || ctx.error_depth() > 0 {
+            return ok;
+        }
+        let name = match ctx.current_cert().and_then(common_name) {
+            Some(x) => x,
+            None => return false,
+        };
+        match roles.get(&name) {
+            Some(role) if allowed.contains(role) => {
+                info!("tls: client {} as {}", name, role);
+                true
+            },
+            Some(role) => {
+                info!("tls: client {} rejected, role {} not allowed", name, role);
+                false
+            },
+            None => {
+                info!("tls: client {} rejected, unknown subject", name);
+                false
+            },
+        }
+    });
+    Ok(builder)
+}
@@ -0,0 +1,103 @@
+//! In-memory sliding window of recent flows, one `FlowMap` per second
+use std::sync::Mutex;
+use chrono::NaiveDateTime;
+use registry;
+use sflow::*;
+
+#[derive(Debug, Default)]
+struct Bucket {
+    second: i64,
+    fm: FlowMap,
+}
+
+/// Ring of per-second buckets filled by the ingest thread. Every bucket has
+/// its own lock so the ingest thread and the HTTP workers rarely contend.
+/// Seconds are counted in the local clock used for `flow.input_date`.
+#[derive(Debug)]
+pub struct SlidingWindow {
+    buckets: Vec<Mutex<Bucket>>,
+    started: i64,
+}
+
+fn seconds(t: NaiveDateTime) -> i64 {
+    t.timestamp()
+}
+
+impl SlidingWindow {
+    pub fn new(len: usize) -> SlidingWindow {
+        SlidingWindow {
+            buckets: (0..len.max(1)).map(|_| Mutex::new(Default::default())).collect(),
+            started: seconds(registry::now()),
+        }
+    }
+
+    /// A window no ingest fills, whose queries all go to the database.
+    pub fn unfed(len: usize) -> SlidingWindow {
+        SlidingWindow { started: i64::max_value(), ..SlidingWindow::new(len) }
+    }
+
+    /// Window length in seconds.
+    pub fn len(&self) -> i64 {
+        self.buckets.len() as i64
+    }
+
+    pub fn now(&self) -> i64 {
+        seconds(registry::now())
+    }
+
+    pub fn add(&self, fm: &FlowMap) {
+        let now = self.now();
+        let idx = (now % self.len()) as usize;
+        if let Ok(mut b) = self.buckets[idx].lock() {
+            if b.second != now {
+                b.second = now;
+                b.fm.clear();
+            }
+            // tenants are tagged after the keys were made
+            for v in fm.values() {
+                merge_flow(&mut b.fm, &flow_key(v), v);
+            }
+        }
+    }
+
+    /// Merges the buckets of seconds `from..=to` whose flows pass `accept`.
+    pub fn collect<F>(&self, from: i64, to: i64, accept: F) -> FlowMap
+        where F: Fn(&FlowDirection) -> bool {
+        let mut fm = FlowMap::new();
+        let now = self.now();
+        let from = from.max(now - self.len() + 1);
+        let to = to.min(now);
+        let mut sec = from;
+        while sec <= to {
+            let idx = (sec % self.len()) as usize;
+            if let Ok(b) = self.buckets[idx].lock() {
+                if b.second == sec {
+                    for (k, v) in b.fm.iter() {
+                        if accept(v) {
+                            merge_flow(&mut fm, k, v);
+                        }
+                    }
+                }
+            }
+            sec += 1;
+        }
+        fm
+    }
+
+    /// True when all of `up..=dn` that can hold data is inside the window.
+    pub fn covers(&self, up: NaiveDateTime, dn: NaiveDateTime) -> bool {
+        let now = self.now();
+        let up = seconds(up);
+        up <= seconds(dn) && up > self.started && up > now - self.len()
+    }
+
+    /// Flows of `up..=dn` that pass `accept`, or `None` when the range has
+    /// to go to the database.
+    pub fn query<F>(&self, up: NaiveDateTime, dn: NaiveDateTime, accept: F) -> Option<FlowMap>
+        where F: Fn(&FlowDirection) -> bool {
+        if !self.covers(up, dn) {
+            return None;
+        }
+        Some(self.collect(seconds(up), seconds(dn), accept))
+    }
+}